@@ -20,7 +20,7 @@ pub fn hlir_sample(c: &mut Criterion) {
 }
 
 pub fn optir_sample(c: &mut Criterion) {
-    let ir = sawblade::hlir::IR::<sawblade::arch::X86_64Nasm>::from_ast(sawblade::ast::parse_source(SAMPLE));
+    let ir = sawblade::hlir::IR::<sawblade::arch::X86_64Nasm>::from_ast(sawblade::ast::parse_source(SAMPLE)).unwrap();
     c.bench_function("HLIR to OPTIR", |b| b.iter(|| {
         let blocks = ir.blocks.clone();
         sawblade::optir::dissect_from_hlir(black_box(blocks))
@@ -7,8 +7,11 @@ endif
 let g:syntax = 'sawblade'
 set iskeyword+='-'
 
-syn keyword sawbladeKw block return arguments
-syn keyword sawbladeInsn add call sub flags br
+syn keyword sawbladeKw block return arguments inline noinline
+syn keyword sawbladeInsn add call sub flags br switch fence addc adde subc sube asm syscall popcnt clz ctz bswap bitreverse
+syn keyword sawbladeKw in out clobber
+syn match sawbladeInsn /\vatomic\.(load|store|xchg|add|cas)/
+syn keyword sawbladeOrdering relaxed acquire release acqrel seqcst
 syn region sawbladeExportLabel start=/\v"/ end=/\v"/
 syn match sawbladeColonColon /::/
 syn match sawbladeRefLabel /@\w\+/
@@ -22,3 +25,4 @@ hi def link sawbladeExportLabel @string
 hi def link sawbladeRefLabel @function.call
 hi def link sawbladeLocal @symbol
 hi def link sawbladeNum @number
+hi def link sawbladeOrdering @constant
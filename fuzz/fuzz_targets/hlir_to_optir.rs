@@ -3,6 +3,9 @@ use libfuzzer_sys::fuzz_target;
 
 fuzz_target!(|data: Vec<sawblade::hlir::Block>| {
     if let Ok(data) = sawblade::hlir::check_arbitary_blocks(data) {
-        let _ = sawblade::optir::dissect_from_hlir(data);
+        let (ir, _) = sawblade::optir::dissect_from_hlir_lenient(data);
+        if let Err(errors) = sawblade::optir::verify(&ir) {
+            panic!("{:?}", errors);
+        }
     }
 });
@@ -4,7 +4,7 @@ use crate::arch::{Architecture, RegisterSet};
 use crate::hlir::Spec;
 use crate::index::{self, Register, RegisterRange};
 use crate::optir::{self, FixedArray, Op};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::mem::MaybeUninit;
 use std::ops::Range;
 
@@ -81,6 +81,20 @@ impl<'a> BindingSetBlockMut<'a> {
             binding,
         })
     }
+
+    fn remove(&mut self, binding: u16) -> bool {
+        self.r#ref.remove(&FullBinding {
+            block: self.block_index,
+            binding,
+        })
+    }
+
+    fn as_ref(&self) -> BindingSetBlock<'_> {
+        BindingSetBlock {
+            block_index: self.block_index,
+            r#ref: self.r#ref,
+        }
+    }
 }
 
 struct BindingSetBlock<'a> {
@@ -148,15 +162,306 @@ impl ActiveBindingSet {
     }
 }
 
-fn linear_alloc_block<'alloc_bindings, 'spilled_bindings>(
-    registers: &mut [MaybeUninit<Register>],
-    allocated_bindings: BindingSetBlock<'alloc_bindings>,
-    register_set: RegisterSet,
+/// Registers that an operation needs for itself, so that no binding can live across the
+/// operation in them.
+struct Reservation {
+    op_index: u16,
+    registers: Vec<Register>,
+}
+
+fn reservations(block: &optir::Block) -> Vec<Reservation> {
+    block
+        .operations
+        .iter()
+        .enumerate()
+        .filter_map(|(op_index, op)| match op {
+            Op::Asm {
+                inputs,
+                outputs,
+                clobbers,
+                ..
+            } => Some(Reservation {
+                op_index: op_index as u16,
+                registers: inputs
+                    .iter()
+                    .map(|(register, _)| *register)
+                    .chain(outputs.iter().copied())
+                    .chain(clobbers.iter().copied())
+                    .collect(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+#[inline(always)]
+fn lifetimes_overlap(starts: &[u16], ends: &[u16], lhs: u16, rhs: u16) -> bool {
+    starts[lhs as usize] < ends[rhs as usize] && starts[rhs as usize] < ends[lhs as usize]
+}
+
+/// The lifetimes of the bindings of a block, along with the registers that are already taken
+/// while they live.
+struct Occupancy<'a> {
+    starts: &'a [u16],
+    ends: &'a [u16],
+    /// The first `arg_count` bindings are the arguments, which all live at the same time when
+    /// the block is entered.
+    arg_count: usize,
+    /// Bindings whose register is already decided.
+    pinned_bindings: Vec<u16>,
+    reservations: Vec<Reservation>,
+}
+
+impl<'a> Occupancy<'a> {
+    fn new(
+        block: &optir::Block,
+        starts: &'a [u16],
+        ends: &'a [u16],
+        allocated_bindings: BindingSetBlock,
+    ) -> Self {
+        Self {
+            starts,
+            ends,
+            arg_count: block.arg_count,
+            pinned_bindings: (0..starts.len() as u16)
+                .filter(|binding| allocated_bindings.contains(*binding))
+                .collect(),
+            reservations: reservations(block),
+        }
+    }
+
+    fn is_argument(&self, binding: u16) -> bool {
+        (binding as usize) < self.arg_count
+    }
+
+    /// Whether an operation that `binding` lives across reserves `register`.
+    fn is_reserved(&self, binding: u16, register: Register) -> bool {
+        let (start, end) = (self.starts[binding as usize], self.ends[binding as usize]);
+        self.reservations.iter().any(|reservation| {
+            start < reservation.op_index
+                && reservation.op_index < end
+                && reservation.registers.contains(&register)
+        })
+    }
+
+    /// Whether `binding` can't be placed in `register`, either because a pinned binding that
+    /// lives at the same time needs it, or because an operation that it lives across reserves it.
+    fn is_register_taken(
+        &self,
+        registers: &[MaybeUninit<Register>],
+        binding: u16,
+        register: Register,
+    ) -> bool {
+        self.is_reserved(binding, register)
+            || self.pinned_bindings.iter().copied().any(|pinned| {
+                pinned != binding
+                    && (lifetimes_overlap(self.starts, self.ends, binding, pinned)
+                        || self.is_argument(binding) && self.is_argument(pinned))
+                    // SAFE: pinned bindings have their register written
+                    && unsafe { registers[pinned as usize].assume_init() } == register
+            })
+    }
+}
+
+/// Pins the bindings that inline assembly reads or writes to the registers it wants them in, the
+/// same way that specs pin arguments. The code generator moves them into place around the
+/// assembly anyway, so a binding is only pinned if the register is free for all of its lifetime,
+/// and inputs only if the assembly is their last usage.
+///
+/// Bindings that were pinned before (e.g returns) and live across assembly that uses their
+/// register are moved out of the way, since the code generator moves them into the register they
+/// are returned in as well. Arguments can't be moved, since they're already there when the block
+/// is entered.
+fn resolve_allocs_from_constraints(
+    block: &optir::Block,
     starts: &[u16],
     ends: &[u16],
+    registers: &mut [MaybeUninit<Register>],
+    mut allocated_bindings: BindingSetBlockMut,
+) {
+    let mut occupancy = Occupancy::new(block, starts, ends, allocated_bindings.as_ref());
+
+    for pinned in std::mem::take(&mut occupancy.pinned_bindings) {
+        // SAFE: pinned bindings have their register written
+        let register = unsafe { registers[pinned as usize].assume_init() };
+        if !occupancy.is_reserved(pinned, register) {
+            occupancy.pinned_bindings.push(pinned);
+        } else if occupancy.is_argument(pinned) {
+            panic!(
+                "argument {} is passed in register {}, which inline assembly uses while the \
+                 argument is still needed",
+                pinned,
+                unsafe { register.as_index() }
+            );
+        } else {
+            allocated_bindings.remove(pinned);
+        }
+    }
+
+    for (op_index, op) in block.operations.iter().enumerate() {
+        let Op::Asm {
+            inputs, outputs, ..
+        } = op
+        else {
+            continue;
+        };
+
+        let results = block
+            .results_of(op_index)
+            .map(|binding| unsafe { binding.to_index() });
+        let inputs = inputs
+            .iter()
+            .map(|(register, binding)| (unsafe { binding.to_index() }, *register))
+            .filter(|(binding, _)| ends[*binding as usize] as usize == op_index);
+        for (binding, register) in results.zip(outputs.iter().copied()).chain(inputs) {
+            if !allocated_bindings.contains(binding)
+                && !occupancy.is_register_taken(registers, binding, register)
+            {
+                registers[binding as usize].write(register);
+                allocated_bindings.insert(binding);
+                occupancy.pinned_bindings.push(binding);
+            }
+        }
+    }
+}
+
+/// The registers that the blocks returning on behalf of a block with a spec have to return their
+/// bindings in, by block and binding index. Blocks split from another one return like it does.
+fn return_registers<A>(
+    ir: &optir::IR,
+    return_blocks: &[Vec<index::Label>],
+    spec: &[crate::hlir::Spec<A>],
+) -> HashMap<(u16, u16), Register> {
+    let mut registers = HashMap::new();
+    for (index, returns) in ir
+        .return_registers(
+            return_blocks,
+            &spec
+                .iter()
+                .map(|spec| &spec.returns[..])
+                .collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .enumerate()
+    {
+        let block = &ir.blocks[index];
+        for (binding, register) in block.exported_bindings.iter().zip(returns) {
+            registers.insert((index as u16, unsafe { binding.to_index() }), *register);
+        }
+    }
+    registers
+}
+
+/// The registers of the bindings of all the blocks, along with their lifetimes. Each block's
+/// bindings are at its range.
+struct Allocation<'a> {
+    block_ranges: &'a [Range<usize>],
+    starts: &'a [u16],
+    ends: &'a [u16],
+    registers: &'a mut [MaybeUninit<Register>],
+    allocated_bindings: &'a mut BindingSet,
+}
+
+impl<'a> Allocation<'a> {
+    fn register_index(&self, block: u16, binding: u16) -> usize {
+        self.block_ranges[block as usize].start + binding as usize
+    }
+
+    /// Whether `binding` of `block` can be placed in `register`, going by what was pinned in its
+    /// block so far.
+    fn is_register_free(
+        &self,
+        ir: &optir::IR,
+        block: u16,
+        binding: u16,
+        register: Register,
+    ) -> bool {
+        let range = self.block_ranges[block as usize].clone();
+        !Occupancy::new(
+            &ir.blocks[block as usize],
+            &self.starts[range.clone()],
+            &self.ends[range.clone()],
+            self.allocated_bindings.get_block(block),
+        )
+        .is_register_taken(&self.registers[range], binding, register)
+    }
+}
+
+/// Pins all the bindings of each selection bucket to the same register, so that branching into a
+/// join point doesn't have to move anything around. A binding that is already pinned (e.g by a
+/// spec), or that is returned in a given register, decides the register of its bucket. Buckets
+/// that can't get a register that's free for all of their bindings are left to the linear scan,
+/// and the branches line up the registers instead.
+fn resolve_allocs_from_selection_buckets(
+    ir: &optir::IR,
+    return_registers: &HashMap<(u16, u16), Register>,
+    allocation: &mut Allocation,
+    register_set: RegisterSet,
+) {
+    for bucket in ir.selection_buckets.iter() {
+        let members = bucket
+            .iter()
+            .map(|value| {
+                (unsafe { value.block.to_index() }, unsafe {
+                    value.binding.to_index()
+                })
+            })
+            .collect::<Vec<_>>();
+        let is_allocated = |(block, binding): &(u16, u16)| {
+            allocation
+                .allocated_bindings
+                .get_block(*block)
+                .contains(*binding)
+        };
+        let mut pinned_registers = members
+            .iter()
+            .filter(|member| is_allocated(member))
+            .map(|(block, binding)| unsafe {
+                allocation.registers[allocation.register_index(*block, *binding)].assume_init()
+            })
+            .chain(
+                members
+                    .iter()
+                    .filter_map(|member| return_registers.get(member).copied()),
+            )
+            .collect::<Vec<_>>();
+        pinned_registers.sort_by_key(|register| unsafe { register.as_index() });
+        pinned_registers.dedup();
+        let candidates = match pinned_registers[..] {
+            [] => register_set.gp_registers.collect::<Vec<_>>(),
+            [register] => vec![register],
+            _ => continue,
+        };
+
+        let Some(register) = candidates.into_iter().find(|register| {
+            members.iter().all(|member| {
+                is_allocated(member)
+                    || allocation.is_register_free(ir, member.0, member.1, *register)
+            })
+        }) else {
+            continue;
+        };
+        for (block, binding) in members {
+            if allocation
+                .allocated_bindings
+                .get_block_mut(block)
+                .insert(binding)
+            {
+                let index = allocation.register_index(block, binding);
+                allocation.registers[index].write(register);
+            }
+        }
+    }
+}
+
+fn linear_alloc_block(
+    block: &optir::Block,
+    registers: &mut [MaybeUninit<Register>],
+    occupancy: &Occupancy,
+    register_set: RegisterSet,
     ordered_bindings_by_start: &[u16],
     ordered_bindings_by_end: &[u16],
-    mut spills: BindingSetBlockMut<'spilled_bindings>,
+    mut spills: BindingSetBlockMut,
 ) {
     debug_assert!(
         register_set.gp_registers.len() > 0,
@@ -164,27 +469,34 @@ fn linear_alloc_block<'alloc_bindings, 'spilled_bindings>(
     );
     let mut active = ActiveBindingSet::new();
     let mut pool: HashSet<_> = register_set.gp_registers.collect();
+    let (starts, ends) = (occupancy.starts, occupancy.ends);
     for binding in ordered_bindings_by_start.iter().copied() {
         let start = starts[binding as usize];
-        // expire old intervals
-        let end_i = active
-            .bindings
-            .iter()
-            .copied()
-            .enumerate()
-            .find(|(i, binding)| ends[*binding as usize] > start)
-            .map(|(i, _)| i)
-            .unwrap_or(active.bindings.len());
+        // expire old intervals. Arguments are all live when the block is entered, so they can't
+        // take the place of each other even if one of them is last used by the first operation.
+        let end_i = match block.binding_defs[binding as usize] {
+            optir::bucket::Definition::Argument(_) => 0,
+            optir::bucket::Definition::Op(_) => active
+                .bindings
+                .iter()
+                .copied()
+                .enumerate()
+                .find(|(i, binding)| ends[*binding as usize] > start)
+                .map(|(i, _)| i)
+                .unwrap_or(active.bindings.len()),
+        };
 
         for dropped_binding in active.bindings.drain(..end_i) {
+            let register = unsafe { registers[dropped_binding as usize].assume_init() };
+            // pinned bindings might be outside of the pool
             debug_assert!(
-                pool.insert(unsafe { registers[dropped_binding as usize].assume_init() }),
+                !register_set.gp_registers.contains(register) || pool.insert(register),
                 "register should have been in use!"
             );
         }
 
         // if it's known, skip the handling of a register.
-        let already_known_register = if allocated_bindings.contains(binding) {
+        let already_known_register = if occupancy.pinned_bindings.contains(&binding) {
             Some(unsafe { registers[binding as usize].assume_init() })
         } else {
             None
@@ -192,10 +504,10 @@ fn linear_alloc_block<'alloc_bindings, 'spilled_bindings>(
 
         // try to find an available register.
         let unused_register = already_known_register.or_else(|| {
-            register_set
-                .gp_registers
-                .clone()
-                .find(|register| pool.contains(register))
+            register_set.gp_registers.clone().find(|register| {
+                pool.contains(register)
+                    && !occupancy.is_register_taken(registers, binding, *register)
+            })
         });
 
         let found_register = unused_register.or_else(|| {
@@ -215,7 +527,7 @@ fn linear_alloc_block<'alloc_bindings, 'spilled_bindings>(
 
         if let Some(register) = found_register {
             debug_assert!(
-                pool.remove(&register),
+                !register_set.gp_registers.contains(register) || pool.remove(&register),
                 "register should not have been in use"
             );
             registers[binding as usize].write(register);
@@ -228,12 +540,12 @@ fn linear_alloc_block<'alloc_bindings, 'spilled_bindings>(
 
 fn resolve_allocs_from_spec<'alloc_bindings, A>(
     spec: &[crate::hlir::Spec<A>],
+    return_registers: &HashMap<(u16, u16), Register>,
     block_index: usize,
     block: &optir::Block,
     allocator: &mut GPAllocator,
     registers: &mut [MaybeUninit<Register>],
     mut allocated_bindings: BindingSetBlockMut<'alloc_bindings>,
-    spilled_returns: &mut [Option<u16>],
 ) {
     for (index, register) in (0..block.arg_count).zip(&spec[block_index].arguments) {
         registers[index].write(*register);
@@ -241,26 +553,18 @@ fn resolve_allocs_from_spec<'alloc_bindings, A>(
 
     allocated_bindings.extend((0..spec[block_index].arguments.len() as u16));
 
-    match &block.end {
-        optir::CFTransfer::Return => {
-            for (index, (binding, register)) in block
-                .exported_bindings
-                .iter()
-                .copied()
-                .zip(spec[block_index].returns.iter().copied())
-                .enumerate()
-            {
-                let index = index as u16;
-                let binding = unsafe { binding.to_index() };
-                // don't try to reallocate. Returns are easy to reallocate since upon return th
-                if !allocated_bindings.contains(binding) {
-                    registers[binding as usize].write(register);
-                } else {
-                    spilled_returns[binding as usize] = Some(binding);
-                }
+    if let optir::CFTransfer::Return = &block.end {
+        for binding in block.exported_bindings.iter().copied() {
+            let binding = unsafe { binding.to_index() };
+            // bindings that are already pinned (e.g arguments) are moved into the return
+            // register when the block returns.
+            let Some(register) = return_registers.get(&(block_index as u16, binding)) else {
+                continue;
+            };
+            if allocated_bindings.insert(binding) {
+                registers[binding as usize].write(*register);
             }
         }
-        _ => {}
     }
 }
 
@@ -305,6 +609,30 @@ impl Tracker {
     }
 }
 
+/// Arguments are all defined when the block is entered, operations define their results as they
+/// execute.
+fn binding_start(definition: optir::bucket::Definition) -> u16 {
+    match definition {
+        optir::bucket::Definition::Argument(_) => 0,
+        optir::bucket::Definition::Op(index) => index,
+    }
+}
+
+/// Bindings without usages (e.g ignored call results) still get a register, which must hold them
+/// while the operation that defines them executes. Arguments that are never read are dead as soon
+/// as the block is entered, so they don't keep their register away from the first operation.
+fn dead_binding_end(definition: optir::bucket::Definition) -> optir::bucket::UsageIndex {
+    match definition {
+        optir::bucket::Definition::Argument(_) => optir::bucket::UsageIndex::Op(0),
+        optir::bucket::Definition::Op(index) => optir::bucket::UsageIndex::Op(index + 1),
+    }
+}
+
+/// Immediate usages are encoded into the instruction, so they don't keep a register alive.
+fn needs_place(usage: &optir::bucket::Usage) -> bool {
+    !matches!(usage.usage_kind, optir::bucket::UsageKind::Immediate)
+}
+
 fn compute_lifetimes(
     block: &optir::Block,
     starts: &mut [MaybeUninit<u16>],
@@ -313,12 +641,14 @@ fn compute_lifetimes(
     for (usage, target) in block
         .binding_usages
         .iter()
-        .map(|usages| {
+        .zip(block.binding_defs.iter().copied())
+        .map(|(usages, definition)| {
             usages
                 .iter()
-                .max_by_key(|usage| usage.index)
-                .expect("all bindings must be used")
-                .index
+                .filter(|usage| needs_place(usage))
+                .map(|usage| usage.index)
+                .max()
+                .unwrap_or_else(|| dead_binding_end(definition))
         })
         .zip(ends)
     {
@@ -330,28 +660,41 @@ fn compute_lifetimes(
         .binding_defs
         .iter()
         .copied()
-        .map(optir::bucket::Definition::index)
+        .map(binding_start)
         .zip(starts)
     {
         target.write(value);
     }
 }
 
-// TODO: don't collide selective usages that match selection buckets.
+/// The selection bucket that the binding is passed into a join point with, if any.
+fn selection_bucket(usages: &[optir::bucket::Usage]) -> Option<u16> {
+    usages.iter().find_map(|usage| match usage.usage_kind {
+        optir::bucket::UsageKind::Selective { selection_bucket } => Some(selection_bucket),
+        _ => None,
+    })
+}
+
+// Bindings in the same selection bucket never exist at the same time, so they don't collide.
 fn compute_lifetime_collisions(block: &optir::Block, collisions: &mut [HashSet<index::Binding>]) {
-    let ends = block.binding_usages.iter().map(|usages| {
-        usages
-            .iter()
-            .min_by_key(|usage| usage.index)
-            .expect("all bindings must be used")
-            .index
-    });
+    let ends = block
+        .binding_usages
+        .iter()
+        .zip(block.binding_defs.iter().copied())
+        .map(|(usages, definition)| {
+            usages
+                .iter()
+                .filter(|usage| needs_place(usage))
+                .map(|usage| usage.index)
+                .min()
+                .unwrap_or_else(|| dead_binding_end(definition))
+        });
 
     // NOTE: len() - 1 should not panic here: blocks with nothing to do are discarded.
     let lifetimes = block.binding_defs[..block.binding_defs.len()]
         .iter()
         .copied()
-        .map(optir::bucket::Definition::index)
+        .map(binding_start)
         .zip(ends)
         .map(|(start, end)| Lifetime { end, start })
         .collect::<FixedArray<_>>();
@@ -359,12 +702,18 @@ fn compute_lifetime_collisions(block: &optir::Block, collisions: &mut [HashSet<i
     for index in (0..(block.binding_defs.len() - 1) as u16).rev() {
         let binding = unsafe { index::Binding::from_index(index) };
         let self_lifetime = &lifetimes[index as usize];
+        let self_bucket = selection_bucket(&block.binding_usages[index as usize]);
 
         collisions[index as usize].extend(
             (0..lifetimes.len() as u16)
                 .zip(lifetimes.iter())
                 .skip(index as usize + 1)
-                .filter(|(_, other_lifetime)| self_lifetime.collides_with(**other_lifetime))
+                .filter(|(other, other_lifetime)| {
+                    self_lifetime.collides_with(**other_lifetime)
+                        && (self_bucket.is_none()
+                            || self_bucket
+                                != selection_bucket(&block.binding_usages[*other as usize]))
+                })
                 .map(|(index, _)| unsafe { index::Binding::from_index(index) }),
         );
     }
@@ -436,6 +785,7 @@ struct BlockIndices {
 // returns an array of registres and an array of the register range per block
 pub fn allocate_registers<A: Architecture>(
     ir: &optir::IR,
+    analyses: &mut optir::Analyses,
     spec: &[Spec<A>],
 ) -> (FixedArray<Register>, FixedArray<Range<usize>>) {
     let register_set = A::register_set();
@@ -474,46 +824,22 @@ pub fn allocate_registers<A: Architecture>(
 
     let mut allocator = GPAllocator::new(register_set.gp_registers);
 
-    let (mut spilled_returns, spilled_return_ranges) = {
-        let mut ranges = Box::new_uninit_slice(ir.blocks.len());
-
-        let total_arg_count = ir
-            .blocks
-            .iter()
-            .map(|block| block.arg_count)
-            .enumerate()
-            .fold(0, |start, (index, count)| {
-                let end = start + count;
-                ranges[index].write(start..end);
-                end
-            });
-
-        (vec![None; total_arg_count].into_boxed_slice(), unsafe {
-            ranges.assume_init()
-        })
-    };
-
     let mut allocated_bindings = BindingSet {
         inner_set: HashSet::new(),
     };
 
+    let return_registers = return_registers(ir, analyses.return_blocks(ir), spec);
     for (index, range) in block_ranges.iter().cloned().enumerate() {
         resolve_allocs_from_spec(
-            &spec,
+            spec,
+            &return_registers,
             index,
             &ir.blocks[index],
             &mut allocator,
             &mut registers[range.clone()],
             allocated_bindings.get_block_mut(index as u16),
-            &mut spilled_returns[spilled_return_ranges[index].clone()],
         );
     }
-    // TODO: for the new assembly format, generate more instructions to move
-    // spilled returns to their respective registers
-    assert!(
-        spilled_returns.iter().all(Option::is_none),
-        "there is no infrastructure to support re-moving returns to their respective next usages"
-    );
 
     // starts, ends could be a single allocation...
     let lifetime_data = {
@@ -563,6 +889,29 @@ pub fn allocate_registers<A: Architecture>(
     {
         let [starts, ends, ordered_bindings_by_start, ordered_bindings_by_end] =
             chunkify_exact(&lifetime_data);
+        for (index, range) in block_ranges.iter().cloned().enumerate() {
+            resolve_allocs_from_constraints(
+                &ir.blocks[index],
+                &starts[range.clone()],
+                &ends[range.clone()],
+                &mut registers[range],
+                allocated_bindings.get_block_mut(index as u16),
+            );
+        }
+        // after the constraints, which can't be moved out of the way of a bucket.
+        resolve_allocs_from_selection_buckets(
+            ir,
+            &return_registers,
+            &mut Allocation {
+                block_ranges: &block_ranges,
+                starts,
+                ends,
+                registers: &mut registers,
+                allocated_bindings: &mut allocated_bindings,
+            },
+            register_set,
+        );
+
         for (index, range) in block_ranges.iter().cloned().enumerate() {
             let ordered_bindings_by_start = &ordered_bindings_by_start[range.clone()];
             let ordered_bindings_by_end = &ordered_bindings_by_end[range.clone()];
@@ -571,11 +920,15 @@ pub fn allocate_registers<A: Architecture>(
             let registers = &mut registers[range];
 
             linear_alloc_block(
+                &ir.blocks[index],
                 registers,
-                allocated_bindings.get_block(index as u16),
+                &Occupancy::new(
+                    &ir.blocks[index],
+                    starts,
+                    ends,
+                    allocated_bindings.get_block(index as u16),
+                ),
                 register_set,
-                starts,
-                ends,
                 ordered_bindings_by_start,
                 ordered_bindings_by_end,
                 spilled_bindings.get_block_mut(index as u16),
@@ -611,7 +964,7 @@ fn chunkify_exact<const N: usize, T>(slice: &[T]) -> [&[T]; N] {
     );
     let per_chunk_len = slice.len() / N;
 
-    let mut array = MaybeUninit::uninit_array();
+    let mut array = [const { MaybeUninit::uninit() }; N];
 
     let mut ptr = slice.as_ptr();
     for target in array.iter_mut() {
@@ -629,7 +982,7 @@ fn chunkify_exact_mut<const N: usize, T>(slice: &mut [T]) -> [&mut [T]; N] {
     );
     let per_chunk_len = slice.len() / N;
 
-    let mut array = MaybeUninit::uninit_array();
+    let mut array = [const { MaybeUninit::uninit() }; N];
 
     let mut ptr = slice.as_mut_ptr();
     for target in array.iter_mut() {
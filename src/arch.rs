@@ -1,8 +1,10 @@
 mod aarch64;
-use std::mem::MaybeUninit;
+pub use self::aarch64::Aarch64;
+use std::collections::HashSet;
 
 use bitflags::bitflags;
 
+use crate::hlir::{AtomicOrdering, BitOperation, CarryOperation, RmwOperation};
 use crate::index;
 use crate::llir::Input;
 
@@ -16,6 +18,10 @@ use self::x86_64_nasm::Register;
 pub trait Architecture {
     fn index_from_register(name: &str) -> Option<index::Register>;
     fn register_set() -> RegisterSet;
+    fn syscall_convention() -> SyscallConvention;
+    /// Whether `value` can be added or subtracted as an immediate operand, rather than from a
+    /// register.
+    fn fits_arithmetic_immediate(value: u64) -> bool;
     fn assemble<'label, W: std::io::Write>(
         ir: crate::llir::IR,
         label_map: &[&'label str],
@@ -23,6 +29,20 @@ pub trait Architecture {
     ) -> std::io::Result<()>;
 }
 
+/// How the kernel expects a system call to be made.
+pub struct SyscallConvention {
+    /// The instruction that traps into the kernel.
+    pub instruction: &'static str,
+    /// Where the system call number goes.
+    pub number: index::Register,
+    /// Where each of the arguments go, in order.
+    pub arguments: Vec<index::Register>,
+    /// Where the kernel leaves the result.
+    pub result: index::Register,
+    /// Registers that the kernel overwrites, besides the result.
+    pub clobbers: Vec<index::Register>,
+}
+
 // TODO: callee/caller reserved registers
 #[derive(Clone, Copy)]
 pub struct RegisterSet {
@@ -149,15 +169,18 @@ mod x86_64_nasm {
         R13,
         R14,
         R15,
+        // after the rest, so that the registers that were there before it keep their index. It's
+        // outside of the registers that get allocated, like r15.
+        R8,
     }
 
     crate::impl_self_dependency_eq!(Register);
 
     // some fills to use ranges with it
     impl Register {
-        pub const COUNT: u8 = 15;
+        pub const COUNT: u8 = 16;
         pub const fn from_number(num: u8) -> Option<Self> {
-            if num > Self::COUNT {
+            if num >= Self::COUNT {
                 None
             } else {
                 Some(unsafe { std::mem::transmute(num as u8) })
@@ -185,6 +208,7 @@ mod x86_64_nasm {
                 Register::Rdx => "rdx",
                 Register::Rsi => "rsi",
                 Register::Rdi => "rdi",
+                Register::R8 => "r8",
                 Register::R9 => "r9",
                 Register::R10 => "r10",
                 Register::R11 => "r11",
@@ -194,6 +218,27 @@ mod x86_64_nasm {
                 Register::R15 => "r15",
             }
         }
+        /// The name of the lowest byte of the register.
+        pub const fn byte_name(&self) -> &str {
+            match self {
+                Register::Rsp => "spl",
+                Register::Rbp => "bpl",
+                Register::Rax => "al",
+                Register::Rbx => "bl",
+                Register::Rcx => "cl",
+                Register::Rdx => "dl",
+                Register::Rsi => "sil",
+                Register::Rdi => "dil",
+                Register::R8 => "r8b",
+                Register::R9 => "r9b",
+                Register::R10 => "r10b",
+                Register::R11 => "r11b",
+                Register::R12 => "r12b",
+                Register::R13 => "r13b",
+                Register::R14 => "r14b",
+                Register::R15 => "r15b",
+            }
+        }
     }
 
     impl std::fmt::Debug for Register {
@@ -202,7 +247,7 @@ mod x86_64_nasm {
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy)]
     pub enum DataSource<'a> {
         Constant(u64),
         Register(Register),
@@ -231,6 +276,78 @@ mod x86_64_nasm {
             rhs: DataSource<'a>,
         },
 
+        /// Add with the carry flag.
+        Adc {
+            lhs: Register,
+            rhs: DataSource<'a>,
+        },
+
+        /// Subtract with the carry flag as the borrow.
+        Sbb {
+            lhs: Register,
+            rhs: DataSource<'a>,
+        },
+
+        /// Bitwise not, leaves the flags alone.
+        Not(Register),
+        /// Two's complement negation.
+        Neg(Register),
+        /// Add one, leaving the carry flag alone.
+        Inc(Register),
+        /// Subtract one, leaving the carry flag alone.
+        Dec(Register),
+        /// Add a register and a displacement (or two registers) into another one, without
+        /// touching the flags.
+        Lea {
+            dest: Register,
+            base: Register,
+            index: Option<Register>,
+            displacement: i32,
+        },
+        And {
+            lhs: Register,
+            rhs: DataSource<'a>,
+        },
+        Or {
+            lhs: Register,
+            rhs: DataSource<'a>,
+        },
+        Shl {
+            lhs: Register,
+            amount: u8,
+        },
+        Shr {
+            lhs: Register,
+            amount: u8,
+        },
+        Popcnt {
+            dest: Register,
+            source: Register,
+        },
+        Lzcnt {
+            dest: Register,
+            source: Register,
+        },
+        Tzcnt {
+            dest: Register,
+            source: Register,
+        },
+        Bswap(Register),
+        /// Complement the carry flag.
+        Cmc,
+        /// Set the carry flag.
+        Stc,
+        /// Copy a bit of a register into the carry flag.
+        Bt {
+            register: Register,
+            bit: u8,
+        },
+        /// Set the lowest byte of a register to whether the condition holds.
+        Set {
+            register: Register,
+            condition: Condition,
+        },
+
         // XXX: I can't use offsets for x86_64 until I can control how many bytes is each
         // instruction (sized constants might also play here). That's what you get from variable
         // length instructions.
@@ -247,6 +364,64 @@ mod x86_64_nasm {
             condition: Condition,
         },
 
+        Cmp {
+            lhs: Register,
+            rhs: DataSource<'a>,
+        },
+
+        /// Load the address of a label, relative to the instruction pointer.
+        LeaLabel {
+            dest: Register,
+            label: &'a str,
+        },
+        /// Load the 4-byte entry `index` of a table at `table`, sign extending it.
+        LoadTableEntry {
+            dest: Register,
+            table: Register,
+            index: Register,
+        },
+        /// Jump to the address held by a register.
+        JumpRegister(Register),
+
+        /// Load from the address held by a register.
+        Load {
+            dest: Register,
+            address: Register,
+        },
+        /// Store into the address held by a register.
+        Store {
+            address: Register,
+            source: Register,
+        },
+        LockAdd {
+            address: Register,
+            source: Register,
+        },
+        LockXadd {
+            address: Register,
+            source: Register,
+        },
+        /// Exchanging with memory is always locked, no need to prefix it.
+        Xchg {
+            address: Register,
+            source: Register,
+        },
+        /// Compares `rax` against memory implicitly.
+        LockCmpxchg {
+            address: Register,
+            source: Register,
+        },
+        /// Swaps the contents of two registers.
+        XchgRegisters {
+            lhs: Register,
+            rhs: Register,
+        },
+        Mfence,
+        /// Inline assembly, printed as is.
+        Verbatim(&'a str),
+        Push(Register),
+        Pop(Register),
+
         Ret,
     }
 
@@ -261,6 +436,8 @@ mod x86_64_nasm {
         NE,
         O,
         NO,
+        // unsigned comparison, used for bound checks.
+        A,
     }
 
     impl Condition {
@@ -282,13 +459,14 @@ mod x86_64_nasm {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             f.write_str(match self {
                 Condition::LE => "le",
-                Condition::GT => "gt",
+                Condition::GT => "g",
                 Condition::GE => "ge",
-                Condition::LT => "lt",
+                Condition::LT => "l",
                 Condition::EQ => "e",
                 Condition::NE => "ne",
                 Self::O => "o",
                 Self::NO => "no",
+                Self::A => "a",
             })
         }
     }
@@ -312,13 +490,342 @@ mod x86_64_nasm {
                 Self::Ret => f.write_str("ret"),
                 Self::Xor { lhs, rhs } => write!(f, "xorq {}, {}", lhs.name(), rhs),
                 AssemblyOp::Sub { lhs, rhs } => write!(f, "sub {}, {}", lhs.name(), rhs),
+                AssemblyOp::Adc { lhs, rhs } => write!(f, "adc {}, {}", lhs.name(), rhs),
+                AssemblyOp::Sbb { lhs, rhs } => write!(f, "sbb {}, {}", lhs.name(), rhs),
+                AssemblyOp::Not(register) => write!(f, "not {}", register.name()),
+                AssemblyOp::Neg(register) => write!(f, "neg {}", register.name()),
+                AssemblyOp::Inc(register) => write!(f, "inc {}", register.name()),
+                AssemblyOp::Dec(register) => write!(f, "dec {}", register.name()),
+                AssemblyOp::Lea {
+                    dest,
+                    base,
+                    index,
+                    displacement,
+                } => {
+                    write!(f, "lea {}, [{}", dest.name(), base.name())?;
+                    if let Some(index) = index {
+                        write!(f, " + {}", index.name())?;
+                    }
+                    match displacement {
+                        0 => (),
+                        d if *d < 0 => write!(f, " - {}", d.unsigned_abs())?,
+                        d => write!(f, " + {}", d)?,
+                    }
+                    f.write_str("]")
+                }
+                AssemblyOp::And { lhs, rhs } => write!(f, "and {}, {}", lhs.name(), rhs),
+                AssemblyOp::Or { lhs, rhs } => write!(f, "or {}, {}", lhs.name(), rhs),
+                AssemblyOp::Shl { lhs, amount } => write!(f, "shl {}, {}", lhs.name(), amount),
+                AssemblyOp::Shr { lhs, amount } => write!(f, "shr {}, {}", lhs.name(), amount),
+                AssemblyOp::Popcnt { dest, source } => {
+                    write!(f, "popcnt {}, {}", dest.name(), source.name())
+                }
+                AssemblyOp::Lzcnt { dest, source } => {
+                    write!(f, "lzcnt {}, {}", dest.name(), source.name())
+                }
+                AssemblyOp::Tzcnt { dest, source } => {
+                    write!(f, "tzcnt {}, {}", dest.name(), source.name())
+                }
+                AssemblyOp::Bswap(register) => write!(f, "bswap {}", register.name()),
+                AssemblyOp::Cmc => f.write_str("cmc"),
+                AssemblyOp::Stc => f.write_str("stc"),
+                AssemblyOp::Bt { register, bit } => write!(f, "bt {}, {}", register.name(), bit),
+                AssemblyOp::Set {
+                    register,
+                    condition,
+                } => write!(f, "set{} {}", condition, register.byte_name()),
                 AssemblyOp::Jump { label } => write!(f, "jmp {}", label),
                 AssemblyOp::CJump { label, condition } => write!(f, "j{} {}", condition, label),
+                AssemblyOp::Cmp { lhs, rhs } => write!(f, "cmp {}, {}", lhs.name(), rhs),
+                AssemblyOp::Load { dest, address } => {
+                    write!(f, "mov {}, qword ptr [{}]", dest.name(), address.name())
+                }
+                AssemblyOp::Store { address, source } => {
+                    write!(f, "mov qword ptr [{}], {}", address.name(), source.name())
+                }
+                AssemblyOp::LockAdd { address, source } => {
+                    write!(
+                        f,
+                        "lock add qword ptr [{}], {}",
+                        address.name(),
+                        source.name()
+                    )
+                }
+                AssemblyOp::LockXadd { address, source } => {
+                    write!(
+                        f,
+                        "lock xadd qword ptr [{}], {}",
+                        address.name(),
+                        source.name()
+                    )
+                }
+                AssemblyOp::Xchg { address, source } => {
+                    write!(f, "xchg qword ptr [{}], {}", address.name(), source.name())
+                }
+                AssemblyOp::LockCmpxchg { address, source } => write!(
+                    f,
+                    "lock cmpxchg qword ptr [{}], {}",
+                    address.name(),
+                    source.name()
+                ),
+                AssemblyOp::XchgRegisters { lhs, rhs } => {
+                    write!(f, "xchg {}, {}", lhs.name(), rhs.name())
+                }
+                AssemblyOp::Mfence => f.write_str("mfence"),
+                AssemblyOp::Verbatim(template) => f.write_str(template),
+                AssemblyOp::Push(register) => write!(f, "push {}", register.name()),
+                AssemblyOp::Pop(register) => write!(f, "pop {}", register.name()),
+                AssemblyOp::LeaLabel { dest, label } => {
+                    write!(f, "lea {}, [rip + {}]", dest.name(), label)
+                }
+                AssemblyOp::LoadTableEntry { dest, table, index } => write!(
+                    f,
+                    "movsxd {}, dword ptr [{} + {}*4]",
+                    dest.name(),
+                    table.name(),
+                    index.name()
+                ),
+                AssemblyOp::JumpRegister(register) => write!(f, "jmp {}", register.name()),
             }
         }
     }
 }
 
+/// Registers that a jump table can be computed in, the ones that calls don't preserve first.
+const JUMP_TABLE_SCRATCH: [Register; 14] = [
+    Register::R11,
+    Register::R10,
+    Register::R9,
+    Register::R8,
+    Register::Rax,
+    Register::Rcx,
+    Register::Rdx,
+    Register::Rsi,
+    Register::Rdi,
+    Register::Rbx,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+/// x86 has no instruction to reverse the bits of a value, so after reversing the bytes we swap
+/// the nibbles, the bit pairs and the bits inside each byte with masks. Returns the last operation.
+fn expand_bit_reverse_in_bytes<'a>(
+    register: Register,
+    assembly: &mut Vec<AssemblyOp<'a>>,
+) -> AssemblyOp<'a> {
+    // two scratch registers that are preserved in the stack
+    let mut scratch = [Register::Rax, Register::Rbx, Register::Rcx]
+        .into_iter()
+        .filter(|scratch| *scratch != register);
+    // SAFE: at most one of the three is filtered out
+    let (shifted, mask) = unsafe {
+        (
+            scratch.next().unwrap_unchecked(),
+            scratch.next().unwrap_unchecked(),
+        )
+    };
+    assembly.push(AssemblyOp::Push(shifted));
+    assembly.push(AssemblyOp::Push(mask));
+    for (amount, mask_value) in [
+        (4, 0x0F0F_0F0F_0F0F_0F0F),
+        (2, 0x3333_3333_3333_3333),
+        (1, 0x5555_5555_5555_5555),
+    ] {
+        // register = ((register >> amount) & mask) | ((register & mask) << amount)
+        assembly.push(AssemblyOp::Mov {
+            dest: shifted,
+            source: DataSource::Register(register),
+        });
+        assembly.push(AssemblyOp::Shr {
+            lhs: shifted,
+            amount,
+        });
+        assembly.push(AssemblyOp::Mov {
+            dest: mask,
+            source: DataSource::Constant(mask_value),
+        });
+        assembly.push(AssemblyOp::And {
+            lhs: shifted,
+            rhs: DataSource::Register(mask),
+        });
+        assembly.push(AssemblyOp::And {
+            lhs: register,
+            rhs: DataSource::Register(mask),
+        });
+        assembly.push(AssemblyOp::Shl {
+            lhs: register,
+            amount,
+        });
+        assembly.push(AssemblyOp::Or {
+            lhs: register,
+            rhs: DataSource::Register(shifted),
+        });
+    }
+    assembly.push(AssemblyOp::Pop(mask));
+    AssemblyOp::Pop(shifted)
+}
+
+/// What an instruction does with the status flags.
+enum FlagEffect {
+    /// It reads them, or whatever comes after it might.
+    Read,
+    /// It overwrites them without reading them.
+    Write,
+    /// It leaves them alone, or it only sets some of them.
+    Keep,
+}
+
+fn flag_effect(op: &AssemblyOp) -> FlagEffect {
+    match op {
+        AssemblyOp::Adc { .. }
+        | AssemblyOp::Sbb { .. }
+        | AssemblyOp::Cmc
+        | AssemblyOp::Set { .. }
+        | AssemblyOp::CJump { .. }
+        | AssemblyOp::Jump { .. }
+        | AssemblyOp::JumpRegister(_)
+        | AssemblyOp::Verbatim(_) => FlagEffect::Read,
+        AssemblyOp::Add { .. }
+        | AssemblyOp::Sub { .. }
+        | AssemblyOp::Xor { .. }
+        | AssemblyOp::And { .. }
+        | AssemblyOp::Or { .. }
+        | AssemblyOp::Neg(_)
+        | AssemblyOp::Cmp { .. }
+        | AssemblyOp::Popcnt { .. }
+        | AssemblyOp::Lzcnt { .. }
+        | AssemblyOp::Tzcnt { .. }
+        | AssemblyOp::LockAdd { .. }
+        | AssemblyOp::LockXadd { .. }
+        | AssemblyOp::LockCmpxchg { .. }
+        | AssemblyOp::Call { .. }
+        | AssemblyOp::Ret => FlagEffect::Write,
+        _ => FlagEffect::Keep,
+    }
+}
+
+/// Rewrites instructions into shorter ones that do the same: moves into the same register are
+/// removed, zeros are set with `xor`, adding one with `inc`, and moving then adding with `lea`.
+/// Most of these change the flags, so they're only done when nothing reads them. Instructions
+/// aren't combined across labels, and the labels are moved along with their instructions.
+fn peephole(assembly: &mut Vec<AssemblyOp<'_>>, label_offsets: &mut [u16]) {
+    // labels aren't in the order they're placed in once the blocks are laid out.
+    let label_starts = label_offsets.iter().copied().collect::<HashSet<_>>();
+    let is_label = |offset: usize| label_starts.contains(&(offset as u16));
+    let mut flags_read = vec![true; assembly.len()];
+    let mut read = true;
+    for (index, op) in assembly.iter().enumerate().rev() {
+        flags_read[index] = read;
+        read = match flag_effect(op) {
+            FlagEffect::Read => true,
+            FlagEffect::Write => false,
+            FlagEffect::Keep => read,
+        };
+        if is_label(index) {
+            read = true;
+        }
+    }
+
+    let ops = std::mem::take(assembly);
+    let mut new_offsets = Vec::with_capacity(ops.len() + 1);
+    let mut ops = ops.into_iter().enumerate().peekable();
+    while let Some((index, op)) = ops.next() {
+        new_offsets.push(assembly.len() as u16);
+        let flags_unused = !flags_read[index];
+        let op = match op {
+            AssemblyOp::Mov {
+                dest,
+                source: DataSource::Register(source),
+            } if dest == source => continue,
+            AssemblyOp::XchgRegisters { lhs, rhs } if lhs == rhs => continue,
+            AssemblyOp::Mov {
+                dest,
+                source: DataSource::Constant(0),
+            } if flags_unused => AssemblyOp::Xor {
+                lhs: dest,
+                rhs: DataSource::Register(dest),
+            },
+            AssemblyOp::Add {
+                rhs: DataSource::Constant(0),
+                ..
+            }
+            | AssemblyOp::Sub {
+                rhs: DataSource::Constant(0),
+                ..
+            } if flags_unused => continue,
+            AssemblyOp::Add {
+                lhs,
+                rhs: DataSource::Constant(1),
+            }
+            | AssemblyOp::Sub {
+                lhs,
+                rhs: DataSource::Constant(u64::MAX),
+            } if flags_unused => AssemblyOp::Inc(lhs),
+            AssemblyOp::Add {
+                lhs,
+                rhs: DataSource::Constant(u64::MAX),
+            }
+            | AssemblyOp::Sub {
+                lhs,
+                rhs: DataSource::Constant(1),
+            } if flags_unused => AssemblyOp::Dec(lhs),
+            AssemblyOp::Mov { dest, source } => {
+                let lea = match ops.peek() {
+                    Some((add_index, AssemblyOp::Add { lhs, rhs }))
+                        if *lhs == dest && !flags_read[*add_index] && !is_label(*add_index) =>
+                    {
+                        lea(dest, source, *rhs)
+                    }
+                    _ => None,
+                };
+                match lea {
+                    Some(lea) => {
+                        ops.next();
+                        new_offsets.push(assembly.len() as u16);
+                        lea
+                    }
+                    None => AssemblyOp::Mov { dest, source },
+                }
+            }
+            op => op,
+        };
+        assembly.push(op);
+    }
+    new_offsets.push(assembly.len() as u16);
+
+    for offset in label_offsets.iter_mut() {
+        *offset = new_offsets[*offset as usize];
+    }
+}
+
+/// `mov dest, source` followed by `add dest, addend` as a single `lea`, when the operands fit in
+/// an address.
+fn lea<'a>(
+    dest: Register,
+    source: DataSource<'a>,
+    addend: DataSource<'a>,
+) -> Option<AssemblyOp<'a>> {
+    let displacement = |value: u64| i32::try_from(value as i64).ok();
+    let (base, index, displacement) = match (source, addend) {
+        // adding the destination to itself reads it after the move.
+        (_, DataSource::Register(addend)) if addend == dest => return None,
+        (DataSource::Register(base), DataSource::Register(index)) => (base, Some(index), 0),
+        (DataSource::Register(base), DataSource::Constant(value))
+        | (DataSource::Constant(value), DataSource::Register(base)) => {
+            (base, None, displacement(value)?)
+        }
+        _ => return None,
+    };
+    Some(AssemblyOp::Lea {
+        dest,
+        base,
+        index,
+        displacement,
+    })
+}
+
 impl Architecture for X86_64Nasm {
     fn index_from_register(name: &str) -> Option<index::Register> {
         use x86_64_nasm::Register::*;
@@ -331,6 +838,7 @@ impl Architecture for X86_64Nasm {
             "rdx" => Rdx,
             "rsi" => Rsi,
             "rdi" => Rdi,
+            "r8" => R8,
             "r9" => R9,
             "r10" => R10,
             "r11" => R11,
@@ -351,6 +859,23 @@ impl Architecture for X86_64Nasm {
         .with_frame_pointer(Register::Rbp.as_index())
         .with_status_flags(Flags::all())
     }
+    fn syscall_convention() -> SyscallConvention {
+        use x86_64_nasm::Register::*;
+        SyscallConvention {
+            instruction: "syscall",
+            number: Rax.as_index(),
+            arguments: [Rdi, Rsi, Rdx, R10, R8, R9]
+                .map(Register::as_index)
+                .to_vec(),
+            result: Rax.as_index(),
+            // the kernel stores the return address in rcx and the flags in r11.
+            clobbers: vec![Rcx.as_index(), R11.as_index()],
+        }
+    }
+    fn fits_arithmetic_immediate(value: u64) -> bool {
+        // immediates are 32 bits wide, and sign extended to 64.
+        i32::try_from(value as i64).is_ok()
+    }
     fn assemble<'label, W: std::io::Write>(
         mut ir: crate::llir::IR,
         exported_labels: &[&'label str],
@@ -369,78 +894,78 @@ impl Architecture for X86_64Nasm {
             }
         };
 
+        let jump_table_names = (0..ir.jump_tables.len())
+            .map(|index| format!(".LJT{}", index))
+            .collect::<Vec<_>>();
+
+        let flags_read = crate::llir::flags_read_after(&ir.ops, &ir.label_offsets);
         let mut assembly = Vec::with_capacity(ir.ops.len());
+        let placement = ir.labels_in_placement_order();
         let mut last_start = 0;
-        let mut total_offset = 0;
-        let mut dummy_uninit = MaybeUninit::uninit();
-        let mut new_offsets = Box::new_uninit_slice(ir.label_offsets.len());
-        for (offset, new_offset) in ir
-            .label_offsets
+        let mut new_offsets = vec![0; ir.label_offsets.len()].into_boxed_slice();
+        for (offset, label) in placement
             .iter()
-            .copied()
-            .zip(new_offsets.iter_mut())
-            .chain(Some((ir.ops.len() as u16, &mut dummy_uninit)))
+            .map(|label| {
+                let index = unsafe { label.to_index() } as usize;
+                (ir.label_offsets[index], Some(index))
+            })
+            .chain(Some((ir.ops.len() as u16, None)))
         {
-            for op in &ir.ops[last_start as usize..offset as usize] {
+            for op_index in last_start as usize..offset as usize {
+                let op = &ir.ops[op_index];
                 let pushed_op = match op {
                     crate::llir::Op::CopyRegister { target, source } => AssemblyOp::Mov {
                         dest: Register::expect_from_number(*target),
                         source: DataSource::Register(Register::expect_from_number(*source)),
                     },
+                    crate::llir::Op::SwapRegisters { lhs, rhs } => AssemblyOp::XchgRegisters {
+                        lhs: Register::expect_from_number(*lhs),
+                        rhs: Register::expect_from_number(*rhs),
+                    },
                     crate::llir::Op::SetValue { target, value } => AssemblyOp::Mov {
                         dest: Register::expect_from_number(*target),
                         source: constant_to_ds(value),
                     },
                     crate::llir::Op::USub { target, lhs, rhs } => {
-                        'b: {
-                            if let Input::Register(r) = rhs {
-                                match (target == lhs, target == r) {
-                                    // this one can escape, since we're going to set a zero.
-                                    // TODO: make it output xor instead of mov.
-                                    (true, true) => {
-                                        let reg = Register::expect_from_number(*target);
-                                        break 'b AssemblyOp::Xor {
-                                            lhs: reg,
-                                            rhs: DataSource::Register(reg),
-                                        };
-                                    }
-                                    // this is a tricky one: we'll make the reversed subtraction
-                                    // and then negate it using XOR
-                                    (false, true) => {
-                                        let reg = Register::expect_from_number(*target);
-                                        assembly.push(AssemblyOp::Sub {
-                                            lhs: reg,
-                                            rhs: DataSource::Register(
-                                                Register::expect_from_number(*r),
-                                            ),
-                                        });
-                                        assembly.push(AssemblyOp::Xor {
-                                            lhs: reg,
-                                            rhs: DataSource::Constant(0xFFFFFFFFFFFFFFFF),
-                                        });
-                                        total_offset += 2;
-                                        break 'b AssemblyOp::Add {
-                                            lhs: reg,
-                                            rhs: DataSource::Constant(1),
-                                        };
+                        let target = Register::expect_from_number(*target);
+                        let lhs = Register::expect_from_number(*lhs);
+                        match input_to_ds(rhs) {
+                            DataSource::Register(rhs) if rhs == target && rhs == lhs => {
+                                AssemblyOp::Xor {
+                                    lhs: target,
+                                    rhs: DataSource::Register(target),
+                                }
+                            }
+                            rhs if target == lhs => AssemblyOp::Sub { lhs: target, rhs },
+                            // the operands are the other way around. Adding lhs to -rhs only
+                            // gets some of the flags right, so when they're read the subtraction
+                            // is done in lhs, which is restored afterwards.
+                            DataSource::Register(rhs) if rhs == target => {
+                                if flags_read[op_index] {
+                                    assembly.push(AssemblyOp::Push(lhs));
+                                    assembly.push(AssemblyOp::Sub {
+                                        lhs,
+                                        rhs: DataSource::Register(target),
+                                    });
+                                    assembly.push(AssemblyOp::Mov {
+                                        dest: target,
+                                        source: DataSource::Register(lhs),
+                                    });
+                                    AssemblyOp::Pop(lhs)
+                                } else {
+                                    assembly.push(AssemblyOp::Neg(target));
+                                    AssemblyOp::Add {
+                                        lhs: target,
+                                        rhs: DataSource::Register(lhs),
                                     }
-                                    _ => (),
                                 }
                             }
-                            let treg = Register::expect_from_number(*target);
-                            if target != lhs {
+                            rhs => {
                                 assembly.push(AssemblyOp::Mov {
-                                    dest: treg,
-                                    source: DataSource::Register(Register::expect_from_number(
-                                        *lhs,
-                                    )),
+                                    dest: target,
+                                    source: DataSource::Register(lhs),
                                 });
-                                total_offset += 1;
-                            }
-
-                            AssemblyOp::Add {
-                                lhs: Register::expect_from_number(*target),
-                                rhs: input_to_ds(rhs),
+                                AssemblyOp::Sub { lhs: target, rhs }
                             }
                         }
                     }
@@ -462,7 +987,6 @@ impl Architecture for X86_64Nasm {
                                             *r,
                                         )),
                                     });
-                                    total_offset += 1;
 
                                     (
                                         target,
@@ -475,7 +999,6 @@ impl Architecture for X86_64Nasm {
                                     dest: target,
                                     source: input_to_ds(rhs),
                                 });
-                                total_offset += 1;
                                 (
                                     target,
                                     DataSource::Register(Register::expect_from_number(*lhs)),
@@ -496,32 +1019,384 @@ impl Architecture for X86_64Nasm {
                     crate::llir::Op::Branch { target } => AssemblyOp::Jump {
                         label: &ir.label_names[unsafe { target.to_index() } as usize],
                     },
+                    crate::llir::Op::Compare { lhs, rhs } => {
+                        let lhs = Register::expect_from_number(*lhs);
+                        match input_to_ds(rhs) {
+                            // there's no room for it in the instruction, so it goes through a
+                            // register that is restored afterwards, which leaves the flags alone.
+                            DataSource::Constant(value)
+                                if !Self::fits_arithmetic_immediate(value) =>
+                            {
+                                let scratch = if lhs == Register::Rax {
+                                    Register::Rcx
+                                } else {
+                                    Register::Rax
+                                };
+                                assembly.push(AssemblyOp::Push(scratch));
+                                assembly.push(AssemblyOp::Mov {
+                                    dest: scratch,
+                                    source: DataSource::Constant(value),
+                                });
+                                assembly.push(AssemblyOp::Cmp {
+                                    lhs,
+                                    rhs: DataSource::Register(scratch),
+                                });
+                                AssemblyOp::Pop(scratch)
+                            }
+                            rhs => AssemblyOp::Cmp { lhs, rhs },
+                        }
+                    }
+                    crate::llir::Op::JumpTable { index, table } => {
+                        let table_index = *table as usize;
+                        let table = &ir.jump_tables[table_index];
+                        let index = Register::expect_from_number(*index);
+                        let default = &ir.label_names[unsafe { table.default.to_index() } as usize];
+                        // nothing reads the rest of the registers after jumping, so the jump is
+                        // computed in them.
+                        let mut scratch = JUMP_TABLE_SCRATCH.into_iter().filter(|register| {
+                            *register != index && !table.live.contains(&(*register as u8))
+                        });
+                        let (entry, address) = scratch
+                            .next()
+                            .zip(scratch.next())
+                            .expect("a switch can't pass along all of the registers");
+                        assembly.push(AssemblyOp::Mov {
+                            dest: entry,
+                            source: DataSource::Register(index),
+                        });
+                        if table.base != 0 {
+                            let base = if Self::fits_arithmetic_immediate(table.base) {
+                                DataSource::Constant(table.base)
+                            } else {
+                                assembly.push(AssemblyOp::Mov {
+                                    dest: address,
+                                    source: DataSource::Constant(table.base),
+                                });
+                                DataSource::Register(address)
+                            };
+                            assembly.push(AssemblyOp::Sub {
+                                lhs: entry,
+                                rhs: base,
+                            });
+                        }
+                        // values below the base wrap around, so a single unsigned comparison
+                        // bound checks the index. Values out of the table go to default.
+                        assembly.push(AssemblyOp::Cmp {
+                            lhs: entry,
+                            rhs: DataSource::Constant(table.targets.len() as u64 - 1),
+                        });
+                        assembly.push(AssemblyOp::CJump {
+                            label: default,
+                            condition: x86_64_nasm::Condition::A,
+                        });
+                        // the entries are relative to the table, so that they don't need to be
+                        // relocated.
+                        assembly.push(AssemblyOp::LeaLabel {
+                            dest: address,
+                            label: &jump_table_names[table_index],
+                        });
+                        assembly.push(AssemblyOp::LoadTableEntry {
+                            dest: entry,
+                            table: address,
+                            index: entry,
+                        });
+                        assembly.push(AssemblyOp::Add {
+                            lhs: entry,
+                            rhs: DataSource::Register(address),
+                        });
+                        AssemblyOp::JumpRegister(entry)
+                    }
+                    crate::llir::Op::CarryArithmetic {
+                        operation,
+                        target,
+                        lhs,
+                        rhs,
+                        carry_in,
+                    } => {
+                        let target = Register::expect_from_number(*target);
+                        let lhs = Register::expect_from_number(*lhs);
+                        let rhs = input_to_ds(rhs);
+                        // the carry goes in first, moves after it don't touch the flags.
+                        if let crate::llir::CarryIn::Register(carry) = carry_in {
+                            assembly.push(AssemblyOp::Bt {
+                                register: Register::expect_from_number(*carry),
+                                bit: 0,
+                            });
+                        }
+                        let with_carry = !matches!(carry_in, crate::llir::CarryIn::None);
+                        let add = |lhs, rhs| {
+                            if with_carry {
+                                AssemblyOp::Adc { lhs, rhs }
+                            } else {
+                                AssemblyOp::Add { lhs, rhs }
+                            }
+                        };
+                        let target_is_rhs = matches!(rhs, DataSource::Register(r) if r == target);
+                        match operation {
+                            CarryOperation::Add if target == lhs => add(target, rhs),
+                            CarryOperation::Add if target_is_rhs => {
+                                add(target, DataSource::Register(lhs))
+                            }
+                            CarryOperation::Sub if target == lhs => {
+                                if with_carry {
+                                    AssemblyOp::Sbb { lhs: target, rhs }
+                                } else {
+                                    AssemblyOp::Sub { lhs: target, rhs }
+                                }
+                            }
+                            // lhs - rhs - borrow = lhs + !rhs + !borrow, with the carry out being
+                            // the complement of the borrow out.
+                            CarryOperation::Sub if target_is_rhs => {
+                                assembly.push(AssemblyOp::Not(target));
+                                assembly.push(if with_carry {
+                                    AssemblyOp::Cmc
+                                } else {
+                                    AssemblyOp::Stc
+                                });
+                                assembly.push(AssemblyOp::Adc {
+                                    lhs: target,
+                                    rhs: DataSource::Register(lhs),
+                                });
+                                AssemblyOp::Cmc
+                            }
+                            operation => {
+                                assembly.push(AssemblyOp::Mov {
+                                    dest: target,
+                                    source: DataSource::Register(lhs),
+                                });
+                                match (operation, with_carry) {
+                                    (CarryOperation::Add, _) => add(target, rhs),
+                                    (CarryOperation::Sub, true) => {
+                                        AssemblyOp::Sbb { lhs: target, rhs }
+                                    }
+                                    (CarryOperation::Sub, false) => {
+                                        AssemblyOp::Sub { lhs: target, rhs }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    crate::llir::Op::Bit {
+                        operation,
+                        target,
+                        source,
+                    } => {
+                        let dest = Register::expect_from_number(*target);
+                        let source = Register::expect_from_number(*source);
+                        match operation {
+                            BitOperation::PopCount => AssemblyOp::Popcnt { dest, source },
+                            BitOperation::LeadingZeros => AssemblyOp::Lzcnt { dest, source },
+                            BitOperation::TrailingZeros => AssemblyOp::Tzcnt { dest, source },
+                            BitOperation::ByteSwap | BitOperation::BitReverse => {
+                                if dest != source {
+                                    assembly.push(AssemblyOp::Mov {
+                                        dest,
+                                        source: DataSource::Register(source),
+                                    });
+                                }
+                                if let BitOperation::ByteSwap = operation {
+                                    AssemblyOp::Bswap(dest)
+                                } else {
+                                    assembly.push(AssemblyOp::Bswap(dest));
+                                    expand_bit_reverse_in_bytes(dest, &mut assembly)
+                                }
+                            }
+                        }
+                    }
+                    // x86 leaves both the carry and the borrow in the carry flag.
+                    crate::llir::Op::SetCarry { target, .. } => {
+                        let target = Register::expect_from_number(*target);
+                        assembly.push(AssemblyOp::Mov {
+                            dest: target,
+                            source: DataSource::Constant(0),
+                        });
+                        AssemblyOp::Adc {
+                            lhs: target,
+                            rhs: DataSource::Constant(0),
+                        }
+                    }
+                    // the rest of the register is cleared first, with a move that leaves the flags
+                    // alone.
+                    crate::llir::Op::SetCondition { condition, target } => {
+                        let register = Register::expect_from_number(*target);
+                        assembly.push(AssemblyOp::Mov {
+                            dest: register,
+                            source: DataSource::Constant(0),
+                        });
+                        AssemblyOp::Set {
+                            register,
+                            condition: x86_64_nasm::Condition::from_ir(*condition),
+                        }
+                    }
+                    // x86 is strongly ordered: every plain load is an acquire and every plain
+                    // store is a release. Only sequentially consistent stores need a fence.
+                    crate::llir::Op::AtomicLoad {
+                        target, address, ..
+                    } => match target {
+                        Some(target) => AssemblyOp::Load {
+                            dest: Register::expect_from_number(*target),
+                            address: Register::expect_from_number(*address),
+                        },
+                        None => continue,
+                    },
+                    crate::llir::Op::AtomicStore {
+                        address,
+                        value,
+                        ordering,
+                    } => {
+                        let store = AssemblyOp::Store {
+                            address: Register::expect_from_number(*address),
+                            source: Register::expect_from_number(*value),
+                        };
+                        if *ordering == AtomicOrdering::SeqCst {
+                            assembly.push(store);
+                            AssemblyOp::Mfence
+                        } else {
+                            store
+                        }
+                    }
+                    crate::llir::Op::AtomicRmw {
+                        target,
+                        address,
+                        value,
+                        operation,
+                        ..
+                    } => {
+                        let address = Register::expect_from_number(*address);
+                        let value = Register::expect_from_number(*value);
+                        let rmw = |source| match operation {
+                            RmwOperation::Add => AssemblyOp::LockXadd { address, source },
+                            RmwOperation::Exchange => AssemblyOp::Xchg { address, source },
+                        };
+                        match target.map(Register::expect_from_number) {
+                            None if matches!(operation, RmwOperation::Add) => AssemblyOp::LockAdd {
+                                address,
+                                source: value,
+                            },
+                            // the value is not used after this, so it can be overwritten.
+                            Some(target) if target == value => rmw(target),
+                            Some(target) if target != address => {
+                                assembly.push(AssemblyOp::Mov {
+                                    dest: target,
+                                    source: DataSource::Register(value),
+                                });
+                                rmw(target)
+                            }
+                            // the previous value is loaded into the value register, so we
+                            // have to preserve it.
+                            target => {
+                                assembly.push(AssemblyOp::Push(value));
+                                assembly.push(rmw(value));
+                                if let Some(target) = target {
+                                    assembly.push(AssemblyOp::Mov {
+                                        dest: target,
+                                        source: DataSource::Register(value),
+                                    });
+                                }
+                                AssemblyOp::Pop(value)
+                            }
+                        }
+                    }
+                    crate::llir::Op::AtomicCas {
+                        target,
+                        address,
+                        expected,
+                        new,
+                        ..
+                    } => {
+                        let rax = Register::Rax;
+                        let address = Register::expect_from_number(*address);
+                        let expected = Register::expect_from_number(*expected);
+                        let new = Register::expect_from_number(*new);
+                        let target = target.map(Register::expect_from_number);
+                        // cmpxchg compares against rax and loads the previous value into it.
+                        if expected == rax {
+                            let cmpxchg = AssemblyOp::LockCmpxchg {
+                                address,
+                                source: new,
+                            };
+                            if target == Some(rax) {
+                                cmpxchg
+                            } else {
+                                assembly.push(AssemblyOp::Push(rax));
+                                assembly.push(cmpxchg);
+                                if let Some(target) = target {
+                                    assembly.push(AssemblyOp::Mov {
+                                        dest: target,
+                                        source: DataSource::Register(rax),
+                                    });
+                                }
+                                AssemblyOp::Pop(rax)
+                            }
+                        } else {
+                            // swap rax with the expected register, so that any operand that
+                            // was in rax can now be found in the expected register.
+                            let moved =
+                                |register| if register == rax { expected } else { register };
+                            let preserves_expected = target != Some(expected);
+                            if preserves_expected {
+                                assembly.push(AssemblyOp::Push(expected));
+                            }
+                            assembly.push(AssemblyOp::XchgRegisters {
+                                lhs: rax,
+                                rhs: expected,
+                            });
+                            assembly.push(AssemblyOp::LockCmpxchg {
+                                address: moved(address),
+                                source: moved(new),
+                            });
+                            if target != Some(rax) {
+                                // restore rax, the previous value ends up in the expected register.
+                                assembly.push(AssemblyOp::XchgRegisters {
+                                    lhs: rax,
+                                    rhs: expected,
+                                });
+                                if let Some(target) = target.filter(|target| *target != expected) {
+                                    assembly.push(AssemblyOp::Mov {
+                                        dest: target,
+                                        source: DataSource::Register(expected),
+                                    });
+                                }
+                            }
+                            if preserves_expected {
+                                AssemblyOp::Pop(expected)
+                            } else {
+                                continue;
+                            }
+                        }
+                    }
+                    crate::llir::Op::Fence(ordering) => match ordering {
+                        AtomicOrdering::SeqCst => AssemblyOp::Mfence,
+                        _ => continue,
+                    },
+                    crate::llir::Op::Asm { template } => AssemblyOp::Verbatim(template),
                 };
 
                 assembly.push(pushed_op);
             }
-            new_offset.write(offset + total_offset);
+            // labels point to wherever their ops ended up after expanding the previous ones.
+            if let Some(index) = label {
+                new_offsets[index] = assembly.len() as u16;
+            }
             last_start = offset;
         }
 
-        let new_offsets = unsafe { new_offsets.assume_init() };
+        peephole(&mut assembly, &mut new_offsets);
 
-        output.write(b".text\n.intel_syntax noprefix\n")?;
+        output.write_all(b".text\n.intel_syntax noprefix\n")?;
 
         for label in exported_labels {
             writeln!(output, ".global {}", label)?;
         }
 
         let mut last_start = 0;
-        for (label, start) in ir
-            .label_names
-            .iter()
-            .zip(crate::BoxIntoIter::new(new_offsets))
-        {
+        for label in placement {
+            let index = unsafe { label.to_index() } as usize;
+            let start = new_offsets[index];
             for op in &assembly[last_start as usize..start as usize] {
                 writeln!(output, "\t{}", op)?;
             }
-            writeln!(output, "{}:", label)?;
+            writeln!(output, "{}:", ir.label_names[index])?;
             last_start = start;
         }
 
@@ -529,6 +1404,21 @@ impl Architecture for X86_64Nasm {
             writeln!(output, "\t{}", op)?;
         }
 
+        if !ir.jump_tables.is_empty() {
+            output.write_all(b".section .rodata\n.balign 4\n")?;
+            for (name, table) in jump_table_names.iter().zip(ir.jump_tables.iter()) {
+                writeln!(output, "{}:", name)?;
+                for target in table.targets.iter() {
+                    writeln!(
+                        output,
+                        "\t.long {} - {}",
+                        ir.label_names[unsafe { target.to_index() } as usize],
+                        name
+                    )?;
+                }
+            }
+        }
+
         Ok(())
     }
 }
@@ -1,6 +1,10 @@
-use crate::{hlir::Condition, index};
+use super::{Architecture, Flags, RegisterSet, SyscallConvention};
+use crate::hlir::{AtomicOrdering, BitOperation, CarryOperation, Condition, RmwOperation};
+use crate::index;
+use crate::llir::Input;
+use crate::optir::Constant;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Register {
     GeneralPurpose { index: u8 },
     StackPointer,
@@ -10,10 +14,40 @@ pub enum Register {
 
 crate::impl_self_dependency_eq!(Register);
 
+// x16 and x17 are the intra-procedure-call scratch registers, so we keep them out of the
+// allocator and use them for expanding operations that need temporaries.
+const SCRATCH0: Register = Register::GeneralPurpose { index: 16 };
+const SCRATCH1: Register = Register::GeneralPurpose { index: 17 };
+
+impl Register {
+    const FRAME_POINTER_INDEX: u8 = 29;
+    const LINK_REGISTER_INDEX: u8 = 30;
+    const STACK_POINTER_INDEX: u8 = 31;
+
+    pub const fn from_number(num: u8) -> Self {
+        match num {
+            Self::FRAME_POINTER_INDEX => Self::FramePointer,
+            Self::LINK_REGISTER_INDEX => Self::LinkRegister,
+            Self::STACK_POINTER_INDEX => Self::StackPointer,
+            index => Self::GeneralPurpose { index },
+        }
+    }
+
+    pub const fn as_index(self) -> index::Register {
+        let index = match self {
+            Self::GeneralPurpose { index } => index,
+            Self::FramePointer => Self::FRAME_POINTER_INDEX,
+            Self::LinkRegister => Self::LINK_REGISTER_INDEX,
+            Self::StackPointer => Self::STACK_POINTER_INDEX,
+        };
+        unsafe { index::Register::from_index(index) }
+    }
+}
+
 impl core::fmt::Display for Register {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Register::GeneralPurpose { index } => write!(f, "w{}", index),
+            Register::GeneralPurpose { index } => write!(f, "x{}", index),
             Register::StackPointer => f.write_str("sp"),
             Register::FramePointer => f.write_str("fp"),
             Register::LinkRegister => f.write_str("lr"),
@@ -51,6 +85,29 @@ impl core::fmt::Display for CanBeConstant<'_> {
     }
 }
 
+fn condition_code(condition: Condition) -> &'static str {
+    match condition {
+        Condition::LessThan => "lt",
+        Condition::GreaterEqual => "ge",
+        Condition::LessEqual => "le",
+        Condition::GreaterThan => "gt",
+        Condition::Overflow => "vs",
+        Condition::NotOverflow => "vc",
+        Condition::Zero => "eq",
+        Condition::NotZero => "ne",
+    }
+}
+
+/// The suffix that gives acquire and/or release semantics to an LSE atomic instruction.
+fn ordering_suffix(ordering: AtomicOrdering) -> &'static str {
+    match ordering {
+        AtomicOrdering::Relaxed => "",
+        AtomicOrdering::Acquire => "a",
+        AtomicOrdering::Release => "l",
+        AtomicOrdering::AcqRel | AtomicOrdering::SeqCst => "al",
+    }
+}
+
 #[derive(Debug)]
 pub enum Op<'a> {
     Ret,
@@ -58,26 +115,691 @@ pub enum Op<'a> {
         target: Register,
         source: CanBeConstant<'a>,
     },
-    MvN {
+    /// Load a constant from the literal pool, used for constants that can't be encoded in a
+    /// `mov`.
+    LoadLiteral {
         target: Register,
-        source: CanBeConstant<'a>,
+        value: C<'a>,
     },
     Cmp {
         register: Register,
         data: CanBeConstant<'a>,
     },
+    /// Compare against the negated value, for immediates that only fit negated.
+    Cmn {
+        register: Register,
+        data: CanBeConstant<'a>,
+    },
     Cset {
         target: Register,
-        condition: Condition,
+        condition: &'static str,
     },
     Add {
         target: Register,
         lhs: Register,
         rhs: CanBeConstant<'a>,
     },
+    Eor {
+        target: Register,
+        lhs: Register,
+        rhs: Register,
+    },
     Sub {
         target: Register,
         lhs: Register,
         rhs: CanBeConstant<'a>,
     },
+    /// Add, setting the flags.
+    Adds {
+        target: Register,
+        lhs: Register,
+        rhs: CanBeConstant<'a>,
+    },
+    /// Add with the carry flag, setting it.
+    Adcs {
+        target: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    /// Subtract, setting the flags. The carry flag is cleared on borrow.
+    Subs {
+        target: Register,
+        lhs: Register,
+        rhs: CanBeConstant<'a>,
+    },
+    /// Subtract with the inverted carry flag as the borrow, clearing it on borrow.
+    Sbcs {
+        target: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    /// Negate, setting the carry flag only if `source` is zero.
+    Negs {
+        target: Register,
+        source: Register,
+    },
+    Bl {
+        label: &'a str,
+    },
+    /// Push the frame pointer and the link register, which calls overwrite.
+    SaveFrame,
+    /// Pop the frame pointer and the link register pushed by `SaveFrame`.
+    RestoreFrame,
+    B {
+        label: &'a str,
+    },
+    BCond {
+        condition: &'static str,
+        label: &'a str,
+    },
+    Br {
+        register: Register,
+    },
+    /// Load the address of a label, relative to the program counter.
+    Address {
+        target: Register,
+        label: &'a str,
+    },
+    /// Load the 4-byte element `index` out of an array at `base`, sign extending it.
+    LoadTableEntry {
+        target: Register,
+        base: Register,
+        index: Register,
+    },
+    /// Load from an address, with acquire semantics if `acquire` is set.
+    Load {
+        target: Register,
+        address: Register,
+        acquire: bool,
+    },
+    /// Store into an address, with release semantics if `release` is set.
+    Store {
+        source: Register,
+        address: Register,
+        release: bool,
+    },
+    /// LSE atomic add, loads the previous value into `target`.
+    Ldadd {
+        value: Register,
+        target: Register,
+        address: Register,
+        ordering: AtomicOrdering,
+    },
+    /// LSE atomic swap, loads the previous value into `target`.
+    Swp {
+        value: Register,
+        target: Register,
+        address: Register,
+        ordering: AtomicOrdering,
+    },
+    /// LSE compare and swap, `compare` gets the previous value.
+    Cas {
+        compare: Register,
+        new: Register,
+        address: Register,
+        ordering: AtomicOrdering,
+    },
+    /// Data memory barrier. Only waits for loads if `loads_only` is set.
+    Dmb {
+        loads_only: bool,
+    },
+    /// Inline assembly, printed as is.
+    Verbatim(&'a str),
+    Clz {
+        target: Register,
+        source: Register,
+    },
+    Rbit {
+        target: Register,
+        source: Register,
+    },
+    Rev {
+        target: Register,
+        source: Register,
+    },
+    /// Count the bits that are set, through the SIMD scratch register, since there is no
+    /// general purpose instruction for it before the CSSC extension.
+    PopCount {
+        target: Register,
+        source: Register,
+    },
+}
+
+impl core::fmt::Display for Op<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Ret => f.write_str("ret"),
+            Op::Mov { target, source } => write!(f, "mov {}, {}", target, source),
+            Op::LoadLiteral { target, value } => write!(f, "ldr {}, ={}", target, value),
+            Op::Cmp { register, data } => write!(f, "cmp {}, {}", register, data),
+            Op::Cmn { register, data } => write!(f, "cmn {}, {}", register, data),
+            Op::Cset { target, condition } => write!(f, "cset {}, {}", target, condition),
+            Op::Add { target, lhs, rhs } => write!(f, "add {}, {}, {}", target, lhs, rhs),
+            Op::Eor { target, lhs, rhs } => write!(f, "eor {}, {}, {}", target, lhs, rhs),
+            Op::Sub { target, lhs, rhs } => write!(f, "sub {}, {}, {}", target, lhs, rhs),
+            Op::Adds { target, lhs, rhs } => write!(f, "adds {}, {}, {}", target, lhs, rhs),
+            Op::Adcs { target, lhs, rhs } => write!(f, "adcs {}, {}, {}", target, lhs, rhs),
+            Op::Subs { target, lhs, rhs } => write!(f, "subs {}, {}, {}", target, lhs, rhs),
+            Op::Sbcs { target, lhs, rhs } => write!(f, "sbcs {}, {}, {}", target, lhs, rhs),
+            Op::Negs { target, source } => write!(f, "negs {}, {}", target, source),
+            Op::Bl { label } => write!(f, "bl {}", label),
+            Op::SaveFrame => f.write_str("stp x29, x30, [sp, -16]!"),
+            Op::RestoreFrame => f.write_str("ldp x29, x30, [sp], 16"),
+            Op::B { label } => write!(f, "b {}", label),
+            Op::BCond { condition, label } => write!(f, "b.{} {}", condition, label),
+            Op::Br { register } => write!(f, "br {}", register),
+            Op::Address { target, label } => write!(
+                f,
+                "adrp {}, {}\n\tadd {}, {}, :lo12:{}",
+                target, label, target, target, label
+            ),
+            Op::LoadTableEntry {
+                target,
+                base,
+                index,
+            } => write!(f, "ldrsw {}, [{}, {}, lsl #2]", target, base, index),
+            Op::Load {
+                target,
+                address,
+                acquire,
+            } => write!(
+                f,
+                "{} {}, [{}]",
+                if *acquire { "ldar" } else { "ldr" },
+                target,
+                address
+            ),
+            Op::Store {
+                source,
+                address,
+                release,
+            } => write!(
+                f,
+                "{} {}, [{}]",
+                if *release { "stlr" } else { "str" },
+                source,
+                address
+            ),
+            Op::Ldadd {
+                value,
+                target,
+                address,
+                ordering,
+            } => write!(
+                f,
+                "ldadd{} {}, {}, [{}]",
+                ordering_suffix(*ordering),
+                value,
+                target,
+                address
+            ),
+            Op::Swp {
+                value,
+                target,
+                address,
+                ordering,
+            } => write!(
+                f,
+                "swp{} {}, {}, [{}]",
+                ordering_suffix(*ordering),
+                value,
+                target,
+                address
+            ),
+            Op::Cas {
+                compare,
+                new,
+                address,
+                ordering,
+            } => write!(
+                f,
+                "cas{} {}, {}, [{}]",
+                ordering_suffix(*ordering),
+                compare,
+                new,
+                address
+            ),
+            Op::Verbatim(template) => f.write_str(template),
+            Op::Clz { target, source } => write!(f, "clz {}, {}", target, source),
+            Op::Rbit { target, source } => write!(f, "rbit {}, {}", target, source),
+            Op::Rev { target, source } => write!(f, "rev {}, {}", target, source),
+            Op::PopCount { target, source } => write!(
+                f,
+                "fmov d31, {}\n\tcnt v31.8b, v31.8b\n\taddv b31, v31.8b\n\tfmov {}, d31",
+                source, target
+            ),
+            Op::Dmb { loads_only } => {
+                f.write_str(if *loads_only { "dmb ishld" } else { "dmb ish" })
+            }
+        }
+    }
+}
+
+/// Maximum value that fits in a `mov` immediate without shifting.
+const MOV_IMMEDIATE_MAX: u64 = 0xFFFF;
+/// Maximum value that fits in an `add`/`sub`/`cmp` immediate without shifting.
+const ARITHMETIC_IMMEDIATE_MAX: u64 = 0xFFF;
+
+/// Arithmetic immediates are 12 bits wide, optionally shifted left by 12.
+fn fits_arithmetic_immediate(value: u64) -> bool {
+    value <= ARITHMETIC_IMMEDIATE_MAX
+        || (value & ARITHMETIC_IMMEDIATE_MAX == 0 && value >> 12 <= ARITHMETIC_IMMEDIATE_MAX)
+}
+
+/// Makes a constant usable as an arithmetic operand, loading it into `scratch` if it can't be
+/// encoded as an immediate.
+fn arithmetic_operand<'a>(
+    value: u64,
+    scratch: Register,
+    ops: &mut Vec<Op<'a>>,
+) -> CanBeConstant<'a> {
+    if fits_arithmetic_immediate(value) {
+        CanBeConstant::Constant(C::U(value))
+    } else {
+        ops.push(Op::LoadLiteral {
+            target: scratch,
+            value: C::U(value),
+        });
+        CanBeConstant::Register(scratch)
+    }
+}
+
+pub struct Aarch64;
+
+impl Architecture for Aarch64 {
+    fn index_from_register(name: &str) -> Option<index::Register> {
+        let register = match name {
+            "sp" => Register::StackPointer,
+            "fp" | "x29" => Register::FramePointer,
+            "lr" | "x30" => Register::LinkRegister,
+            _ => {
+                let index = name.strip_prefix('x')?.parse::<u8>().ok()?;
+                if index >= Register::FRAME_POINTER_INDEX {
+                    return None;
+                }
+                Register::GeneralPurpose { index }
+            }
+        };
+        Some(register.as_index())
+    }
+
+    fn register_set() -> RegisterSet {
+        RegisterSet::new(index::RegisterRange { start: 0, end: 16 })
+            .with_stack_pointer(Register::StackPointer.as_index())
+            .with_frame_pointer(Register::FramePointer.as_index())
+            .with_status_flags(Flags::all())
+    }
+
+    fn syscall_convention() -> SyscallConvention {
+        let x = |index| Register::GeneralPurpose { index }.as_index();
+        SyscallConvention {
+            instruction: "svc #0",
+            number: x(8),
+            arguments: (0..6).map(x).collect(),
+            result: x(0),
+            clobbers: Vec::new(),
+        }
+    }
+
+    fn fits_arithmetic_immediate(value: u64) -> bool {
+        fits_arithmetic_immediate(value)
+    }
+
+    fn assemble<W: std::io::Write>(
+        ir: crate::llir::IR,
+        exported_labels: &[&str],
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        let constant = |c: &Constant| match c {
+            Constant::Numeric(n) => C::U(*n),
+            Constant::Label(l) => C::Label(exported_labels[unsafe { l.to_index() } as usize]),
+        };
+        let label_name =
+            |label: &index::Label| &ir.label_names[unsafe { label.to_index() } as usize];
+        let jump_table_names = (0..ir.jump_tables.len())
+            .map(|index| format!(".LJT{}", index))
+            .collect::<Vec<_>>();
+        let flags_read = crate::llir::flags_read_after(&ir.ops, &ir.label_offsets);
+
+        output.write_all(b".text\n")?;
+        for label in exported_labels {
+            writeln!(output, ".global {}", label)?;
+        }
+
+        let mut labels = ir
+            .labels_in_placement_order()
+            .into_iter()
+            .map(|label| {
+                let index = unsafe { label.to_index() } as usize;
+                (ir.label_offsets[index], &ir.label_names[index])
+            })
+            .peekable();
+        let mut ops = Vec::new();
+        for (op_index, op) in ir.ops.iter().enumerate() {
+            while let Some((_, label)) = labels.next_if(|(offset, _)| *offset as usize == op_index)
+            {
+                writeln!(output, "{}:", label)?;
+            }
+
+            let reg = |r: &u8| Register::from_number(*r);
+            match op {
+                crate::llir::Op::CopyRegister { target, source } => ops.push(Op::Mov {
+                    target: reg(target),
+                    source: CanBeConstant::Register(reg(source)),
+                }),
+                // there is no exchange between registers, but three xors swap them without a
+                // scratch register.
+                crate::llir::Op::SwapRegisters { lhs, rhs } => {
+                    let (lhs, rhs) = (reg(lhs), reg(rhs));
+                    for (target, source) in [(lhs, rhs), (rhs, lhs), (lhs, rhs)] {
+                        ops.push(Op::Eor {
+                            target,
+                            lhs: target,
+                            rhs: source,
+                        });
+                    }
+                }
+                crate::llir::Op::SetValue { target, value } => match value {
+                    Constant::Numeric(n) if *n <= MOV_IMMEDIATE_MAX => ops.push(Op::Mov {
+                        target: reg(target),
+                        source: CanBeConstant::Constant(C::U(*n)),
+                    }),
+                    value => ops.push(Op::LoadLiteral {
+                        target: reg(target),
+                        value: constant(value),
+                    }),
+                },
+                crate::llir::Op::UAdd { target, lhs, rhs }
+                | crate::llir::Op::USub { target, lhs, rhs } => {
+                    let rhs = match rhs {
+                        Input::Register(r) => CanBeConstant::Register(reg(r)),
+                        Input::Constant(Constant::Numeric(n)) => {
+                            arithmetic_operand(*n, SCRATCH0, &mut ops)
+                        }
+                        Input::Constant(c) => {
+                            ops.push(Op::LoadLiteral {
+                                target: SCRATCH0,
+                                value: constant(c),
+                            });
+                            CanBeConstant::Register(SCRATCH0)
+                        }
+                    };
+                    // unlike x86, arithmetic only sets the flags when asked to.
+                    let (target, lhs) = (reg(target), reg(lhs));
+                    let add = matches!(op, crate::llir::Op::UAdd { .. });
+                    ops.push(match (add, flags_read[op_index]) {
+                        (true, false) => Op::Add { target, lhs, rhs },
+                        (true, true) => Op::Adds { target, lhs, rhs },
+                        (false, false) => Op::Sub { target, lhs, rhs },
+                        (false, true) => Op::Subs { target, lhs, rhs },
+                    });
+                }
+                crate::llir::Op::CarryArithmetic {
+                    operation,
+                    target,
+                    lhs,
+                    rhs,
+                    carry_in,
+                } => {
+                    let rhs = match rhs {
+                        Input::Register(r) => reg(r),
+                        Input::Constant(c) => {
+                            ops.push(Op::LoadLiteral {
+                                target: SCRATCH0,
+                                value: constant(c),
+                            });
+                            SCRATCH0
+                        }
+                    };
+                    // the carry flag is set when there is a carry, but cleared when there is a
+                    // borrow.
+                    if let crate::llir::CarryIn::Register(carry) = carry_in {
+                        ops.push(match operation {
+                            CarryOperation::Add => Op::Cmp {
+                                register: reg(carry),
+                                data: CanBeConstant::Constant(C::U(1)),
+                            },
+                            CarryOperation::Sub => Op::Negs {
+                                target: SCRATCH1,
+                                source: reg(carry),
+                            },
+                        });
+                    }
+                    let with_carry = !matches!(carry_in, crate::llir::CarryIn::None);
+                    let (target, lhs) = (reg(target), reg(lhs));
+                    ops.push(match (operation, with_carry) {
+                        (CarryOperation::Add, false) => Op::Adds {
+                            target,
+                            lhs,
+                            rhs: CanBeConstant::Register(rhs),
+                        },
+                        (CarryOperation::Add, true) => Op::Adcs { target, lhs, rhs },
+                        (CarryOperation::Sub, false) => Op::Subs {
+                            target,
+                            lhs,
+                            rhs: CanBeConstant::Register(rhs),
+                        },
+                        (CarryOperation::Sub, true) => Op::Sbcs { target, lhs, rhs },
+                    });
+                }
+                crate::llir::Op::SetCondition { condition, target } => ops.push(Op::Cset {
+                    target: reg(target),
+                    condition: condition_code(*condition),
+                }),
+                crate::llir::Op::SetCarry { operation, target } => ops.push(Op::Cset {
+                    target: reg(target),
+                    condition: match operation {
+                        CarryOperation::Add => "cs",
+                        CarryOperation::Sub => "cc",
+                    },
+                }),
+                // the call overwrites the link register, which the block returns through.
+                crate::llir::Op::Call { label } => {
+                    ops.push(Op::SaveFrame);
+                    ops.push(Op::Bl {
+                        label: label_name(label),
+                    });
+                    ops.push(Op::RestoreFrame);
+                }
+                crate::llir::Op::Ret => ops.push(Op::Ret),
+                crate::llir::Op::CBranch { condition, target } => ops.push(Op::BCond {
+                    condition: condition_code(*condition),
+                    label: label_name(target),
+                }),
+                crate::llir::Op::Branch { target } => ops.push(Op::B {
+                    label: label_name(target),
+                }),
+                // comparing against the negation of the value sets the same flags.
+                crate::llir::Op::Compare {
+                    lhs,
+                    rhs: Input::Constant(Constant::Numeric(n)),
+                } if !fits_arithmetic_immediate(*n)
+                    && fits_arithmetic_immediate(n.wrapping_neg()) =>
+                {
+                    ops.push(Op::Cmn {
+                        register: reg(lhs),
+                        data: CanBeConstant::Constant(C::U(n.wrapping_neg())),
+                    })
+                }
+                crate::llir::Op::Compare { lhs, rhs } => {
+                    let data = match rhs {
+                        Input::Register(r) => CanBeConstant::Register(reg(r)),
+                        Input::Constant(Constant::Numeric(n)) => {
+                            arithmetic_operand(*n, SCRATCH0, &mut ops)
+                        }
+                        Input::Constant(c) => {
+                            ops.push(Op::LoadLiteral {
+                                target: SCRATCH0,
+                                value: constant(c),
+                            });
+                            CanBeConstant::Register(SCRATCH0)
+                        }
+                    };
+                    ops.push(Op::Cmp {
+                        register: reg(lhs),
+                        data,
+                    });
+                }
+                crate::llir::Op::JumpTable { index, table } => {
+                    let table_index = *table as usize;
+                    let table = &ir.jump_tables[table_index];
+                    // rebase the index into the scratch register, so that a single unsigned
+                    // comparison is enough to bound check it.
+                    let rebase = arithmetic_operand(table.base, SCRATCH1, &mut ops);
+                    ops.push(Op::Sub {
+                        target: SCRATCH0,
+                        lhs: reg(index),
+                        rhs: rebase,
+                    });
+                    let last =
+                        arithmetic_operand(table.targets.len() as u64 - 1, SCRATCH1, &mut ops);
+                    ops.push(Op::Cmp {
+                        register: SCRATCH0,
+                        data: last,
+                    });
+                    ops.push(Op::BCond {
+                        condition: "hi",
+                        label: label_name(&table.default),
+                    });
+                    // the entries are relative to the table, so that they don't need to be
+                    // relocated.
+                    ops.push(Op::Address {
+                        target: SCRATCH1,
+                        label: &jump_table_names[table_index],
+                    });
+                    ops.push(Op::LoadTableEntry {
+                        target: SCRATCH0,
+                        base: SCRATCH1,
+                        index: SCRATCH0,
+                    });
+                    ops.push(Op::Add {
+                        target: SCRATCH1,
+                        lhs: SCRATCH1,
+                        rhs: CanBeConstant::Register(SCRATCH0),
+                    });
+                    ops.push(Op::Br { register: SCRATCH1 });
+                }
+                crate::llir::Op::AtomicLoad {
+                    target,
+                    address,
+                    ordering,
+                } => ops.push(Op::Load {
+                    target: target.map(Register::from_number).unwrap_or(SCRATCH0),
+                    address: reg(address),
+                    acquire: *ordering != AtomicOrdering::Relaxed,
+                }),
+                crate::llir::Op::AtomicStore {
+                    address,
+                    value,
+                    ordering,
+                } => ops.push(Op::Store {
+                    source: reg(value),
+                    address: reg(address),
+                    release: *ordering != AtomicOrdering::Relaxed,
+                }),
+                crate::llir::Op::AtomicRmw {
+                    target,
+                    address,
+                    value,
+                    operation,
+                    ordering,
+                } => {
+                    // the previous value goes through a scratch register, so that it doesn't
+                    // matter if the target aliases with the operands.
+                    let (value, address, ordering) = (reg(value), reg(address), *ordering);
+                    ops.push(match operation {
+                        RmwOperation::Add => Op::Ldadd {
+                            value,
+                            target: SCRATCH0,
+                            address,
+                            ordering,
+                        },
+                        RmwOperation::Exchange => Op::Swp {
+                            value,
+                            target: SCRATCH0,
+                            address,
+                            ordering,
+                        },
+                    });
+                    if let Some(target) = target {
+                        ops.push(Op::Mov {
+                            target: reg(target),
+                            source: CanBeConstant::Register(SCRATCH0),
+                        });
+                    }
+                }
+                crate::llir::Op::AtomicCas {
+                    target,
+                    address,
+                    expected,
+                    new,
+                    ordering,
+                } => {
+                    ops.push(Op::Mov {
+                        target: SCRATCH0,
+                        source: CanBeConstant::Register(reg(expected)),
+                    });
+                    ops.push(Op::Cas {
+                        compare: SCRATCH0,
+                        new: reg(new),
+                        address: reg(address),
+                        ordering: *ordering,
+                    });
+                    if let Some(target) = target {
+                        ops.push(Op::Mov {
+                            target: reg(target),
+                            source: CanBeConstant::Register(SCRATCH0),
+                        });
+                    }
+                }
+                crate::llir::Op::Asm { template } => ops.push(Op::Verbatim(template)),
+                crate::llir::Op::Bit {
+                    operation,
+                    target,
+                    source,
+                } => {
+                    let (target, source) = (reg(target), reg(source));
+                    match operation {
+                        BitOperation::PopCount => ops.push(Op::PopCount { target, source }),
+                        BitOperation::LeadingZeros => ops.push(Op::Clz { target, source }),
+                        BitOperation::TrailingZeros => {
+                            ops.push(Op::Rbit { target, source });
+                            ops.push(Op::Clz {
+                                target,
+                                source: target,
+                            });
+                        }
+                        BitOperation::ByteSwap => ops.push(Op::Rev { target, source }),
+                        BitOperation::BitReverse => ops.push(Op::Rbit { target, source }),
+                    }
+                }
+                crate::llir::Op::Fence(ordering) => match ordering {
+                    AtomicOrdering::Relaxed => (),
+                    AtomicOrdering::Acquire => ops.push(Op::Dmb { loads_only: true }),
+                    _ => ops.push(Op::Dmb { loads_only: false }),
+                },
+            }
+
+            for op in ops.drain(..) {
+                writeln!(output, "\t{}", op)?;
+            }
+        }
+
+        // labels that point past the last operation
+        for (_, label) in labels {
+            writeln!(output, "{}:", label)?;
+        }
+
+        if !ir.jump_tables.is_empty() {
+            output.write_all(b".section .rodata\n.balign 4\n")?;
+            for (name, table) in jump_table_names.iter().zip(ir.jump_tables.iter()) {
+                writeln!(output, "{}:", name)?;
+                for target in table.targets.iter() {
+                    writeln!(output, "\t.word {} - {}", label_name(target), name)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
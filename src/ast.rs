@@ -1,11 +1,12 @@
 // Note: I'm building a fast parser that
 // doesn't give a fuck about spans.
 
-use crate::hlir::Condition;
+use crate::hlir::{AtomicOrdering, Condition, InlineHint};
 
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Block<'a> {
+    pub inline: Option<InlineHint>,
     pub name: LinkageLabel<'a>,
     pub spec: Option<Spec<'a>>,
     pub arguments: Option<Vec<&'a str>>, // no ignored arguments!
@@ -64,6 +65,29 @@ pub enum Expr<'a> {
         if_true: Redirection<'a>,
         if_false: Redirection<'a>,
     },
+    Switch {
+        value: &'a str,
+        cases: Vec<SwitchCase<'a>>,
+        default: Redirection<'a>,
+    },
+    Asm(Asm<'a>),
+}
+
+/// Inline assembly, with the registers it reads from, writes to and clobbers.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Asm<'a> {
+    pub template: &'a str,
+    pub inputs: Vec<(&'a str, Rvalue<'a>)>,
+    pub outputs: Vec<&'a str>,
+    pub clobbers: Vec<&'a str>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct SwitchCase<'a> {
+    pub value: u64,
+    pub redirection: Redirection<'a>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -90,6 +114,7 @@ pub enum Rvalue<'a> {
     Constant(u64),
     Binding(&'a str),
     Condition(Condition),
+    Ordering(AtomicOrdering),
 }
 
 #[derive(Debug)]
@@ -99,6 +124,7 @@ enum LRvalue<'a> {
     Binding(&'a str),
     Label(&'a str),
     Condition(Condition),
+    Ordering(AtomicOrdering),
 }
 
 impl<'a> LRvalue<'a> {
@@ -108,6 +134,7 @@ impl<'a> LRvalue<'a> {
             Self::Binding(v) => Some(Rvalue::Binding(v)),
             Self::Label(l) => Some(Rvalue::Label(l)),
             Self::Condition(f) => Some(Rvalue::Condition(f)),
+            Self::Ordering(o) => Some(Rvalue::Ordering(o)),
             Self::Ignore => None,
         }
     }
@@ -127,7 +154,7 @@ struct Parser<'a> {
 }
 
 fn is_delim(ch: char) -> bool {
-    ch.is_whitespace() || "()[]{}\";=".contains(ch)
+    ch.is_whitespace() || "()[]{}\";=,".contains(ch)
 }
 
 impl<'a> Parser<'a> {
@@ -234,6 +261,20 @@ impl<'a> Parser<'a> {
         })
     }
 
+    fn parse_ordering(&mut self) -> Option<AtomicOrdering> {
+        // relaxed | acquire | release | acqrel | seqcst
+        self.r#try(|p| {
+            Some(match p.lex_name_end() {
+                "relaxed" => AtomicOrdering::Relaxed,
+                "acquire" => AtomicOrdering::Acquire,
+                "release" => AtomicOrdering::Release,
+                "acqrel" => AtomicOrdering::AcqRel,
+                "seqcst" => AtomicOrdering::SeqCst,
+                _ => return None,
+            })
+        })
+    }
+
     fn parse_lrvalue(&mut self) -> Option<LRvalue<'a>> {
         match self.current()? {
             '_' => {
@@ -253,7 +294,10 @@ impl<'a> Parser<'a> {
                 self.lex_name_end_nonempty().map(LRvalue::Label)
             }
             ch if ch.is_ascii_digit() => self.parse_constant().map(LRvalue::Constant),
-            _ => self.parse_condition().map(LRvalue::Condition),
+            _ => self
+                .parse_ordering()
+                .map(LRvalue::Ordering)
+                .or_else(|| self.parse_condition().map(LRvalue::Condition)),
         }
     }
 
@@ -394,6 +438,9 @@ impl<'a> Parser<'a> {
 
     fn parse_expr(&mut self) -> Option<Expr<'a>> {
         let opt_insn = self.lex_insn_name();
+        if opt_insn == Some("asm") {
+            return self.parse_asm();
+        }
         self.whitespace();
         self.collect_rvalues().map(move |args| {
             if let Some(name) = opt_insn {
@@ -423,6 +470,7 @@ impl<'a> Parser<'a> {
             args.push(arg);
             self.whitespace();
             if self.current()? == ',' {
+                self.accept();
                 self.whitespace();
                 continue;
             } else {
@@ -438,10 +486,159 @@ impl<'a> Parser<'a> {
         Some(Redirection { label, args })
     }
 
+    // switch %value [<constant> <redirection>, ...] <redirection>
+    fn parse_switch(&mut self) -> Option<Expr<'a>> {
+        self.whitespace();
+        let value = self.parse_rvalue().and_then(|v| {
+            if let Rvalue::Binding(name) = v {
+                Some(name)
+            } else {
+                None
+            }
+        })?;
+        self.whitespace();
+        if self.current()? != '[' {
+            return None;
+        }
+        self.accept();
+
+        let mut cases = Vec::new();
+        loop {
+            self.whitespace();
+            let Some(Rvalue::Constant(value)) = self.parse_rvalue() else {
+                break;
+            };
+            self.whitespace();
+            let redirection = self.parse_redirection()?;
+            cases.push(SwitchCase { value, redirection });
+            self.whitespace();
+            if self.current()? == ',' {
+                self.accept();
+                continue;
+            }
+            break;
+        }
+
+        if self.current()? != ']' {
+            return None;
+        }
+        self.accept();
+        self.whitespace();
+        let default = self.parse_redirection()?;
+
+        Some(Expr::Switch {
+            value,
+            cases,
+            default,
+        })
+    }
+
+    /// Parses a list of registers like `[rax, rdx]`, where each register can be given a value
+    /// if `with_values` is set, like `[rdi=%x]`.
+    fn parse_register_list(
+        &mut self,
+        with_values: bool,
+    ) -> Option<Vec<(&'a str, Option<Rvalue<'a>>)>> {
+        if self.current()? != '[' {
+            return None;
+        }
+        self.accept();
+
+        let mut registers = Vec::new();
+        loop {
+            self.whitespace();
+            let Some(register) = self.lex_name_end_nonempty() else {
+                break;
+            };
+            let value = if with_values {
+                if self.current()? != '=' {
+                    return None;
+                }
+                self.accept();
+                Some(self.parse_rvalue()?)
+            } else {
+                None
+            };
+            registers.push((register, value));
+            self.whitespace();
+            if self.current()? == ',' {
+                self.accept();
+            }
+        }
+
+        if self.current()? != ']' {
+            return None;
+        }
+        self.accept();
+        Some(registers)
+    }
+
+    fn parse_asm(&mut self) -> Option<Expr<'a>> {
+        self.whitespace();
+        if self.current()? != '"' {
+            return None;
+        }
+        self.accept();
+        // the template is passed through verbatim, there are no escapes.
+        let start = self.offset;
+        while self.current()? != '"' {
+            self.accept();
+        }
+        let template = &self.input[start..self.offset];
+        self.accept();
+
+        let mut asm = Asm {
+            template,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            clobbers: Vec::new(),
+        };
+        loop {
+            self.whitespace();
+            let checkpoint = self.offset;
+            match self.lex_insn_name() {
+                Some("in") => {
+                    self.whitespace();
+                    asm.inputs = self
+                        .parse_register_list(true)?
+                        .into_iter()
+                        // SAFE: we asked for values
+                        .map(|(register, value)| (register, unsafe { value.unwrap_unchecked() }))
+                        .collect();
+                }
+                Some("out") => {
+                    self.whitespace();
+                    asm.outputs = self
+                        .parse_register_list(false)?
+                        .into_iter()
+                        .map(|(register, _)| register)
+                        .collect();
+                }
+                Some("clobber") => {
+                    self.whitespace();
+                    asm.clobbers = self
+                        .parse_register_list(false)?
+                        .into_iter()
+                        .map(|(register, _)| register)
+                        .collect();
+                }
+                _ => {
+                    self.offset = checkpoint;
+                    break;
+                }
+            }
+        }
+        Some(Expr::Asm(asm))
+    }
+
     fn parse_statement(&mut self) -> Option<Statement<'a>> {
         // try to recognize a return by instruction
         if let Some(name) = self.lex_insn_name() {
-            let expr = if name == "br" {
+            let expr = if name == "switch" {
+                self.parse_switch()?
+            } else if name == "asm" {
+                self.parse_asm()?
+            } else if name == "br" {
                 self.whitespace();
                 let flag = self.parse_rvalue().and_then(|f| {
                     if let Rvalue::Binding(name) = f {
@@ -524,8 +721,22 @@ impl<'a> Parser<'a> {
         had_newline
     }
 
+    // inline | noinline
+    fn parse_inline_hint(&mut self) -> Option<InlineHint> {
+        let (keyword, hint) = [
+            ("inline", InlineHint::Always),
+            ("noinline", InlineHint::Never),
+        ]
+        .into_iter()
+        .find(|(keyword, _)| self.current_input().starts_with(keyword))?;
+        self.offset += keyword.len();
+        self.whitespace();
+        Some(hint)
+    }
+
     fn parse_block(&mut self) -> Option<Block<'a>> {
         self.whitespace();
+        let inline = self.parse_inline_hint();
         if !self.current_input().starts_with("block") {
             return None;
         }
@@ -591,6 +802,7 @@ impl<'a> Parser<'a> {
         } else {
             self.accept();
             Some(Block {
+                inline,
                 name: linkage,
                 spec,
                 arguments,
@@ -24,6 +24,9 @@ pub struct Block {
     // other calculations (could be ignored)
     pub assigns: Vec<Assignment>,
     pub end: End,
+    pub inline: Option<InlineHint>,
+    /// Exported blocks can be called from outside, so their interface must stay as it is.
+    pub exported: bool,
 }
 
 pub struct Spec<Arch> {
@@ -87,6 +90,19 @@ pub enum Value {
         label: index::Label,
         params: Vec<Pure>,
     },
+    /// Adds or subtracts two values and an incoming carry (or borrow), yielding the result and
+    /// the outgoing carry. Meant to be chained for multi-precision arithmetic.
+    CarryArithmetic {
+        operation: CarryOperation,
+        lhs: Pure,
+        rhs: Pure,
+        carry_in: Option<Pure>,
+    },
+    /// Counts or moves around the bits of a value.
+    Bit {
+        operation: BitOperation,
+        value: Pure,
+    },
     /// Retrieves the CPU flags that some instruction produces.
     /// UNSTABLE: sawblade still doesn't reorder the flag queries so that our queried flags are
     /// still valid.
@@ -94,10 +110,112 @@ pub enum Value {
         instruction: index::Binding,
         condition: Condition,
     },
+    /// Atomically reads the value at an address.
+    AtomicLoad {
+        ordering: AtomicOrdering,
+        address: Pure,
+    },
+    /// Atomically writes a value to an address. Yields nothing.
+    AtomicStore {
+        ordering: AtomicOrdering,
+        address: Pure,
+        value: Pure,
+    },
+    /// Atomically modifies the value at an address, yielding the value that was there before.
+    AtomicRmw {
+        operation: RmwOperation,
+        ordering: AtomicOrdering,
+        address: Pure,
+        value: Pure,
+    },
+    /// Atomically replaces the value at an address with `new` if it's equal to `expected`.
+    /// Yields the value that was there before, so the exchange succeeded if it's equal to
+    /// `expected`.
+    AtomicCas {
+        ordering: AtomicOrdering,
+        address: Pure,
+        expected: Pure,
+        new: Pure,
+    },
+    /// Orders the memory accesses around it. Yields nothing.
+    Fence(AtomicOrdering),
+    /// Inline assembly, passed through verbatim. The inputs are placed in their registers before
+    /// it runs, and it yields the values left in the output registers.
+    Asm {
+        template: String,
+        inputs: Vec<(index::Register, Pure)>,
+        outputs: Vec<index::Register>,
+        clobbers: Vec<index::Register>,
+    },
+}
+
+/// The memory ordering constraints of an atomic operation. They have the same meaning as the
+/// C++20 memory model orderings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum AtomicOrdering {
+    Relaxed,
+    Acquire,
+    Release,
+    AcqRel,
+    SeqCst,
+}
+
+impl AtomicOrdering {
+    /// Whether a load can have this ordering. Loads can't release.
+    pub const fn is_valid_for_load(self) -> bool {
+        !matches!(self, Self::Release | Self::AcqRel)
+    }
+
+    /// Whether a store can have this ordering. Stores can't acquire.
+    pub const fn is_valid_for_store(self) -> bool {
+        !matches!(self, Self::Acquire | Self::AcqRel)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum BitOperation {
+    /// Number of bits that are set.
+    PopCount,
+    /// Number of zeros above the highest set bit, 64 for zero.
+    LeadingZeros,
+    /// Number of zeros below the lowest set bit, 64 for zero.
+    TrailingZeros,
+    /// Reverses the order of the bytes.
+    ByteSwap,
+    /// Reverses the order of the bits.
+    BitReverse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum CarryOperation {
+    /// `lhs + rhs + carry`, the carry out is set if the result wrapped around.
+    Add,
+    /// `lhs - rhs - borrow`, the borrow out is set if the result wrapped around.
+    Sub,
 }
 
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum RmwOperation {
+    /// Replaces the value.
+    Exchange,
+    /// Adds to the value.
+    Add,
+}
+
+/// Whether calls to a block should be inlined, regardless of what the optimizer thinks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum InlineHint {
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Condition {
     LessThan,
     GreaterEqual,
@@ -122,6 +240,20 @@ impl Condition {
             Condition::NotZero => Flags::ZERO,
         }
     }
+
+    /// The condition that holds whenever this one doesn't.
+    pub const fn inverted(self) -> Self {
+        match self {
+            Condition::LessThan => Condition::GreaterEqual,
+            Condition::GreaterEqual => Condition::LessThan,
+            Condition::LessEqual => Condition::GreaterThan,
+            Condition::GreaterThan => Condition::LessEqual,
+            Condition::Overflow => Condition::NotOverflow,
+            Condition::NotOverflow => Condition::Overflow,
+            Condition::Zero => Condition::NotZero,
+            Condition::NotZero => Condition::Zero,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -144,7 +276,7 @@ impl Pure {
             crate::ast::Rvalue::Binding(binding) => {
                 bindings.get_binding_index(binding).map(Self::Binding)
             }
-            Rvalue::Condition(_) => None,
+            Rvalue::Condition(_) | Rvalue::Ordering(_) => None,
         }
     }
 }
@@ -158,6 +290,20 @@ pub enum End {
         if_true: Redirection,
         if_false: Redirection,
     },
+    /// Multi-way branch on the value of a binding. Values that don't match any case go to
+    /// `default`.
+    Switch {
+        value: index::Binding,
+        cases: Vec<SwitchCase>,
+        default: Redirection,
+    },
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct SwitchCase {
+    pub value: u64,
+    pub redirection: Redirection,
 }
 
 #[derive(Debug, Clone)]
@@ -209,6 +355,59 @@ impl<Arch> Spec<Arch> {
     }
 }
 
+/// Registers in inline assembly are written by hand, so unlike the ones in specs they must exist
+/// on the target. `IR::from_ast` checks that they do before converting any block.
+fn expect_register<A: Architecture>(name: &str) -> index::Register {
+    index::Register::from::<A>(name).expect("registers in assembly are checked beforehand")
+}
+
+/// A register that inline assembly names but the target doesn't have.
+#[derive(Debug, Clone)]
+pub struct UnknownRegister<'src> {
+    /// The name of the block with the assembly.
+    pub block: &'src str,
+    pub register: &'src str,
+}
+
+impl std::fmt::Display for UnknownRegister<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "block {:?} uses register {:?} in assembly, which doesn't exist on this target",
+            self.block, self.register
+        )
+    }
+}
+
+/// The registers of the inline assembly in `block` that don't exist on the target.
+fn unknown_registers<'block, 'src, A: Architecture>(
+    block: &'block crate::ast::Block<'src>,
+) -> impl Iterator<Item = UnknownRegister<'src>> + 'block {
+    block
+        .stmts
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Statement::Assign {
+                value: Expr::Asm(asm),
+                ..
+            }
+            | Statement::Return(Expr::Asm(asm)) => Some(asm),
+            _ => None,
+        })
+        .flat_map(|asm| {
+            asm.inputs
+                .iter()
+                .map(|(register, _)| *register)
+                .chain(asm.outputs.iter().copied())
+                .chain(asm.clobbers.iter().copied())
+        })
+        .filter(|register| index::Register::from::<A>(register).is_none())
+        .map(|register| UnknownRegister {
+            block: block.name.name(),
+            register,
+        })
+}
+
 pub struct BindingMap<'a>(HashMap<&'a str, u16>);
 
 impl<'a> BindingMap<'a> {
@@ -272,29 +471,61 @@ fn expect_label_from_ast<'src>(
     }
 }
 
-fn expr_as_br_cond<'src>(
+fn expect_ordering_from_ast(rvalue: Rvalue) -> Option<AtomicOrdering> {
+    if let Rvalue::Ordering(ordering) = rvalue {
+        Some(ordering)
+    } else {
+        None
+    }
+}
+
+fn expr_as_branch<'src>(
     expr: Expr<'src>,
     binding_map: &BindingMap<'src>,
     label_map: &LabelMap<'src>,
-) -> Option<Result<(index::Binding, Redirection, Redirection), Expr<'src>>> {
-    Some(
-        if let Expr::ConditionalBranch {
+) -> Option<Result<End, Expr<'src>>> {
+    Some(match expr {
+        Expr::ConditionalBranch {
             flag,
             if_true,
             if_false,
-        } = expr
-        {
+        } => {
             let flag = binding_map.get_binding_index(flag)?;
             let if_true = Redirection::from_ast(if_true, binding_map, label_map)?;
             let if_false = Redirection::from_ast(if_false, binding_map, label_map)?;
-            Ok((flag, if_true, if_false))
-        } else {
-            Err(expr)
-        },
-    )
+            Ok(End::ConditionalBranch {
+                flag,
+                if_true,
+                if_false,
+            })
+        }
+        Expr::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            let value = binding_map.get_binding_index(value)?;
+            let cases = cases
+                .into_iter()
+                .map(|crate::ast::SwitchCase { value, redirection }| {
+                    Some(SwitchCase {
+                        value,
+                        redirection: Redirection::from_ast(redirection, binding_map, label_map)?,
+                    })
+                })
+                .try_collect()?;
+            let default = Redirection::from_ast(default, binding_map, label_map)?;
+            Ok(End::Switch {
+                value,
+                cases,
+                default,
+            })
+        }
+        other => Err(other),
+    })
 }
 
-fn expr_as_value<'src>(
+fn expr_as_value<'src, A: Architecture>(
     expr: Expr<'src>,
     binding_map: &BindingMap<'src>,
     label_map: &LabelMap<'src>,
@@ -358,6 +589,112 @@ fn expr_as_value<'src>(
                         condition,
                     })
                 }
+                // popcnt/clz/ctz/bswap/bitreverse value
+                "popcnt" | "clz" | "ctz" | "bswap" | "bitreverse" => {
+                    let operation = match name {
+                        "popcnt" => BitOperation::PopCount,
+                        "clz" => BitOperation::LeadingZeros,
+                        "ctz" => BitOperation::TrailingZeros,
+                        "bswap" => BitOperation::ByteSwap,
+                        _ => BitOperation::BitReverse,
+                    };
+                    let value = Pure::from_ast(args.into_iter().next()?, binding_map, label_map)?;
+                    Some(Value::Bit { operation, value })
+                }
+                // addc/subc lhs rhs, adde/sube lhs rhs carry
+                "addc" | "subc" | "adde" | "sube" => {
+                    let operation = if name.starts_with("add") {
+                        CarryOperation::Add
+                    } else {
+                        CarryOperation::Sub
+                    };
+                    let mut args = args
+                        .into_iter()
+                        .map(|arg| Pure::from_ast(arg, binding_map, label_map));
+                    let lhs = args.next()??;
+                    let rhs = args.next()??;
+                    let carry_in = if name.ends_with('e') {
+                        Some(args.next()??)
+                    } else {
+                        None
+                    };
+                    Some(Value::CarryArithmetic {
+                        operation,
+                        lhs,
+                        rhs,
+                        carry_in,
+                    })
+                }
+                // atomic.<op> <ordering> %address args...
+                "atomic.load" => {
+                    let mut args = args.into_iter();
+                    let ordering = expect_ordering_from_ast(args.next()?)
+                        .filter(|ordering| ordering.is_valid_for_load())?;
+                    let address = Pure::from_ast(args.next()?, binding_map, label_map)?;
+                    Some(Value::AtomicLoad { ordering, address })
+                }
+                "atomic.store" => {
+                    let mut args = args.into_iter();
+                    let ordering = expect_ordering_from_ast(args.next()?)
+                        .filter(|ordering| ordering.is_valid_for_store())?;
+                    let address = Pure::from_ast(args.next()?, binding_map, label_map)?;
+                    let value = Pure::from_ast(args.next()?, binding_map, label_map)?;
+                    Some(Value::AtomicStore {
+                        ordering,
+                        address,
+                        value,
+                    })
+                }
+                "atomic.xchg" | "atomic.add" => {
+                    let operation = if name == "atomic.add" {
+                        RmwOperation::Add
+                    } else {
+                        RmwOperation::Exchange
+                    };
+                    let mut args = args.into_iter();
+                    let ordering = expect_ordering_from_ast(args.next()?)?;
+                    let address = Pure::from_ast(args.next()?, binding_map, label_map)?;
+                    let value = Pure::from_ast(args.next()?, binding_map, label_map)?;
+                    Some(Value::AtomicRmw {
+                        operation,
+                        ordering,
+                        address,
+                        value,
+                    })
+                }
+                "atomic.cas" => {
+                    let mut args = args.into_iter();
+                    let ordering = expect_ordering_from_ast(args.next()?)?;
+                    let address = Pure::from_ast(args.next()?, binding_map, label_map)?;
+                    let expected = Pure::from_ast(args.next()?, binding_map, label_map)?;
+                    let new = Pure::from_ast(args.next()?, binding_map, label_map)?;
+                    Some(Value::AtomicCas {
+                        ordering,
+                        address,
+                        expected,
+                        new,
+                    })
+                }
+                "fence" => expect_ordering_from_ast(args.into_iter().next()?).map(Value::Fence),
+                // syscall number args...
+                // System calls are just inline assembly that follows the kernel's convention.
+                "syscall" => {
+                    let convention = A::syscall_convention();
+                    let mut args = args
+                        .into_iter()
+                        .map(|arg| Pure::from_ast(arg, binding_map, label_map));
+                    let number = args.next()??;
+                    let mut inputs = vec![(convention.number, number)];
+                    for (index, arg) in args.enumerate() {
+                        inputs.push((*convention.arguments.get(index)?, arg?));
+                    }
+                    Some(Value::Asm {
+                        template: convention.instruction.to_owned(),
+                        inputs,
+                        outputs: vec![convention.result],
+                        clobbers: convention.clobbers,
+                    })
+                }
                 _ => None,
             }
         }
@@ -366,14 +703,35 @@ fn expr_as_value<'src>(
             .map(|arg| Pure::from_ast(arg, binding_map, label_map))
             .try_collect()
             .map(Value::Copied),
-        Expr::ConditionalBranch { .. } => None,
+        Expr::Asm(crate::ast::Asm {
+            template,
+            inputs,
+            outputs,
+            clobbers,
+        }) => Some(Value::Asm {
+            template: template.to_owned(),
+            inputs: inputs
+                .into_iter()
+                .map(|(register, value)| {
+                    Some((
+                        expect_register::<A>(register),
+                        Pure::from_ast(value, binding_map, label_map)?,
+                    ))
+                })
+                .try_collect()?,
+            outputs: outputs.into_iter().map(expect_register::<A>).collect(),
+            clobbers: clobbers.into_iter().map(expect_register::<A>).collect(),
+        }),
+        Expr::ConditionalBranch { .. } | Expr::Switch { .. } => None,
     }
 }
 
 impl Block {
-    fn from_ast<'src>(
+    fn from_ast<'src, A: Architecture>(
         mut stmts: Vec<Statement<'src>>,
         arguments: Option<Vec<&'src str>>,
+        inline: Option<InlineHint>,
+        exported: bool,
         label_map: &LabelMap<'src>,
     ) -> Option<Self> {
         // create a binding with:
@@ -433,14 +791,10 @@ impl Block {
             }
             // TODO: check if the return value is a `br` insn
             crate::ast::Statement::Return(expr) => {
-                match expr_as_br_cond(expr, &binding_map, label_map).unwrap() {
-                    Ok((flag, if_true, if_false)) => End::ConditionalBranch {
-                        flag,
-                        if_true,
-                        if_false,
-                    },
+                match expr_as_branch(expr, &binding_map, label_map).unwrap() {
+                    Ok(branch) => branch,
                     Err(other) => {
-                        End::TailValue(expr_as_value(other, &binding_map, label_map).unwrap())
+                        End::TailValue(expr_as_value::<A>(other, &binding_map, label_map).unwrap())
                     }
                 }
             }
@@ -450,7 +804,7 @@ impl Block {
             .into_iter()
             .filter_map(|stmt| match stmt {
                 crate::ast::Statement::Assign { bindings, value } => {
-                    let value = expr_as_value(value, &binding_map, label_map).unwrap();
+                    let value = expr_as_value::<A>(value, &binding_map, label_map).unwrap();
                     let (used_bindings, value) = if let Value::Copied(copied) = value {
                         // Ignore `Pure` values that were ignored
                         let (used_bindings, values): (Vec<_>, Vec<_>) = bindings
@@ -501,12 +855,18 @@ impl Block {
                 // We'll have an ignored assignment
                 crate::ast::Statement::Return(value) => Some(Assignment {
                     used_bindings: Vec::new(),
-                    value: expr_as_value(value, &binding_map, label_map)?,
+                    value: expr_as_value::<A>(value, &binding_map, label_map)?,
                 }),
             })
             .collect();
 
-        Some(Block { gets, assigns, end })
+        Some(Block {
+            gets,
+            assigns,
+            end,
+            inline,
+            exported,
+        })
     }
 }
 
@@ -529,10 +889,22 @@ impl<Arch> std::fmt::Debug for IR<'_, Arch> {
 }
 
 impl<'src, Arch> IR<'src, Arch> {
-    pub fn from_ast(mut ast: Vec<crate::ast::Block<'src>>) -> Self
+    /// Makes the HLIR of the parsed blocks. Fails with the registers of the inline assembly that
+    /// don't exist on the target, if there's any.
+    pub fn from_ast(
+        mut ast: Vec<crate::ast::Block<'src>>,
+    ) -> Result<Self, Vec<UnknownRegister<'src>>>
     where
         Arch: Architecture,
     {
+        let unknown = ast
+            .iter()
+            .flat_map(unknown_registers::<Arch>)
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Err(unknown);
+        }
+
         // 1. Collect the number of exports
         let export_count = ast
             .iter()
@@ -574,16 +946,23 @@ impl<'src, Arch> IR<'src, Arch> {
             .into_iter()
             .filter_map(|block| {
                 let spec = block.spec.map(Spec::<Arch>::from_ast).unwrap_or_default();
-                let block = Block::from_ast(block.stmts, block.arguments, &label_map)?;
+                let exported = matches!(block.name, LinkageLabel::Export(_));
+                let block = Block::from_ast::<Arch>(
+                    block.stmts,
+                    block.arguments,
+                    block.inline,
+                    exported,
+                    &label_map,
+                )?;
                 Some((block, spec))
             })
             .unzip();
 
-        Self {
+        Ok(Self {
             label_map,
             blocks,
             specs,
-        }
+        })
     }
 }
 
@@ -619,6 +998,17 @@ fn check_block_labels(block: &Block, block_len: u16) -> bool {
                 && check_arbitrary_label(*label_if_true, block_len)
                 && check_arbitrary_label(*label_if_false, block_len)
         }
+        End::Switch { cases, default, .. } => cases
+            .iter()
+            .map(|case| &case.redirection)
+            .chain(Some(default))
+            .all(|redirection| {
+                check_arbitrary_label(redirection.label, block_len)
+                    && redirection
+                        .args
+                        .iter()
+                        .all(|pure| check_pure_label(pure, block_len))
+            }),
     }) && block
         .assigns
         .iter()
@@ -639,6 +1029,29 @@ fn check_value(value: &Value, block_len: u16) -> bool {
             check_arbitrary_label(*label, block_len)
                 && params.iter().all(|pure| check_pure_label(pure, block_len))
         }
+        Value::CarryArithmetic {
+            lhs, rhs, carry_in, ..
+        } => [lhs, rhs]
+            .into_iter()
+            .chain(carry_in)
+            .all(|pure| check_pure_label(pure, block_len)),
+        Value::AtomicLoad { address, .. } => check_pure_label(address, block_len),
+        Value::AtomicStore { address, value, .. } | Value::AtomicRmw { address, value, .. } => {
+            check_pure_label(address, block_len) && check_pure_label(value, block_len)
+        }
+        Value::AtomicCas {
+            address,
+            expected,
+            new,
+            ..
+        } => [address, expected, new]
+            .into_iter()
+            .all(|pure| check_pure_label(pure, block_len)),
+        Value::Fence(_) => true,
+        Value::Bit { value, .. } => check_pure_label(value, block_len),
+        Value::Asm { inputs, .. } => inputs
+            .iter()
+            .all(|(_, pure)| check_pure_label(pure, block_len)),
     }
 }
 
@@ -5,10 +5,9 @@
 #![feature(new_uninit)]
 #![feature(allocator_api)]
 #![feature(is_some_and)]
-#![feature(binary_heap_into_iter_sorted)]
 #![feature(unchecked_math)]
 #![feature(slice_as_chunks)]
-#![feature(maybe_uninit_uninit_array)]
+// SHIM #![feature(maybe_uninit_uninit_array)]
 #![feature(maybe_uninit_array_assume_init)]
 #![feature(ptr_internals)]
 #![feature(iterator_try_collect)]
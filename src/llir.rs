@@ -2,10 +2,17 @@
 //! so it can be lowered with ease to the particular architecture. Note that there are no constant
 //! besidse
 
-use crate::hlir::Condition;
-use crate::index::{Label, Register};
-use crate::optir::{Block, CFTransfer, Constant};
+use std::collections::HashSet;
+
+use crate::hlir::{AtomicOrdering, BitOperation, CarryOperation, Condition, RmwOperation};
+use crate::index::{Binding, Label, Register};
+use crate::optir::{bucket, Block, CFTransfer, Constant, SwitchCase};
 use crate::PackedSlice;
+
+mod peephole;
+
+pub use peephole::{flags_read_after, peephole};
+
 #[derive(Debug)]
 pub enum Op {
     /// set register a to b
@@ -13,6 +20,11 @@ pub enum Op {
         target: u8,
         source: u8,
     },
+    /// Exchange the values of two registers.
+    SwapRegisters {
+        lhs: u8,
+        rhs: u8,
+    },
     /// Set a constant to a register.
     SetValue {
         target: u8,
@@ -30,6 +42,32 @@ pub enum Op {
         rhs: Input,
     },
 
+    /// Add or subtract with a carry (or borrow), setting the carry flag.
+    CarryArithmetic {
+        operation: CarryOperation,
+        target: u8,
+        lhs: u8,
+        rhs: Input,
+        carry_in: CarryIn,
+    },
+    /// Set the carry (or borrow) left in the flags by the previous operation as a 0 or 1.
+    SetCarry {
+        operation: CarryOperation,
+        target: u8,
+    },
+    /// Set whether `condition` holds for the flags as a 0 or 1.
+    SetCondition {
+        condition: Condition,
+        target: u8,
+    },
+
+    /// Count or move around the bits of a register.
+    Bit {
+        operation: BitOperation,
+        target: u8,
+        source: u8,
+    },
+
     /// Call label
     Call {
         label: crate::index::Label,
@@ -48,22 +86,94 @@ pub enum Op {
     Branch {
         target: Label,
     },
+
+    /// Compare a register against an input, setting the flags.
+    Compare {
+        lhs: u8,
+        rhs: Input,
+    },
+
+    /// Branch through a jump table, indexed by the value of a register.
+    JumpTable {
+        index: u8,
+        table: u16,
+    },
+
+    /// Atomically load the value at the address held by `address`.
+    AtomicLoad {
+        target: Option<u8>,
+        address: u8,
+        ordering: AtomicOrdering,
+    },
+    /// Atomically store a value at the address held by `address`.
+    AtomicStore {
+        address: u8,
+        value: u8,
+        ordering: AtomicOrdering,
+    },
+    /// Atomically modify the value at the address held by `address`, loading the previous
+    /// value into `target`.
+    AtomicRmw {
+        target: Option<u8>,
+        address: u8,
+        value: u8,
+        operation: RmwOperation,
+        ordering: AtomicOrdering,
+    },
+    /// Atomically compare and exchange the value at the address held by `address`, loading
+    /// the previous value into `target`.
+    AtomicCas {
+        target: Option<u8>,
+        address: u8,
+        expected: u8,
+        new: u8,
+        ordering: AtomicOrdering,
+    },
+    Fence(AtomicOrdering),
+    /// Inline assembly, emitted verbatim.
+    Asm {
+        template: Box<str>,
+    },
+}
+
+/// A set of labels selected by a value. The value `base` selects `targets[0]`,
+/// and any value outside of the table goes to `default`.
+#[derive(Debug)]
+pub struct JumpTable {
+    pub base: u64,
+    pub targets: Box<[Label]>,
+    pub default: Label,
+    /// The registers that are read after jumping, which can't be used to compute the jump.
+    pub live: Box<[u8]>,
 }
 
 pub struct IR {
     pub ops: Box<[Op]>,
     pub label_offsets: Box<[u16]>,
     pub label_names: Box<[String]>,
+    pub jump_tables: Box<[JumpTable]>,
 }
 
 impl IR {
+    /// The labels in the order they're placed in, which isn't label order once the blocks are
+    /// laid out (see `optir::IR::layout`). Labels placed at the same offset stay in label order.
+    pub fn labels_in_placement_order(&self) -> Vec<Label> {
+        let mut labels = (0..self.label_offsets.len() as u16)
+            .map(|index| unsafe { Label::from_index(index) })
+            .collect::<Vec<_>>();
+        labels.sort_by_key(|label| self.label_offsets[unsafe { label.to_index() } as usize]);
+        labels
+    }
+
     #[inline(always)]
     pub fn from_optir(
         ir: crate::optir::IR,
+        analyses: &mut crate::optir::Analyses,
         exported_labels: &[&str],
         registers: PackedSlice<Register>,
+        returns: &[&[Register]],
     ) -> IR {
-        optir_to_llir(ir, exported_labels, registers)
+        optir_to_llir(ir, analyses, exported_labels, registers, returns)
     }
 }
 
@@ -73,6 +183,17 @@ pub enum Input {
     Register(u8),
 }
 
+/// Where the incoming carry of an arithmetic operation is.
+#[derive(Debug, Clone, Copy)]
+pub enum CarryIn {
+    /// There is no incoming carry.
+    None,
+    /// The previous operation left it in the status flags.
+    Flags,
+    /// It's a 0 or 1 in a register.
+    Register(u8),
+}
+
 #[repr(u8)]
 pub enum ByteSize {
     U8,
@@ -81,26 +202,183 @@ pub enum ByteSize {
     U64,
 }
 
-fn optir_to_llir(ir: crate::optir::IR, label_map: &[&str], registers: PackedSlice<Register>) -> IR {
+/// Switches with less cases than this are always lowered to a compare chain.
+const JUMP_TABLE_MIN_CASES: usize = 4;
+/// Minimum percentage of the entries in a jump table that must be actual cases.
+const JUMP_TABLE_MIN_DENSITY: u64 = 40;
+
+fn prefers_jump_table(cases: &[SwitchCase]) -> bool {
+    if cases.len() < JUMP_TABLE_MIN_CASES {
+        return false;
+    }
+    let min = cases.iter().map(|case| case.value).min().unwrap_or(0);
+    let max = cases.iter().map(|case| case.value).max().unwrap_or(0);
+    // the table has `max - min + 1` entries, but that might overflow.
+    max - min < cases.len() as u64 * 100 / JUMP_TABLE_MIN_DENSITY
+}
+
+/// Register moves that have to happen before jumping into a label, which can't be done in the
+/// block itself since other branches need the registers as they are.
+struct Trampoline {
+    ops: Vec<Op>,
+    target: Label,
+}
+
+/// Returns the label that has to be jumped into in order to reach `target` after running
+/// `adjust_ops`. If there's something to adjust, a trampoline is deferred to the end of the
+/// code.
+fn redirect_through(
+    target: Label,
+    adjust_ops: Vec<Op>,
+    block_count: usize,
+    trampolines: &mut Vec<Trampoline>,
+) -> Label {
+    if adjust_ops.is_empty() {
+        target
+    } else {
+        let label = unsafe { Label::from_index((block_count + trampolines.len()) as u16) };
+        trampolines.push(Trampoline {
+            ops: adjust_ops,
+            target,
+        });
+        label
+    }
+}
+
+/// Finds the carries that can stay in the status flags instead of being set to a register. That
+/// is when the only usage of a carry is the next operation that touches the flags, and it is the
+/// same kind of arithmetic (borrows and carries don't share flags in every architecture).
+fn carries_in_flags(block: &Block) -> HashSet<Binding> {
+    let definition_of =
+        |binding: Binding| match block.binding_defs[unsafe { binding.to_index() } as usize] {
+            bucket::Definition::Op(op_index) => Some(op_index as usize),
+            bucket::Definition::Argument(_) => None,
+        };
+
+    let mut in_flags = HashSet::new();
+    for (op_index, op) in block.operations.iter().enumerate() {
+        let crate::optir::Op::CarryArithmetic {
+            operation,
+            carry_in: Some(carry),
+            ..
+        } = op
+        else {
+            continue;
+        };
+        let Some(carry_index) = definition_of(*carry) else {
+            continue;
+        };
+        let crate::optir::Op::CarryOut { instruction } = &block.operations[carry_index] else {
+            continue;
+        };
+        let same_operation = definition_of(*instruction).is_some_and(|instruction_index| {
+            instruction_index + 1 == carry_index
+                && matches!(
+                    &block.operations[instruction_index],
+                    crate::optir::Op::CarryArithmetic { operation: produced, .. }
+                        if produced == operation
+                )
+        });
+        // constants are set with moves, which leave the flags alone.
+        let untouched_flags = block.operations[carry_index + 1..op_index]
+            .iter()
+            .all(|op| matches!(op, crate::optir::Op::Constant(_)));
+        let only_usage = block.binding_usages[unsafe { carry.to_index() } as usize].len() == 1;
+
+        if same_operation && untouched_flags && only_usage {
+            in_flags.insert(*carry);
+        }
+    }
+    in_flags
+}
+
+/// Finds whether the flags that the conditional branch of a block reads are overwritten before
+/// it. If so, they're set to the register of the binding that fetches them instead, while they
+/// still hold, and that register is compared right before branching.
+fn flags_in_register(block: &Block) -> Option<Binding> {
+    let CFTransfer::ConditionalBranch {
+        flag_definition, ..
+    } = &block.end
+    else {
+        return None;
+    };
+    let bucket::Definition::Op(op_index) =
+        block.binding_defs[unsafe { flag_definition.to_index() } as usize]
+    else {
+        return None;
+    };
+    block.operations[op_index as usize + 1..]
+        .iter()
+        .any(crate::optir::Op::overwrites_flags)
+        .then_some(*flag_definition)
+}
+
+fn optir_to_llir(
+    ir: crate::optir::IR,
+    analyses: &mut crate::optir::Analyses,
+    label_map: &[&str],
+    registers: PackedSlice<Register>,
+    returns: &[&[Register]],
+) -> IR {
     let label_count = ir.blocks.len();
-    let mut label_offsets = Vec::with_capacity(label_count);
+    let return_blocks = analyses.return_blocks(&ir);
+    // blocks with a spec return in its registers, the rest return wherever their bindings are.
+    let return_registers = ir.return_registers(return_blocks, returns);
+    let returned_in = |block_index: usize| {
+        ir.blocks[block_index]
+            .exported_bindings
+            .iter()
+            .enumerate()
+            .map(|(index, binding)| {
+                return_registers[block_index]
+                    .get(index)
+                    .copied()
+                    .unwrap_or_else(|| {
+                        registers.elements[unsafe { binding.to_index() } as usize
+                            + registers.ranges[block_index].start]
+                    })
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut label_offsets = vec![0; label_count];
     let op_count = ir.blocks.iter().map(|block| block.operations.len()).sum();
     // we might need more space for return adjustments.
     let mut ops = Vec::with_capacity(op_count);
-
-    for (block_index, block) in ir.blocks.iter().enumerate() {
-        label_offsets.push(ops.len() as u16);
-        // NOTE: currently using op index to get the associated binding . But when stores
-        // come then not all operations will be associated to bindings... Since stores don't have a
-        // local result.
+    let mut trampolines = Vec::new();
+    let mut jump_tables = Vec::new();
+
+    let layout = if ir.layout.is_empty() {
+        (0..label_count as u16)
+            .map(|index| unsafe { Label::from_index(index) })
+            .collect()
+    } else {
+        ir.layout.clone()
+    };
+    for (position, label) in layout.iter().enumerate() {
+        let block_index = unsafe { label.to_index() } as usize;
+        let block = &ir.blocks[block_index];
+        // branching into the block that comes next doesn't need a jump.
+        let next = layout.get(position + 1).copied();
+        label_offsets[block_index] = ops.len() as u16;
+        let register_of = |binding: crate::index::Binding| unsafe {
+            registers.elements[binding.to_index() as usize + registers.ranges[block_index].start]
+                .as_index()
+        };
+        // not all operations define bindings (e.g stores), so the binding an operation defines
+        // can't be deduced from its index.
+        let results = block.first_results();
+        let carries_in_flags = carries_in_flags(block);
+        let flags_in_register = flags_in_register(block);
         for (op_index, op) in block.operations.iter().enumerate() {
-            let binding_index = op_index + block.arg_count;
+            let target = results[op_index].map(register_of);
             match op {
+                // constants that are only used as immediates are encoded where they're used.
+                crate::optir::Op::Constant(_)
+                    if block.is_only_immediate(
+                        results[op_index].expect("constants always define a binding"),
+                    ) => {}
                 crate::optir::Op::Constant(c) => ops.push(Op::SetValue {
-                    target: unsafe {
-                        registers.elements[binding_index + registers.ranges[block_index].start]
-                            .as_index()
-                    },
+                    target: target.expect("constants always define a binding"),
                     value: *c,
                 }),
                 crate::optir::Op::Call {
@@ -147,20 +425,23 @@ fn optir_to_llir(ir: crate::optir::IR, label_map: &[&str], registers: PackedSlic
 
                         // the selected return block must have at LEAST max_ret_index values to
                         // return.
-                        let selected_return_block_index = ir.return_blocks[target_block_index]
+                        let selected_return_block_index = return_blocks[target_block_index]
                             .iter()
                             .copied()
                             .map(|index| unsafe { index.to_index() } as usize)
-                            .find(|index| ir.return_counts[*index] > max_ret_index)
+                            .find(|index| {
+                                ir.blocks[*index].exported_bindings.len() > max_ret_index as usize
+                            })
                             .expect("this call should return the needed values");
 
                         let targets = if let CFTransfer::Return =
                             &ir.blocks[selected_return_block_index].end
                         {
-                            usage_info.result_usage.iter()
-                                .copied()
-                                .map(|ret_index| ir.blocks[selected_return_block_index].exported_bindings[ret_index as usize])
-                                .map(|binding| unsafe { binding.to_index() } as usize + registers.ranges[selected_return_block_index].start).map(|global_index| registers.elements[global_index])
+                            let returned = returned_in(selected_return_block_index);
+                            usage_info
+                                .result_usage
+                                .iter()
+                                .map(move |ret_index| returned[*ret_index as usize])
                         } else {
                             unreachable!("blocks marked as return blocks HAVE to return")
                         };
@@ -169,84 +450,234 @@ fn optir_to_llir(ir: crate::optir::IR, label_map: &[&str], registers: PackedSlic
                     }
                 }
                 crate::optir::Op::Add { lhs, rhs } => {
-                    let lhs = unsafe {
-                        registers.elements
-                            [lhs.to_index() as usize + registers.ranges[block_index].start]
-                            .as_index()
+                    // additions are commutative, so the immediate can be on either side.
+                    let (lhs, rhs) = match (
+                        block.immediate_operand(*lhs, op_index as u16),
+                        block.immediate_operand(*rhs, op_index as u16),
+                    ) {
+                        (_, Some(constant)) => (*lhs, Input::Constant(constant)),
+                        (Some(constant), None) => (*rhs, Input::Constant(constant)),
+                        (None, None) => (*lhs, Input::Register(register_of(*rhs))),
                     };
 
-                    let rhs = unsafe {
-                        registers.elements
-                            [rhs.to_index() as usize + registers.ranges[block_index].start]
-                            .as_index()
-                    };
-
-                    let target = unsafe {
-                        registers.elements[binding_index + registers.ranges[block_index].start]
-                            .as_index()
-                    };
+                    let target = target.expect("arithmetic always defines a binding");
 
                     ops.push(Op::UAdd {
                         target,
-                        lhs,
-                        rhs: Input::Register(rhs),
+                        lhs: register_of(lhs),
+                        rhs,
                     });
                 }
                 crate::optir::Op::Sub { lhs, rhs } => {
-                    let lhs = unsafe {
-                        registers.elements
-                            [lhs.to_index() as usize + registers.ranges[block_index].start]
-                            .as_index()
-                    };
-
-                    let rhs = unsafe {
-                        registers.elements
-                            [rhs.to_index() as usize + registers.ranges[block_index].start]
-                            .as_index()
-                    };
-
-                    let target = unsafe {
-                        registers.elements[binding_index + registers.ranges[block_index].start]
-                            .as_index()
-                    };
+                    let target = target.expect("arithmetic always defines a binding");
 
                     ops.push(Op::USub {
                         target,
-                        lhs,
-                        rhs: Input::Register(rhs),
+                        lhs: register_of(*lhs),
+                        rhs: match block.immediate_operand(*rhs, op_index as u16) {
+                            Some(constant) => Input::Constant(constant),
+                            None => Input::Register(register_of(*rhs)),
+                        },
                     });
                 }
-                crate::optir::Op::FetchFlags(_) => (),
+                crate::optir::Op::FetchFlags { condition, .. } => {
+                    if results[op_index] == flags_in_register {
+                        ops.push(Op::SetCondition {
+                            condition: *condition,
+                            target: target.expect("flags always define a binding"),
+                        });
+                    }
+                }
+                crate::optir::Op::Bit { operation, value } => ops.push(Op::Bit {
+                    operation: *operation,
+                    target: target.expect("bit operations always define a binding"),
+                    source: register_of(*value),
+                }),
+                crate::optir::Op::CarryArithmetic {
+                    operation,
+                    lhs,
+                    rhs,
+                    carry_in,
+                } => ops.push(Op::CarryArithmetic {
+                    operation: *operation,
+                    target: target.expect("arithmetic always defines a binding"),
+                    lhs: register_of(*lhs),
+                    rhs: Input::Register(register_of(*rhs)),
+                    carry_in: match carry_in {
+                        None => CarryIn::None,
+                        Some(carry) if carries_in_flags.contains(carry) => CarryIn::Flags,
+                        Some(carry) => CarryIn::Register(register_of(*carry)),
+                    },
+                }),
+                crate::optir::Op::CarryOut { instruction } => {
+                    let result = results[op_index].expect("carries always define a binding");
+                    if !carries_in_flags.contains(&result) {
+                        let operation = match block.binding_defs
+                            [unsafe { instruction.to_index() } as usize]
+                        {
+                            bucket::Definition::Op(instruction_index) => match &block.operations
+                                [instruction_index as usize]
+                            {
+                                crate::optir::Op::CarryArithmetic { operation, .. } => *operation,
+                                _ => unreachable!("carries are only read from carry arithmetic"),
+                            },
+                            bucket::Definition::Argument(_) => {
+                                unreachable!("carries are only read from carry arithmetic")
+                            }
+                        };
+                        ops.push(Op::SetCarry {
+                            operation,
+                            target: register_of(result),
+                        });
+                    }
+                }
+                crate::optir::Op::AtomicLoad { ordering, address } => ops.push(Op::AtomicLoad {
+                    target,
+                    address: register_of(*address),
+                    ordering: *ordering,
+                }),
+                crate::optir::Op::AtomicStore {
+                    ordering,
+                    address,
+                    value,
+                } => ops.push(Op::AtomicStore {
+                    address: register_of(*address),
+                    value: register_of(*value),
+                    ordering: *ordering,
+                }),
+                crate::optir::Op::AtomicRmw {
+                    operation,
+                    ordering,
+                    address,
+                    value,
+                } => ops.push(Op::AtomicRmw {
+                    target,
+                    address: register_of(*address),
+                    value: register_of(*value),
+                    operation: *operation,
+                    ordering: *ordering,
+                }),
+                crate::optir::Op::AtomicCas {
+                    ordering,
+                    address,
+                    expected,
+                    new,
+                } => ops.push(Op::AtomicCas {
+                    target,
+                    address: register_of(*address),
+                    expected: register_of(*expected),
+                    new: register_of(*new),
+                    ordering: *ordering,
+                }),
+                crate::optir::Op::Fence(ordering) => ops.push(Op::Fence(*ordering)),
+                crate::optir::Op::Asm {
+                    template,
+                    inputs,
+                    outputs,
+                    clobbers: _,
+                } => {
+                    let register_at = |binding: Binding| {
+                        registers.elements[unsafe { binding.to_index() } as usize
+                            + registers.ranges[block_index].start]
+                    };
+                    // the allocator tries to place the bindings in their registers, but inputs
+                    // that are used after the assembly might be somewhere else.
+                    line_up_registers(
+                        inputs.iter().map(|(_, input)| register_at(*input)),
+                        inputs.iter().map(|(register, _)| *register),
+                        &mut ops,
+                    );
+                    ops.push(Op::Asm {
+                        template: template.clone(),
+                    });
+                    line_up_registers(
+                        outputs.iter().copied(),
+                        block.results_of(op_index).map(register_at),
+                        &mut ops,
+                    );
+                }
             }
         }
 
         match &block.end {
-            CFTransfer::Return => ops.push(Op::Ret),
-            CFTransfer::DirectBranch { .. } => todo!(),
+            CFTransfer::Return => {
+                line_up_registers(
+                    block.exported_bindings.iter().map(|binding| {
+                        registers.elements[unsafe { binding.to_index() } as usize
+                            + registers.ranges[block_index].start]
+                    }),
+                    returned_in(block_index),
+                    &mut ops,
+                );
+                ops.push(Op::Ret);
+            }
+            CFTransfer::DirectBranch { target } => {
+                align_outgoing_registers(
+                    *target,
+                    &ir.blocks,
+                    block_index,
+                    block
+                        .exported_bindings
+                        .iter()
+                        .copied()
+                        .map(|b| unsafe { b.to_index() }),
+                    registers,
+                    &mut ops,
+                );
+                if next != Some(*target) {
+                    ops.push(Op::Branch { target: *target });
+                }
+            }
             CFTransfer::ConditionalBranch {
                 stored_condition,
-                flag_definition: _, // XXX: I have no idea what to use this for here. Maybe
-                // reordering?
+                flag_definition,
                 target_if_true,
                 target_if_false,
                 true_branch_binding_count,
             } => {
+                let stored_condition = if flags_in_register.is_some() {
+                    ops.push(Op::Compare {
+                        lhs: register_of(*flag_definition),
+                        rhs: Input::Constant(Constant::Numeric(0)),
+                    });
+                    &Condition::NotZero
+                } else {
+                    stored_condition
+                };
                 let true_branch_bindings =
                     &block.exported_bindings[..*true_branch_binding_count as usize];
                 let false_branch_bindings =
                     &block.exported_bindings[*true_branch_binding_count as usize..];
 
+                // the branch jumps into one of the targets and goes on into the other one. That's
+                // the false one, unless the true one comes next and can fall through, in which
+                // case the condition is inverted.
+                let (condition, (jumped, jumped_bindings), (kept, kept_bindings)) =
+                    if next == Some(*target_if_true) && next != Some(*target_if_false) {
+                        (
+                            stored_condition.inverted(),
+                            (*target_if_false, false_branch_bindings),
+                            (*target_if_true, true_branch_bindings),
+                        )
+                    } else {
+                        (
+                            *stored_condition,
+                            (*target_if_true, true_branch_bindings),
+                            (*target_if_false, false_branch_bindings),
+                        )
+                    };
+
                 // first, know the instructions that are needed to line up each of the bindings
                 // with the required registers.
 
-                let true_branch_adjust_block = {
+                let jumped_branch_adjust_block = {
                     let mut ops = Vec::new();
 
                     align_outgoing_registers(
-                        *target_if_true,
+                        jumped,
                         &ir.blocks,
                         block_index,
-                        true_branch_bindings
+                        jumped_bindings
                             .iter()
                             .copied()
                             .map(|b| unsafe { b.to_index() }),
@@ -256,21 +687,27 @@ fn optir_to_llir(ir: crate::optir::IR, label_map: &[&str], registers: PackedSlic
                     ops
                 };
 
-                // NOTE: we don't need a separate block for adjusting registers for the true branch
-                // since we'll make an unconditional branch anyway.
-
-                // put a dummy op here to register the index we'll push the true branch first and
-                // then adjust the other branch
-                let op_offset = ops.len();
+                // if there's some space needed for adjusting registers for the branch that jumps,
+                // we'll have to make another
+                let branch_label = redirect_through(
+                    jumped,
+                    jumped_branch_adjust_block,
+                    label_count,
+                    &mut trampolines,
+                );
 
-                ops.push(Op::Ret); // <- dummy, we'll update this when we have the correct label.
+                ops.push(Op::CBranch {
+                    condition,
+                    target: branch_label,
+                });
 
-                // align our false branch registers
+                // NOTE: we don't need a separate block for adjusting registers for the other
+                // branch, since nothing else goes through here.
                 align_outgoing_registers(
-                    *target_if_false,
+                    kept,
                     &ir.blocks,
                     block_index,
-                    false_branch_bindings
+                    kept_bindings
                         .iter()
                         .copied()
                         .map(|b| unsafe { b.to_index() }),
@@ -278,28 +715,119 @@ fn optir_to_llir(ir: crate::optir::IR, label_map: &[&str], registers: PackedSlic
                     &mut ops,
                 );
 
-                ops.push(Op::Branch {
-                    target: *target_if_false,
-                });
-
-                // if there's some space needed for adjusting registers for the 'true' branch,
-                // we'll have to make another
-                let branch_label = if true_branch_adjust_block.is_empty() {
-                    *target_if_true
-                } else {
-                    let label_index = label_offsets.len() as u16;
-                    label_offsets.push(ops.len() as u16);
-                    unsafe { Label::from_index(label_index) }
+                if next != Some(kept) {
+                    ops.push(Op::Branch { target: kept });
+                }
+            }
+            CFTransfer::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let value = unsafe {
+                    registers.elements
+                        [value.to_index() as usize + registers.ranges[block_index].start]
+                        .as_index()
                 };
 
-                ops[op_offset] = Op::CBranch {
-                    condition: *stored_condition,
-                    target: branch_label,
-                };
+                let mut bindings = &block.exported_bindings[..];
+                let case_labels = cases
+                    .iter()
+                    .map(|case| {
+                        let (case_bindings, rest) = bindings.split_at(case.binding_count as usize);
+                        bindings = rest;
+                        let mut adjust_ops = Vec::new();
+                        align_outgoing_registers(
+                            case.target,
+                            &ir.blocks,
+                            block_index,
+                            case_bindings.iter().map(|b| unsafe { b.to_index() }),
+                            registers,
+                            &mut adjust_ops,
+                        );
+                        redirect_through(case.target, adjust_ops, label_count, &mut trampolines)
+                    })
+                    .collect::<Vec<_>>();
+
+                // the default target gets the rest of the bindings.
+                let mut default_adjust_ops = Vec::new();
+                align_outgoing_registers(
+                    *default,
+                    &ir.blocks,
+                    block_index,
+                    bindings.iter().map(|b| unsafe { b.to_index() }),
+                    registers,
+                    &mut default_adjust_ops,
+                );
+
+                if prefers_jump_table(cases) {
+                    let default_label = redirect_through(
+                        *default,
+                        default_adjust_ops,
+                        label_count,
+                        &mut trampolines,
+                    );
+                    // SAFE: jump tables are only preferred when there are cases.
+                    let base =
+                        unsafe { cases.iter().map(|case| case.value).min().unwrap_unchecked() };
+                    let len = cases
+                        .iter()
+                        .map(|case| case.value - base)
+                        .max()
+                        .unwrap_or(0)
+                        + 1;
+                    let mut targets = vec![default_label; len as usize].into_boxed_slice();
+                    // going backwards so that the first case with a repeated value wins.
+                    for (case, label) in cases.iter().zip(case_labels).rev() {
+                        targets[(case.value - base) as usize] = label;
+                    }
+
+                    ops.push(Op::JumpTable {
+                        index: value,
+                        table: jump_tables.len() as u16,
+                    });
+                    jump_tables.push(JumpTable {
+                        base,
+                        targets,
+                        default: default_label,
+                        live: block
+                            .exported_bindings
+                            .iter()
+                            .copied()
+                            .map(register_of)
+                            .collect(),
+                    });
+                } else {
+                    for (case, label) in cases.iter().zip(case_labels) {
+                        ops.push(Op::Compare {
+                            lhs: value,
+                            rhs: Input::Constant(Constant::Numeric(case.value)),
+                        });
+                        ops.push(Op::CBranch {
+                            condition: Condition::Zero,
+                            target: label,
+                        });
+                    }
+                    // nothing else can be jumping here, so we can adjust the registers in place.
+                    ops.extend(default_adjust_ops);
+                    if next != Some(*default) {
+                        ops.push(Op::Branch { target: *default });
+                    }
+                }
             }
         }
     }
 
+    for Trampoline {
+        ops: trampoline_ops,
+        target,
+    } in trampolines
+    {
+        label_offsets.push(ops.len() as u16);
+        ops.extend(trampoline_ops);
+        ops.push(Op::Branch { target });
+    }
+
     let label_names = {
         let mut names = Box::new_uninit_slice(label_offsets.len());
         names
@@ -328,6 +856,7 @@ fn optir_to_llir(ir: crate::optir::IR, label_map: &[&str], registers: PackedSlic
         ops: ops.into_boxed_slice(),
         label_offsets: label_offsets.into_boxed_slice(),
         label_names,
+        jump_tables: jump_tables.into_boxed_slice(),
     }
 }
 
@@ -350,26 +879,41 @@ fn line_up_registers(
     target: impl IntoIterator<Item = Register>,
     assembly: &mut Vec<Op>,
 ) {
-    // build a map of which registers have to be moved
-    let dependency_map = current.into_iter().zip(target).collect();
-
-    let mut dep_order = crate::DependencyOrderIter::new(dependency_map);
+    // the moves happen all at once: every target gets what its source held before any of them.
+    let mut moves = current
+        .into_iter()
+        .zip(target)
+        .map(|(current, target)| unsafe { (current.as_index(), target.as_index()) })
+        .filter(|(current, target)| current != target)
+        .collect::<Vec<_>>();
+
+    while !moves.is_empty() {
+        // a register can be overwritten once no other move has to read it.
+        let free = moves
+            .iter()
+            .position(|(_, target)| moves.iter().all(|(source, _)| source != target));
+        if let Some(index) = free {
+            let (source, target) = moves.remove(index);
+            assembly.push(Op::CopyRegister { target, source });
+            continue;
+        }
 
-    for (current, target) in &mut dep_order {
-        if current != target {
-            assembly.push(Op::CopyRegister {
-                target: unsafe { target.as_index() },
-                source: unsafe { current.as_index() },
-            })
+        // every target is read by another move, so they form cycles. Swapping puts the value of
+        // the source in place, and what was in place where the source was.
+        let (source, target) = moves.swap_remove(0);
+        assembly.push(Op::SwapRegisters {
+            lhs: source,
+            rhs: target,
+        });
+        for (other_source, _) in moves.iter_mut() {
+            if *other_source == target {
+                *other_source = source;
+            } else if *other_source == source {
+                *other_source = target;
+            }
         }
+        moves.retain(|(source, target)| source != target);
     }
-
-    // for (current, target) in &mut dep_order {
-    //     // assembly.push(AssemblyOp::Mov {
-    //     //     dest: target,
-    //     //     source: DataSource::Register(current),
-    //     // })
-    // }
 }
 
 /// This one is just a way to avoid repeating code.
@@ -388,7 +932,7 @@ fn align_outgoing_registers(
         .into_iter()
         .map(|ix| registers.elements[ix as usize + current_block_register_start]);
 
-    let targets = registers.elements[current_block_register_start..]
+    let targets = registers.elements[registers.ranges[target_block_index as usize].start..]
         [..blocks[target_block_index as usize].arg_count]
         .iter()
         .copied();
@@ -0,0 +1,135 @@
+//! Peephole optimizations over the LLIR.
+//!
+//! Operations that leave their target as it was are removed, and the ones that only copy a
+//! register are turned into copies, as long as nothing reads the flags they set. Targets run their
+//! own peephole pass over their instructions, since most of the savings come from how they're
+//! encoded.
+
+use std::collections::HashSet;
+
+use super::{CarryIn, Input, Op, IR};
+use crate::optir::Constant;
+
+/// What an operation does with the status flags.
+enum FlagEffect {
+    /// It reads them, or whatever comes after it might.
+    Read,
+    /// It overwrites them without reading them.
+    Write,
+    /// It leaves them alone, or it's not clear that it overwrites them all.
+    Keep,
+}
+
+fn flag_effect(op: &Op) -> FlagEffect {
+    match op {
+        Op::CBranch { .. }
+        | Op::SetCarry { .. }
+        | Op::SetCondition { .. }
+        | Op::CarryArithmetic {
+            carry_in: CarryIn::Flags,
+            ..
+        }
+        // the blocks jumped into may read them, and so may inline assembly.
+        | Op::Branch { .. }
+        | Op::JumpTable { .. }
+        | Op::Asm { .. } => FlagEffect::Read,
+        // flags don't live across calls and returns.
+        Op::UAdd { .. }
+        | Op::USub { .. }
+        | Op::CarryArithmetic { .. }
+        | Op::Compare { .. }
+        | Op::Call { .. }
+        | Op::Ret => FlagEffect::Write,
+        _ => FlagEffect::Keep,
+    }
+}
+
+/// Whether the flags left by each operation may be read afterwards. Labels can be jumped to from
+/// anywhere, so the flags are assumed to be read at all of them.
+pub fn flags_read_after(ops: &[Op], label_offsets: &[u16]) -> Box<[bool]> {
+    // labels aren't in the order they're placed in once the blocks are laid out.
+    let label_starts = label_offsets.iter().copied().collect::<HashSet<_>>();
+    let mut read_after = vec![true; ops.len()].into_boxed_slice();
+    let mut read = true;
+    for (op_index, op) in ops.iter().enumerate().rev() {
+        read_after[op_index] = read;
+        read = match flag_effect(op) {
+            FlagEffect::Read => true,
+            FlagEffect::Write => false,
+            FlagEffect::Keep => read,
+        };
+        if label_starts.contains(&(op_index as u16)) {
+            read = true;
+        }
+    }
+    read_after
+}
+
+/// Removes the operations that do nothing and simplifies the ones that only copy a value. Returns
+/// whether anything changed.
+pub fn peephole(ir: &mut IR) -> bool {
+    let read_after = flags_read_after(&ir.ops, &ir.label_offsets);
+    let ops = std::mem::take(&mut ir.ops).into_vec();
+    // where each operation ends up, so labels can be moved along.
+    let mut new_offsets = Vec::with_capacity(ops.len() + 1);
+    let mut kept = Vec::with_capacity(ops.len());
+    let mut changed = false;
+    for (op_index, op) in ops.into_iter().enumerate() {
+        new_offsets.push(kept.len() as u16);
+        match simplify(op, read_after[op_index]) {
+            Simplified::Same(op) => kept.push(op),
+            Simplified::Into(op) => {
+                kept.push(op);
+                changed = true;
+            }
+            Simplified::Removed => changed = true,
+        }
+    }
+    new_offsets.push(kept.len() as u16);
+
+    for offset in ir.label_offsets.iter_mut() {
+        *offset = new_offsets[*offset as usize];
+    }
+    ir.ops = kept.into_boxed_slice();
+    changed
+}
+
+enum Simplified {
+    Same(Op),
+    Into(Op),
+    Removed,
+}
+
+fn simplify(op: Op, flags_read: bool) -> Simplified {
+    match op {
+        Op::CopyRegister { target, source } if target == source => Simplified::Removed,
+        Op::UAdd {
+            target,
+            lhs,
+            rhs: Input::Constant(Constant::Numeric(0)),
+        }
+        | Op::USub {
+            target,
+            lhs,
+            rhs: Input::Constant(Constant::Numeric(0)),
+        } if !flags_read => {
+            if target == lhs {
+                Simplified::Removed
+            } else {
+                Simplified::Into(Op::CopyRegister {
+                    target,
+                    source: lhs,
+                })
+            }
+        }
+        Op::USub {
+            target,
+            lhs,
+            rhs: Input::Register(rhs),
+        } if lhs == rhs && !flags_read => Simplified::Into(Op::SetValue {
+            target,
+            value: Constant::Numeric(0),
+        }),
+        op => Simplified::Same(op),
+    }
+}
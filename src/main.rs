@@ -8,38 +8,156 @@
 use sawblade::PackedSlice;
 
 use sawblade::arch::Architecture;
+use sawblade::optir::{MalformedBranch, OptLevel, PassManager};
 fn main() {
-    let source =
-        std::fs::read_to_string(std::env::args().nth(1).expect("must have <file>")).unwrap();
+    let mut file = None;
+    let mut target = String::from("x86_64");
+    let mut level = OptLevel::O2;
+    let mut lenient = false;
+    let mut dump_optir = false;
+    for arg in std::env::args().skip(1) {
+        if let Some(name) = arg.strip_prefix("--target=") {
+            target = name.to_owned();
+        } else if let Some(name) = arg.strip_prefix("-O") {
+            level = match name {
+                "0" => OptLevel::O0,
+                "1" => OptLevel::O1,
+                "2" => OptLevel::O2,
+                "s" => OptLevel::Os,
+                other => panic!(
+                    "unknown optimization level {:?}, expected 0, 1, 2 or s",
+                    other
+                ),
+            };
+        } else if arg == "--lenient" {
+            lenient = true;
+        } else if arg == "--dump-optir" {
+            dump_optir = true;
+        } else {
+            file = Some(arg);
+        }
+    }
+    let source = std::fs::read_to_string(file.expect("must have <file>")).unwrap();
 
-    let ast = sawblade::ast::parse_source(&source);
-    let hlir = sawblade::hlir::IR::<sawblade::arch::X86_64Nasm>::from_ast(ast);
-    let optir = sawblade::optir::dissect_from_hlir(hlir.blocks);
+    match target.as_str() {
+        "x86_64" => compile::<sawblade::arch::X86_64Nasm>(&source, level, lenient, dump_optir),
+        "aarch64" => compile::<sawblade::arch::Aarch64>(&source, level, lenient, dump_optir),
+        other => panic!("unknown target {:?}, expected x86_64 or aarch64", other),
+    }
+}
+
+fn compile<A: Architecture>(source: &str, level: OptLevel, lenient: bool, dump_optir: bool) {
+    let ast = sawblade::ast::parse_source(source);
+    let mut hlir = match sawblade::hlir::IR::<A>::from_ast(ast) {
+        Ok(hlir) => hlir,
+        Err(unknown_registers) => {
+            for register in unknown_registers.iter() {
+                eprintln!("error: {}", register);
+            }
+            std::process::exit(1);
+        }
+    };
+    let mut names = vec![""; hlir.label_map.labels.len()];
+    for (name, index) in hlir.label_map.labels.iter() {
+        names[*index as usize] = name;
+    }
+    let describe = |branch: &MalformedBranch| {
+        let name = |label: sawblade::index::Label| names[unsafe { label.to_index() } as usize];
+        let [(first, first_count), (second, second_count)] = branch.targets;
+        let values = |count: u8| if count == 1 { "value" } else { "values" };
+        format!(
+            "block {:?} branches to {:?}, which returns {} {}, and to {:?}, which returns {} {}",
+            name(branch.block),
+            name(first),
+            first_count,
+            values(first_count),
+            name(second),
+            second_count,
+            values(second_count)
+        )
+    };
+    let mut optir = if lenient {
+        let (optir, malformed_branches) = sawblade::optir::dissect_from_hlir_lenient(hlir.blocks);
+        for branch in malformed_branches.iter() {
+            eprintln!(
+                "warning: {}; removing it along with what depends on it",
+                describe(branch)
+            );
+        }
+        optir
+    } else {
+        match sawblade::optir::dissect_from_hlir(hlir.blocks) {
+            Ok(optir) => optir,
+            Err(malformed_branches) => {
+                for branch in malformed_branches.iter() {
+                    eprintln!("error: {}", describe(branch));
+                }
+                std::process::exit(1);
+            }
+        }
+    };
+    // exported blocks come first, so the ones that are left are still the first ones.
+    let export_count = hlir.label_map.export_count as usize
+        - optir
+            .removed_blocks
+            .iter()
+            .filter(|label| {
+                (unsafe { label.to_index() } as usize) < hlir.label_map.export_count as usize
+            })
+            .count();
+    for label in optir.removed_blocks.iter() {
+        names.remove(unsafe { label.to_index() } as usize);
+    }
+    let label_map = names[..export_count].to_vec();
+    for label in optir.undetermined_returns.iter() {
+        eprintln!(
+            "warning: block {:?} never returns, so it's assumed to return no values",
+            names[unsafe { label.to_index() } as usize]
+        );
+    }
+    let mut manager = PassManager::new(level);
+    manager.run(&mut optir);
+    if level != OptLevel::O0 {
+        // before anything else changes the IR, so that the analyses of the passes are still good.
+        sawblade::optir::lay_out_blocks(&mut optir, manager.analyses());
+        sawblade::optir::select_immediates(&mut optir, A::fits_arithmetic_immediate);
+        sawblade::optir::select_join_buckets(&mut optir, manager.analyses());
+    }
+    if dump_optir {
+        eprintln!("{:#?}", optir);
+    }
+    // blocks made by passes come after the ones from the source, so they never have specs
+    for label in optir.removed_blocks.iter() {
+        let index = unsafe { label.to_index() } as usize;
+        if index < hlir.specs.len() {
+            hlir.specs.remove(index);
+        }
+    }
+    // blocks made by inlining don't have any requirements of their own
+    hlir.specs.resize_with(optir.blocks.len(), Default::default);
     let (registers, register_ranges) =
-        sawblade::allocators::allocate_registers::<sawblade::arch::X86_64Nasm>(&optir, &hlir.specs);
+        sawblade::allocators::allocate_registers::<A>(&optir, manager.analyses(), &hlir.specs);
 
     let mut output = std::io::stdout();
 
-    let label_map = {
-        let mut map = Box::new_uninit_slice(hlir.label_map.export_count as usize);
-        hlir.label_map
-            .labels
-            .into_iter()
-            .filter(|(_label, index)| *index < hlir.label_map.export_count)
-            .for_each(|(label, index)| {
-                map[index as usize].write(label);
-            });
-        unsafe { map.assume_init() }
-    };
-
-    let llir = sawblade::llir::IR::from_optir(
+    let returns = hlir
+        .specs
+        .iter()
+        .map(|spec| &spec.returns[..])
+        .collect::<Vec<_>>();
+    let mut llir = sawblade::llir::IR::from_optir(
         optir,
+        manager.analyses(),
         &label_map,
         PackedSlice {
             elements: &registers,
             ranges: &register_ranges,
         },
+        &returns,
     );
+    if level != OptLevel::O0 {
+        sawblade::llir::peephole(&mut llir);
+    }
 
-    sawblade::arch::X86_64Nasm::assemble(llir, &label_map, &mut output).unwrap();
+    A::assemble(llir, &label_map, &mut output).unwrap();
 }
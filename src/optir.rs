@@ -19,14 +19,53 @@
 //! into architecture-specific representation (i.e assembly), with
 //! label linkage information which is kept from HLIR.
 
-use crate::hlir::Condition;
-
-// NOTE: should I look into "data flow graphs"? Since phi nodes
-// here are pretty much not easy to analyze, maybe I need some sort
-// of graph that connects the blocks directly in terms of the data
-// they share between them. The `exported_bindings` property seems
-// like a step in the right direction to create such a graph, if it
-// helps to build a correct and optimized allocator.
+mod cse;
+mod dataflow;
+mod dce;
+mod dominators;
+mod effects;
+mod fold;
+mod immediates;
+mod inline;
+mod ipcp;
+mod joins;
+mod layout;
+mod licm;
+mod loops;
+mod manager;
+mod rewrite;
+mod simplify;
+mod specialize;
+mod verify;
+pub use self::cse::eliminate_common_subexpressions;
+pub use self::dataflow::{DataFlowGraph, Flow, FlowKind, Value};
+pub use self::dce::eliminate_dead_code;
+pub use self::dominators::{DominanceFrontiers, DominatorTree};
+pub use self::effects::{compute_effects, Effects};
+pub use self::fold::fold_constants;
+pub use self::immediates::select_immediates;
+pub use self::inline::inline_calls;
+pub use self::ipcp::propagate_constant_arguments;
+pub use self::joins::select_join_buckets;
+pub use self::layout::lay_out_blocks;
+pub use self::licm::hoist_loop_invariants;
+pub use self::loops::Loop;
+pub use self::manager::{
+    Analyses, EliminateCommonSubexpressions, EliminateDeadCode, FoldConstants, HoistLoopInvariants,
+    Inline, Liveness, OptLevel, Pass, PassManager, PropagateConstantArguments, SimplifyCfg,
+    Specialize,
+};
+pub use self::simplify::simplify_cfg;
+pub use self::specialize::specialize_calls;
+pub use self::verify::{verify, VerifyError, Violation};
+use crate::hlir::{
+    AtomicOrdering, BitOperation, CarryOperation, Condition, InlineHint, RmwOperation,
+};
+
+// NOTE: phi nodes here are pretty much not easy to analyze, so the
+// blocks are also connected directly in terms of the data they share
+// between them (see `DataFlowGraph`), built from the `exported_bindings`
+// of branches, the arguments of calls and the bindings that blocks return.
 use super::index;
 use std::{
     collections::{HashMap, HashSet},
@@ -75,7 +114,11 @@ pub mod bucket {
         /// bucket might be used by other results since selecting
         /// those means this binding doesn't exist, i.e used in selecting
         /// branch results through phi nodes.
-        Selective { selection_bucket: u8 },
+        /// The bucket is an index into `IR::selection_buckets`.
+        Selective { selection_bucket: u16 },
+        /// Doesn't need a place at all: the result is a constant that's encoded right into the
+        /// instruction that uses it (see `select_immediates`).
+        Immediate,
     }
 
     #[derive(Debug, Clone, Copy, Ord, PartialEq, Eq)]
@@ -188,6 +231,13 @@ pub struct Block {
     /// yielded are stored here.
     pub exported_bindings: FixedArray<index::Binding>,
     pub end: CFTransfer,
+    pub inline: Option<InlineHint>,
+    /// Exported blocks can be called from outside, so their arguments and returns must stay as
+    /// they are.
+    pub exported: bool,
+    /// Blocks made by passes (e.g the rest of a block after an inlined call) return on behalf of
+    /// the block they were split from, so they return the same way it does.
+    pub returns_for: Option<index::Label>,
 }
 
 /// The set of bindings that are used as arguments to the next block,
@@ -199,7 +249,7 @@ pub type ExportedBindings = FixedArray<index::Binding>;
 /// as empty blocks and inline wherever they're used to a no-op. They
 /// don't return anything so a checker pass will catch anything that is
 /// bound to them.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum CFTransfer {
     /// Return a set of values back to the caller.
     /// It's like a direct branch, except the target label
@@ -218,6 +268,22 @@ pub enum CFTransfer {
         target_if_false: index::Label,
         true_branch_binding_count: u8,
     },
+    /// A multi-way branch on the value of a binding.
+    /// Like a conditional branch, the exported array is shared between all the targets: each
+    /// case takes the next `binding_count` bindings in order, and the default target takes
+    /// the rest.
+    Switch {
+        value: index::Binding,
+        cases: FixedArray<SwitchCase>,
+        default: index::Label,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SwitchCase {
+    pub value: u64,
+    pub target: index::Label,
+    pub binding_count: u8,
 }
 
 /// Indicates that control flow is being passed with data
@@ -230,6 +296,13 @@ pub struct Redirection {
     pub exported_count: u8,
 }
 
+/// A call to a block, from one of the operations of another.
+#[derive(Debug, Clone, Copy)]
+pub struct CallSite {
+    pub caller: index::Label,
+    pub op_index: u16,
+}
+
 #[derive(Debug)]
 pub struct PhiSelector {
     /// (small) list of bindings that the phi
@@ -246,13 +319,14 @@ pub struct PhiSelector {
 /// There's no constants here, except the `Pure` operation that just means
 /// "assign a constant here". In order to remove the use of a binding,
 /// the optimizer will have to reduce all its uses to constants
-/// (maybe through partially inlined calls, if needed).
+/// (maybe through partially inlined calls, or copies of the called block
+/// specialized for the constants, if needed).
 ///
 /// Since a lot of assemblies support the use of constants in some
 /// instructions (to optimize for use and space), a map of what bindings
 /// are set to constants is handed to the Architecture's codegen
 /// implementation.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Op {
     Constant(Constant),
     Call {
@@ -274,10 +348,156 @@ pub enum Op {
         lhs: index::Binding,
         rhs: index::Binding,
     },
-    FetchFlags(Condition),
+    /// Whether `condition` holds for the flags that `instruction` set.
+    FetchFlags {
+        condition: Condition,
+        instruction: index::Binding,
+    },
+    /// Count or move around the bits of `value`.
+    Bit {
+        operation: BitOperation,
+        value: index::Binding,
+    },
+    /// Add or subtract with an incoming carry (or borrow), setting the outgoing one.
+    CarryArithmetic {
+        operation: CarryOperation,
+        lhs: index::Binding,
+        rhs: index::Binding,
+        carry_in: Option<index::Binding>,
+    },
+    /// The carry (or borrow) that `instruction` produced, as a 0 or 1. It is left in the
+    /// status flags when the next operation consumes it right away.
+    CarryOut {
+        instruction: index::Binding,
+    },
+    /// Atomically reads the value at `address`.
+    AtomicLoad {
+        ordering: AtomicOrdering,
+        address: index::Binding,
+    },
+    /// Atomically writes `value` to `address`. Never defines a binding.
+    AtomicStore {
+        ordering: AtomicOrdering,
+        address: index::Binding,
+        value: index::Binding,
+    },
+    /// Atomically modifies the value at `address`, defining the previous value.
+    AtomicRmw {
+        operation: RmwOperation,
+        ordering: AtomicOrdering,
+        address: index::Binding,
+        value: index::Binding,
+    },
+    /// Atomically replaces the value at `address` with `new` if it's `expected`, defining the
+    /// previous value.
+    AtomicCas {
+        ordering: AtomicOrdering,
+        address: index::Binding,
+        expected: index::Binding,
+        new: index::Binding,
+    },
+    /// Memory fence. Never defines a binding.
+    Fence(AtomicOrdering),
+    /// Inline assembly. Defines a binding for each of its `outputs`, in order.
+    Asm {
+        template: Box<str>,
+        inputs: FixedArray<(index::Register, index::Binding)>,
+        outputs: FixedArray<index::Register>,
+        clobbers: FixedArray<index::Register>,
+    },
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Op {
+    /// Whether the operation can be moved around (or removed if its results aren't used) by
+    /// the optimizer. Calls and memory ordering operations have effects that are observable
+    /// outside of their results, so they have to stay in place and in program order.
+    pub const fn is_reorderable(&self) -> bool {
+        !matches!(
+            self,
+            Op::Call { .. }
+                | Op::AtomicLoad { .. }
+                | Op::AtomicStore { .. }
+                | Op::AtomicRmw { .. }
+                | Op::AtomicCas { .. }
+                | Op::Fence(_)
+                | Op::Asm { .. }
+        )
+    }
+
+    /// Whether the code that the operation is lowered to may overwrite the status flags.
+    /// Arithmetic sets them, and so do calls and most atomic and bit operations. Inline assembly
+    /// is assumed to.
+    pub const fn overwrites_flags(&self) -> bool {
+        matches!(
+            self,
+            Op::Add { .. }
+                | Op::Sub { .. }
+                | Op::Call { .. }
+                | Op::Bit { .. }
+                | Op::CarryArithmetic { .. }
+                | Op::CarryOut { .. }
+                | Op::AtomicRmw { .. }
+                | Op::AtomicCas { .. }
+                | Op::Asm { .. }
+        )
+    }
+
+    /// The bindings that the operation uses, in order.
+    pub fn operands(&self) -> Vec<index::Binding> {
+        match self {
+            Op::Constant(_) | Op::Fence(_) => Vec::new(),
+            Op::Call { args, .. } => args.to_vec(),
+            Op::Add { lhs, rhs } | Op::Sub { lhs, rhs } => vec![*lhs, *rhs],
+            Op::FetchFlags { instruction, .. } | Op::CarryOut { instruction } => {
+                vec![*instruction]
+            }
+            Op::Bit { value, .. } => vec![*value],
+            Op::CarryArithmetic {
+                lhs, rhs, carry_in, ..
+            } => [*lhs, *rhs].into_iter().chain(*carry_in).collect(),
+            Op::AtomicLoad { address, .. } => vec![*address],
+            Op::AtomicStore { address, value, .. } | Op::AtomicRmw { address, value, .. } => {
+                vec![*address, *value]
+            }
+            Op::AtomicCas {
+                address,
+                expected,
+                new,
+                ..
+            } => vec![*address, *expected, *new],
+            Op::Asm { inputs, .. } => inputs.iter().map(|(_, input)| *input).collect(),
+        }
+    }
+
+    /// Same as `operands`, but allows replacing them.
+    pub fn operands_mut(&mut self) -> Vec<&mut index::Binding> {
+        match self {
+            Op::Constant(_) | Op::Fence(_) => Vec::new(),
+            Op::Call { args, .. } => args.iter_mut().collect(),
+            Op::Add { lhs, rhs } | Op::Sub { lhs, rhs } => vec![lhs, rhs],
+            Op::FetchFlags { instruction, .. } | Op::CarryOut { instruction } => {
+                vec![instruction]
+            }
+            Op::Bit { value, .. } => vec![value],
+            Op::CarryArithmetic {
+                lhs, rhs, carry_in, ..
+            } => [lhs, rhs].into_iter().chain(carry_in.as_mut()).collect(),
+            Op::AtomicLoad { address, .. } => vec![address],
+            Op::AtomicStore { address, value, .. } | Op::AtomicRmw { address, value, .. } => {
+                vec![address, value]
+            }
+            Op::AtomicCas {
+                address,
+                expected,
+                new,
+                ..
+            } => vec![address, expected, new],
+            Op::Asm { inputs, .. } => inputs.iter_mut().map(|(_, input)| input).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Constant {
     Numeric(u64),
     Label(index::Label),
@@ -297,8 +517,8 @@ impl TryFrom<crate::hlir::Pure> for Constant {
     }
 }
 
-/// Forward-going can be one of three kinds:
-#[derive(Debug, Clone, Copy)]
+/// Forward-going can be one of four kinds:
+#[derive(Debug, Clone)]
 pub enum ForwardEdge {
     /// This block does not jump to a statically known
     /// block, rather it uses a call/return mechanism to
@@ -310,19 +530,61 @@ pub enum ForwardEdge {
     Conditional {
         target_if_true: index::Label,
         target_if_false: index::Label,
-    }, // NOTE: the label_if_true/false info is duplicated in Value as well for now, unless accessing
-       // the map results in better performance.
+    },
+    // NOTE: the label_if_true/false info is duplicated in Value as well for now, unless accessing
+    // the map results in better performance.
+    /// A selected branch through the value of a binding. Case targets are in case order.
+    Switch {
+        cases: FixedArray<index::Label>,
+        default: index::Label,
+    },
+}
+
+impl ForwardEdge {
+    /// The blocks that may be branched to, in order. Returns go back to the caller, so they have
+    /// none.
+    pub fn targets(&self) -> Vec<index::Label> {
+        match self {
+            ForwardEdge::Dynamic => Vec::new(),
+            ForwardEdge::Direct(target) => vec![*target],
+            ForwardEdge::Conditional {
+                target_if_true,
+                target_if_false,
+            } => vec![*target_if_true, *target_if_false],
+            ForwardEdge::Switch { cases, default } => {
+                cases.iter().copied().chain(Some(*default)).collect()
+            }
+        }
+    }
 }
 
+/// How the blocks branch into each other. See `IR::branching_maps`.
 #[derive(Debug)]
-pub struct IR {
-    pub blocks: Vec<Block>,
+pub struct BranchingMaps {
     /// Branching map that goes parent->child direction.
-    pub forwards_branching_map: HashMap<index::Label, ForwardEdge>,
+    pub forwards: HashMap<index::Label, ForwardEdge>,
     /// Branching map that goes child->parent direction.
-    pub backwards_branching_map: HashMap<index::Label, FixedArray<index::Label>>,
-    pub return_blocks: Box<[Vec<index::Label>]>,
-    pub return_counts: Box<[u8]>,
+    pub backwards: HashMap<index::Label, FixedArray<index::Label>>,
+}
+
+#[derive(Debug)]
+pub struct IR {
+    pub blocks: Vec<Block>,
+    /// The blocks that were removed since the HLIR, in the order they were removed, each one with
+    /// the label it had right before. Whatever is kept per block outside of the IR (e.g the HLIR
+    /// specs) can follow along by removing the same indices.
+    pub removed_blocks: Vec<index::Label>,
+    /// Blocks whose return count can't be told, because no path out of them ever returns (e.g
+    /// they loop forever, or only ever call themselves). They're assumed to return nothing.
+    pub undetermined_returns: Vec<index::Label>,
+    /// Bindings that share a place, because only one of them exists at a time: the arguments of
+    /// join points, along with the bindings that are passed into them. Filled in by
+    /// `select_join_buckets` once the passes are done.
+    pub selection_buckets: Vec<Vec<Value>>,
+    /// The order the blocks are placed in, so that branches fall through into the next block
+    /// where they can. Filled in by `lay_out_blocks` once the passes are done; until then it's
+    /// empty, and the blocks are placed in label order.
+    pub layout: Vec<index::Label>,
 }
 
 struct BlockBuilder {
@@ -487,6 +749,196 @@ impl BlockBuilder {
             })
     }
 
+    fn compile_bit(
+        &mut self,
+        operation: BitOperation,
+        value: crate::hlir::Pure,
+    ) -> Option<index::Binding> {
+        // SAFE: bit operations always define their result
+        self.compile_with_operands([value], |[value]| Op::Bit { operation, value }, true)
+            .map(|result| unsafe { result.unwrap_unchecked() })
+    }
+
+    /// Compile an arithmetic operation with carry. Returns the binding for the result, and the
+    /// binding for the outgoing carry if `defines_carry` is set.
+    fn compile_carry_arithmetic(
+        &mut self,
+        operation: CarryOperation,
+        lhs: crate::hlir::Pure,
+        rhs: crate::hlir::Pure,
+        carry_in: Option<crate::hlir::Pure>,
+        defines_carry: bool,
+    ) -> Option<(index::Binding, Option<index::Binding>)> {
+        let lhs = self.compile_pure(lhs)?;
+        let rhs = self.compile_pure(rhs)?;
+        let carry_in = match carry_in {
+            Some(carry_in) => Some(self.compile_pure(carry_in)?),
+            None => None,
+        };
+
+        // SAFE: the operation is defined right after
+        let usage = unsafe { self.usage_for_next_op(bucket::UsageKind::Exclusive) };
+        for operand in [lhs, rhs].into_iter().chain(carry_in) {
+            self.get_usage_bucket(operand).push(usage);
+        }
+        let result = self.define(Op::CarryArithmetic {
+            operation,
+            lhs,
+            rhs,
+            carry_in,
+        });
+
+        // the carry is read right after the instruction, so that it's still in the flags.
+        let carry = defines_carry.then(|| {
+            // SAFE: the carry is defined right after
+            let usage = unsafe { self.usage_for_next_op(bucket::UsageKind::Exclusive) };
+            self.get_usage_bucket(result).push(usage);
+            self.define(Op::CarryOut {
+                instruction: result,
+            })
+        });
+        Some((result, carry))
+    }
+
+    /// Compile inline assembly. Only the outputs that are used define a binding, the rest are
+    /// just clobbered. Returns the binding for each output that is used.
+    fn compile_asm(
+        &mut self,
+        template: String,
+        inputs: Vec<(index::Register, crate::hlir::Pure)>,
+        outputs: Vec<(index::Register, bool)>,
+        mut clobbers: Vec<index::Register>,
+    ) -> Option<Vec<Option<index::Binding>>> {
+        let inputs = inputs
+            .into_iter()
+            .map(|(register, pure)| Some((register, self.compile_pure(pure)?)))
+            .try_collect::<FixedArray<_>>()?;
+
+        // SAFE: the operation is pushed right after
+        let usage = unsafe { self.usage_for_next_op(bucket::UsageKind::Exclusive) };
+        for (_, input) in inputs.iter() {
+            self.get_usage_bucket(*input).push(usage);
+        }
+
+        let op_index = self.ops.len() as u16;
+        let mut defined_outputs = Vec::new();
+        let results = outputs
+            .into_iter()
+            .map(|(register, used)| {
+                if used {
+                    defined_outputs.push(register);
+                    // SAFE: the operation is pushed right after
+                    Some(unsafe { self.new_binding(bucket::Definition::Op(op_index)) })
+                } else {
+                    clobbers.push(register);
+                    None
+                }
+            })
+            .collect();
+        self.push_op(Op::Asm {
+            template: template.into_boxed_str(),
+            inputs,
+            outputs: defined_outputs.into_boxed_slice(),
+            clobbers: clobbers.into_boxed_slice(),
+        });
+        Some(results)
+    }
+
+    /// Compile an operation that uses all of its operands at once. The operation only defines
+    /// a result if `defines_result` is set.
+    fn compile_with_operands<const N: usize>(
+        &mut self,
+        operands: [crate::hlir::Pure; N],
+        make_op: impl FnOnce([index::Binding; N]) -> Op,
+        defines_result: bool,
+    ) -> Option<Option<index::Binding>> {
+        let operands = operands.map(|pure| self.compile_pure(pure));
+        if operands.iter().any(Option::is_none) {
+            return None;
+        }
+        // SAFE: we just checked that all of them are there
+        let operands = operands.map(|operand| unsafe { operand.unwrap_unchecked() });
+
+        // SAFE: the operation is pushed right after
+        let usage = unsafe { self.usage_for_next_op(bucket::UsageKind::Exclusive) };
+        for operand in operands {
+            self.get_usage_bucket(operand).push(usage);
+        }
+
+        let op = make_op(operands);
+        if defines_result {
+            Some(Some(self.define(op)))
+        } else {
+            self.push_op(op);
+            Some(None)
+        }
+    }
+
+    /// Compile an atomic operation or fence. Returns `None` if the compilation failed, and
+    /// `Some(None)` if the operation was compiled without defining a result.
+    fn compile_atomic(
+        &mut self,
+        value: crate::hlir::Value,
+        defines_result: bool,
+    ) -> Option<Option<index::Binding>> {
+        use crate::hlir::Value;
+        match value {
+            Value::AtomicLoad { ordering, address } => self.compile_with_operands(
+                [address],
+                |[address]| Op::AtomicLoad { ordering, address },
+                defines_result,
+            ),
+            Value::AtomicStore {
+                ordering,
+                address,
+                value,
+            } => self.compile_with_operands(
+                [address, value],
+                |[address, value]| Op::AtomicStore {
+                    ordering,
+                    address,
+                    value,
+                },
+                false,
+            ),
+            Value::AtomicRmw {
+                operation,
+                ordering,
+                address,
+                value,
+            } => self.compile_with_operands(
+                [address, value],
+                |[address, value]| Op::AtomicRmw {
+                    operation,
+                    ordering,
+                    address,
+                    value,
+                },
+                defines_result,
+            ),
+            Value::AtomicCas {
+                ordering,
+                address,
+                expected,
+                new,
+            } => self.compile_with_operands(
+                [address, expected, new],
+                |[address, expected, new]| Op::AtomicCas {
+                    ordering,
+                    address,
+                    expected,
+                    new,
+                },
+                defines_result,
+            ),
+            Value::Fence(ordering) => {
+                self.push_op(Op::Fence(ordering));
+                Some(None)
+            }
+            _ => unreachable!("not an atomic operation"),
+        }
+    }
+
     fn compile_copied(
         &mut self,
         pures: Vec<crate::hlir::Pure>,
@@ -506,21 +958,21 @@ impl BlockBuilder {
         assigned_usage: AssignedUsage,
         target_return_count: u8,
     ) -> Option<BindingRange> {
-        // SAFE: we're pushing the operation later, when we finish assigning
-        // all the usages
-        let usage = unsafe { self.usage_for_next_op(bucket::UsageKind::Exclusive) };
-
         // 1. Transform all parameters into OPTIR bindings
         let params = params
             .into_iter()
-            .map(|value| {
-                let param = self.compile_pure(value)?;
-                self.get_usage_bucket(param).push(usage);
-                Some(param)
-            })
+            .map(|value| self.compile_pure(value))
             .collect::<Option<Vec<_>>>()
             .map(Vec::into_boxed_slice)?;
 
+        // SAFE: we're pushing the operation later, when we finish assigning
+        // all the usages. Compiling the parameters may have pushed operations
+        // of its own, so the index is only known now.
+        let usage = unsafe { self.usage_for_next_op(bucket::UsageKind::Exclusive) };
+        for param in params.iter().copied() {
+            self.get_usage_bucket(param).push(usage);
+        }
+
         let result_start_index = self.binding_count();
         let definition = bucket::Definition::Op(self.ops.len() as u16);
 
@@ -575,7 +1027,13 @@ impl BlockBuilder {
         Some(result_binding_range)
     }
 
-    fn release(mut self, end: CFTransfer, exported_bindings: FixedArray<index::Binding>) -> Block {
+    fn release(
+        mut self,
+        end: CFTransfer,
+        exported_bindings: FixedArray<index::Binding>,
+        inline: Option<InlineHint>,
+        exported: bool,
+    ) -> Block {
         // register usages for end
         exported_bindings.iter().copied().for_each(|binding| {
             self.get_usage_bucket(binding).push(bucket::Usage {
@@ -596,12 +1054,215 @@ impl BlockBuilder {
             operations: self.ops.into(),
             exported_bindings,
             end,
+            inline,
+            exported,
+            returns_for: None,
         }
     }
 }
 
 impl Block {
+    /// Where the block branches to, along with the range of `exported_bindings` that each target
+    /// gets. A target might appear more than once.
+    pub fn redirections(&self) -> Vec<(index::Label, Range<usize>)> {
+        match &self.end {
+            CFTransfer::Return => Vec::new(),
+            CFTransfer::DirectBranch { target } => {
+                vec![(*target, 0..self.exported_bindings.len())]
+            }
+            CFTransfer::ConditionalBranch {
+                target_if_true,
+                target_if_false,
+                true_branch_binding_count,
+                ..
+            } => {
+                let split = *true_branch_binding_count as usize;
+                vec![
+                    (*target_if_true, 0..split),
+                    (*target_if_false, split..self.exported_bindings.len()),
+                ]
+            }
+            CFTransfer::Switch { cases, default, .. } => {
+                let mut start = 0;
+                let mut redirections = cases
+                    .iter()
+                    .map(|case| {
+                        let end = start + case.binding_count as usize;
+                        let range = start..end;
+                        start = end;
+                        (case.target, range)
+                    })
+                    .collect::<Vec<_>>();
+                redirections.push((*default, start..self.exported_bindings.len()));
+                redirections
+            }
+        }
+    }
+
+    /// The labels that the block refers to, once per reference: where it branches to, what it
+    /// calls, the labels it takes as constants and the block it returns on behalf of.
+    pub fn referenced_labels(&self) -> Vec<index::Label> {
+        let mut labels = self
+            .redirections()
+            .into_iter()
+            .map(|(label, _)| label)
+            .collect::<Vec<_>>();
+        labels.extend(self.operations.iter().filter_map(|op| match op {
+            Op::Call { label, .. } | Op::Constant(Constant::Label(label)) => Some(*label),
+            _ => None,
+        }));
+        labels.extend(self.returns_for);
+        labels
+    }
+
+    /// The first binding that each operation defines, if it defines any.
+    pub fn first_results(&self) -> FixedArray<Option<index::Binding>> {
+        let mut results = vec![None; self.operations.len()].into_boxed_slice();
+        // going backwards so that the first binding of each operation is the one that stays.
+        for (binding, definition) in self.binding_defs.iter().enumerate().rev() {
+            if let bucket::Definition::Op(op_index) = definition {
+                results[*op_index as usize] =
+                    Some(unsafe { index::Binding::from_index(binding as u16) });
+            }
+        }
+        results
+    }
+
+    /// The bindings that an operation defines, in order.
+    pub fn results_of(&self, op_index: usize) -> impl Iterator<Item = index::Binding> + '_ {
+        self.binding_defs
+            .iter()
+            .enumerate()
+            .filter(move |(_, definition)| {
+                matches!(definition, bucket::Definition::Op(index) if *index as usize == op_index)
+            })
+            .map(|(binding, _)| unsafe { index::Binding::from_index(binding as u16) })
+    }
+
+    /// Removes the operations at the given indices, along with the bindings they define, and
+    /// renumbers the rest. The removed bindings must not be used anymore. Removed calls take
+    /// their usage information with them.
+    pub fn remove_operations(&mut self, removed: &HashSet<u16>) {
+        if removed.is_empty() {
+            return;
+        }
+
+        let mut op_map = Vec::with_capacity(self.operations.len());
+        let mut kept_call_usages = vec![true; self.call_return_usages.len()];
+        let mut next_op = 0;
+        for op_index in 0..self.operations.len() as u16 {
+            if removed.contains(&op_index) {
+                if let Op::Call {
+                    usage_info_index, ..
+                } = self.operations[op_index as usize]
+                {
+                    kept_call_usages[usage_info_index] = false;
+                }
+                op_map.push(None);
+            } else {
+                op_map.push(Some(next_op));
+                next_op += 1;
+            }
+        }
+        let mut usage_info_map = Vec::with_capacity(kept_call_usages.len());
+        let mut next_usage_info = 0;
+        for kept in kept_call_usages.iter() {
+            usage_info_map.push(next_usage_info);
+            next_usage_info += *kept as usize;
+        }
+
+        let mut binding_map = Vec::with_capacity(self.binding_defs.len());
+        let mut binding_defs = Vec::with_capacity(self.binding_defs.len());
+        for definition in self.binding_defs.iter().copied() {
+            let definition = match definition {
+                bucket::Definition::Argument(_) => Some(definition),
+                bucket::Definition::Op(op_index) => {
+                    op_map[op_index as usize].map(bucket::Definition::Op)
+                }
+            };
+            binding_map.push(definition.map(|_| binding_defs.len() as u16));
+            binding_defs.extend(definition);
+        }
+        let remap = |binding: &mut index::Binding| {
+            // SAFE: the new index comes from the kept bindings
+            *binding = unsafe {
+                index::Binding::from_index(
+                    binding_map[binding.to_index() as usize]
+                        .expect("removed bindings must not be used"),
+                )
+            };
+        };
+
+        let binding_usages = std::mem::take(&mut self.binding_usages);
+        self.binding_usages = binding_usages
+            .into_vec()
+            .into_iter()
+            .zip(binding_map.iter())
+            .filter(|(_, kept)| kept.is_some())
+            .map(|(usages, _)| {
+                usages
+                    .iter()
+                    .map(|usage| bucket::Usage {
+                        usage_kind: usage.usage_kind,
+                        index: match usage.index {
+                            bucket::UsageIndex::Op(op_index) => bucket::UsageIndex::Op(
+                                op_map[op_index as usize]
+                                    .expect("removed operations must not use bindings"),
+                            ),
+                            bucket::UsageIndex::BlockEnd => bucket::UsageIndex::BlockEnd,
+                        },
+                    })
+                    .collect()
+            })
+            .collect();
+        self.binding_defs = binding_defs.into_boxed_slice();
+
+        let operations = std::mem::take(&mut self.operations);
+        self.operations = operations
+            .into_vec()
+            .into_iter()
+            .zip(op_map.iter())
+            .filter(|(_, kept)| kept.is_some())
+            .map(|(mut op, _)| {
+                op.operands_mut().into_iter().for_each(remap);
+                if let Op::Call {
+                    usage_info_index, ..
+                } = &mut op
+                {
+                    *usage_info_index = usage_info_map[*usage_info_index];
+                }
+                op
+            })
+            .collect();
+
+        let call_return_usages = std::mem::take(&mut self.call_return_usages);
+        self.call_return_usages = call_return_usages
+            .into_vec()
+            .into_iter()
+            .zip(kept_call_usages)
+            .filter_map(|(usage, kept)| kept.then_some(usage))
+            .collect();
+        for usage in self.call_return_usages.iter_mut() {
+            let range = &mut usage.result_binding_range.0;
+            if range.start != range.end {
+                let start =
+                    binding_map[range.start as usize].expect("call results can't be removed");
+                *range = start..start + range.len() as u16;
+            }
+        }
+        self.exported_bindings.iter_mut().for_each(remap);
+        match &mut self.end {
+            CFTransfer::Return | CFTransfer::DirectBranch { .. } => (),
+            CFTransfer::ConditionalBranch {
+                flag_definition, ..
+            } => remap(flag_definition),
+            CFTransfer::Switch { value, .. } => remap(value),
+        }
+    }
+
     fn from_hlir_block(hlir_block: super::hlir::Block, block_return_counts: &[u8]) -> Option<Self> {
+        let inline = hlir_block.inline;
+        let exported = hlir_block.exported;
         // 1. Create the definitions
         // NOTE: I'm only using `gets` for its length... Maybe storing those arguments knowing
         // they're the first ones... welp
@@ -691,7 +1352,10 @@ impl Block {
                     {
                         let instruction = builder.get_registered_alias(instruction)?;
                         let op_index = builder.ops.len() as u16;
-                        let binding = builder.define(Op::FetchFlags(condition));
+                        let binding = builder.define(Op::FetchFlags {
+                            condition,
+                            instruction,
+                        });
                         builder.get_usage_bucket(instruction).push(bucket::Usage {
                             usage_kind: bucket::UsageKind::Exclusive,
                             index: bucket::UsageIndex::Op(op_index),
@@ -703,6 +1367,100 @@ impl Block {
                         builder.flag_definitions.insert(binding, condition);
                     }
                 }
+                Value::Bit { operation, value } => {
+                    // Pure like add, skip it if there is no target.
+                    if let Some(crate::hlir::AssignedBinding {
+                        binding: target, ..
+                    }) = assignment.used_bindings.into_iter().next()
+                    {
+                        let result = builder.compile_bit(operation, value)?;
+                        unsafe {
+                            builder.register_result(target, result);
+                        }
+                    }
+                }
+                Value::CarryArithmetic {
+                    operation,
+                    lhs,
+                    rhs,
+                    carry_in,
+                } => {
+                    // Pure like add, so it's skipped if neither the result nor the carry are used.
+                    let target = |assign_index| {
+                        assignment
+                            .used_bindings
+                            .iter()
+                            .find(|assigned| assigned.assign_index == assign_index)
+                            .map(|assigned| assigned.binding)
+                    };
+                    let (result_target, carry_target) = (target(0), target(1));
+                    if result_target.is_some() || carry_target.is_some() {
+                        let (result, carry) = builder.compile_carry_arithmetic(
+                            operation,
+                            lhs,
+                            rhs,
+                            carry_in,
+                            carry_target.is_some(),
+                        )?;
+                        unsafe {
+                            if let Some(target) = result_target {
+                                builder.register_result(target, result);
+                            }
+                            if let (Some(target), Some(carry)) = (carry_target, carry) {
+                                builder.register_result(target, carry);
+                            }
+                        }
+                    }
+                }
+                Value::Asm {
+                    template,
+                    inputs,
+                    outputs,
+                    clobbers,
+                } => {
+                    // inline assembly is always compiled, since we don't know what it does.
+                    let outputs = (0..outputs.len() as u8)
+                        .zip(outputs)
+                        .map(|(assign_index, register)| {
+                            let target = assignment
+                                .used_bindings
+                                .iter()
+                                .find(|assigned| assigned.assign_index == assign_index);
+                            (register, target.map(|assigned| assigned.binding))
+                        })
+                        .collect::<Vec<_>>();
+                    let results = builder.compile_asm(
+                        template,
+                        inputs,
+                        outputs
+                            .iter()
+                            .map(|(register, target)| (*register, target.is_some()))
+                            .collect(),
+                        clobbers,
+                    )?;
+                    for ((_, target), result) in outputs.into_iter().zip(results) {
+                        if let (Some(target), Some(result)) = (target, result) {
+                            unsafe {
+                                builder.register_result(target, result);
+                            }
+                        }
+                    }
+                }
+                value @ (Value::AtomicLoad { .. }
+                | Value::AtomicStore { .. }
+                | Value::AtomicRmw { .. }
+                | Value::AtomicCas { .. }
+                | Value::Fence(_)) => {
+                    // atomic operations are always compiled, since they have effects on memory
+                    // even if what they yield isn't used.
+                    let target = assignment.used_bindings.into_iter().next();
+                    let result = builder.compile_atomic(value, target.is_some())?;
+                    if let (Some(target), Some(result)) = (target, result) {
+                        unsafe {
+                            builder.register_result(target.binding, result);
+                        }
+                    }
+                }
             }
         }
 
@@ -730,6 +1488,51 @@ impl Block {
                     crate::hlir::Value::Flags { .. } => {
                         todo!("returning flags is not yet supported")
                     }
+                    crate::hlir::Value::Bit { operation, value } => {
+                        vec![builder.compile_bit(operation, value)?].into_boxed_slice()
+                    }
+                    crate::hlir::Value::Asm {
+                        template,
+                        inputs,
+                        outputs,
+                        clobbers,
+                    } => builder
+                        .compile_asm(
+                            template,
+                            inputs,
+                            outputs
+                                .into_iter()
+                                .map(|register| (register, true))
+                                .collect(),
+                            clobbers,
+                        )?
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<_>>()
+                        .into_boxed_slice(),
+                    crate::hlir::Value::CarryArithmetic {
+                        operation,
+                        lhs,
+                        rhs,
+                        carry_in,
+                    } => {
+                        let (result, carry) = builder
+                            .compile_carry_arithmetic(operation, lhs, rhs, carry_in, true)?;
+                        [Some(result), carry]
+                            .into_iter()
+                            .flatten()
+                            .collect::<Vec<_>>()
+                            .into_boxed_slice()
+                    }
+                    value @ (crate::hlir::Value::AtomicLoad { .. }
+                    | crate::hlir::Value::AtomicStore { .. }
+                    | crate::hlir::Value::AtomicRmw { .. }
+                    | crate::hlir::Value::AtomicCas { .. }
+                    | crate::hlir::Value::Fence(_)) => builder
+                        .compile_atomic(value, true)?
+                        .into_iter()
+                        .collect::<Vec<_>>()
+                        .into_boxed_slice(),
                 },
             ),
             // TODO: force bindings on conditional branches?
@@ -767,16 +1570,52 @@ impl Block {
                     exposed,
                 )
             }
+            crate::hlir::End::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let value = builder.get_registered_alias(value)?;
+                let mut exposed = Vec::new();
+                let mut switch_cases = Vec::with_capacity(cases.len());
+                for crate::hlir::SwitchCase { value, redirection } in cases {
+                    switch_cases.push(SwitchCase {
+                        value,
+                        target: redirection.label,
+                        binding_count: redirection.args.len() as u8,
+                    });
+                    for arg in redirection.args {
+                        exposed.push(builder.compile_pure(arg)?);
+                    }
+                }
+                for arg in default.args {
+                    exposed.push(builder.compile_pure(arg)?);
+                }
+                builder.get_usage_bucket(value).push(bucket::Usage {
+                    usage_kind: bucket::UsageKind::Exclusive,
+                    index: bucket::UsageIndex::BlockEnd,
+                });
+                (
+                    CFTransfer::Switch {
+                        value,
+                        cases: switch_cases.into_boxed_slice(),
+                        default: default.label,
+                    },
+                    exposed.into_boxed_slice(),
+                )
+            }
         };
 
-        Some(builder.release(end, exported_bindings))
+        Some(builder.release(end, exported_bindings, inline, exported))
     }
 }
 
 impl IR {
-    pub fn return_blocks_in_dependency_order(&self) -> impl Iterator<Item = index::Label> {
+    pub fn return_blocks_in_dependency_order(
+        return_blocks: &[Vec<index::Label>],
+    ) -> impl Iterator<Item = index::Label> {
         crate::DependencyOrderIterWithSet::new(
-            self.return_blocks
+            return_blocks
                 .iter()
                 .enumerate()
                 .map(|(index, returns)| {
@@ -789,28 +1628,174 @@ impl IR {
         )
     }
 
-    fn from_blocks(
-        blocks: Vec<Block>,
-        return_blocks: Box<[Vec<index::Label>]>,
-        return_counts: Box<[u8]>,
-    ) -> Self {
+    fn from_blocks(blocks: Vec<Block>) -> Self {
+        Self {
+            blocks,
+            removed_blocks: Vec::new(),
+            undetermined_returns: Vec::new(),
+            selection_buckets: Vec::new(),
+            layout: Vec::new(),
+        }
+    }
+
+    /// The registers that each block returns its exported bindings in, given the registers that
+    /// each block has to return in (e.g from its spec). Blocks that return on behalf of a block
+    /// with returns return in its registers, and the rest of the blocks get no registers.
+    pub fn return_registers<'r>(
+        &self,
+        return_blocks: &[Vec<index::Label>],
+        returns: &[&'r [index::Register]],
+    ) -> Vec<&'r [index::Register]> {
+        let returns_of = |label: index::Label| {
+            returns
+                .get(unsafe { label.to_index() } as usize)
+                .copied()
+                .unwrap_or_default()
+        };
+        let mut registers = self
+            .blocks
+            .iter()
+            .map(|block| block.returns_for.map_or(&[][..], returns_of))
+            .collect::<Vec<_>>();
+        for (index, returning) in return_blocks.iter().enumerate() {
+            let label = unsafe { index::Label::from_index(index as u16) };
+            if returns_of(label).is_empty() {
+                continue;
+            }
+            for block in returning.iter() {
+                let block = unsafe { block.to_index() } as usize;
+                if let CFTransfer::Return = self.blocks[block].end {
+                    registers[block] = returns_of(label);
+                }
+            }
+        }
+        registers
+    }
+
+    /// The blocks that each block may end up returning from: the ones that return, out of the
+    /// ones it may branch to (itself included).
+    pub fn return_blocks(&self, maps: &BranchingMaps) -> FixedArray<Vec<index::Label>> {
+        (0..self.blocks.len() as u16)
+            .map(|index| {
+                let start = unsafe { index::Label::from_index(index) };
+                let mut visited = HashSet::from([start]);
+                let mut pending = vec![start];
+                let mut returns = Vec::new();
+                while let Some(label) = pending.pop() {
+                    let edge = &maps.forwards[&label];
+                    if let ForwardEdge::Dynamic = edge {
+                        returns.push(label);
+                        continue;
+                    }
+                    for target in edge.targets() {
+                        if visited.insert(target) {
+                            pending.push(target);
+                        }
+                    }
+                }
+                returns
+            })
+            .collect()
+    }
+
+    /// How many values each block returns, from the blocks it returns from. Blocks that never
+    /// return are assumed to return nothing, like when they're compiled from the HLIR.
+    pub fn return_counts(&self, return_blocks: &[Vec<index::Label>]) -> FixedArray<u8> {
+        return_blocks
+            .iter()
+            .map(|returning| {
+                returning
+                    .iter()
+                    .map(|label| {
+                        self.blocks[unsafe { label.to_index() } as usize]
+                            .exported_bindings
+                            .len() as u8
+                    })
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// The call graph, as the places each block is called from. Blocks that are never called
+    /// aren't in it.
+    pub fn call_sites(&self) -> HashMap<index::Label, Vec<CallSite>> {
+        let mut call_sites: HashMap<_, Vec<_>> = HashMap::new();
+        for (block_index, block) in self.blocks.iter().enumerate() {
+            for (op_index, op) in block.operations.iter().enumerate() {
+                if let Op::Call { label, .. } = op {
+                    call_sites.entry(*label).or_default().push(CallSite {
+                        caller: unsafe { index::Label::from_index(block_index as u16) },
+                        op_index: op_index as u16,
+                    });
+                }
+            }
+        }
+        call_sites
+    }
+
+    /// Removes the blocks at the `removed` indices, moving the labels of the rest down to fill
+    /// the gaps, and logs them in `removed_blocks`. Nothing that stays may refer to a removed
+    /// block.
+    fn remove_blocks(&mut self, removed: &HashSet<usize>) {
+        let blocks = std::mem::take(&mut self.blocks);
+        let mut moves = Vec::new();
+        for (index, block) in blocks.into_iter().enumerate() {
+            if removed.contains(&index) {
+                continue;
+            }
+            // SAFE: these are block indices, so they're true labels.
+            let previous = unsafe { index::Label::from_index(index as u16) };
+            let next = unsafe { index::Label::from_index(self.blocks.len() as u16) };
+            if previous != next {
+                moves.push((previous, next));
+            }
+            self.blocks.push(block);
+        }
+
+        self.undetermined_returns
+            .retain(|label| !removed.contains(&(unsafe { label.to_index() } as usize)));
+        // SAFE: labels only ever move down, and in increasing order, so a label is always moved
+        // onto one that was removed or that was already moved away.
+        for (previous, next) in moves {
+            self.blocks
+                .iter_mut()
+                .for_each(|block| unsafe { block.move_label(previous, next) });
+            self.undetermined_returns
+                .iter_mut()
+                .for_each(|label| unsafe { label.move_label(previous, next) });
+        }
+
+        // going backwards, so that each index is still right when it's removed.
+        let mut removed = removed.iter().copied().collect::<Vec<_>>();
+        removed.sort_unstable_by(|a, b| b.cmp(a));
+        self.removed_blocks.extend(
+            removed
+                .into_iter()
+                .map(|index| unsafe { index::Label::from_index(index as u16) }),
+        );
+    }
+
+    /// Builds the parent->child and child->parent branching maps of the blocks.
+    pub fn branching_maps(&self) -> BranchingMaps {
         let mut backwards_branching_map: HashMap<_, HashSet<_>> =
-            HashMap::with_capacity(blocks.len());
-        let forwards_branching_map: HashMap<_, _> = blocks
+            HashMap::with_capacity(self.blocks.len());
+        let forwards_branching_map: HashMap<_, _> = self
+            .blocks
             .iter()
             .enumerate()
             .map(|(index, block)| {
                 let label = unsafe { index::Label::from_index(index as u16) };
-                let edge = match block.end {
-                    CFTransfer::Return { .. } => ForwardEdge::Dynamic,
-                    CFTransfer::DirectBranch { target, .. } => {
+                let edge = match &block.end {
+                    CFTransfer::Return => ForwardEdge::Dynamic,
+                    &CFTransfer::DirectBranch { target, .. } => {
                         backwards_branching_map
                             .entry(target)
                             .or_default()
                             .insert(label);
                         ForwardEdge::Direct(target)
                     }
-                    CFTransfer::ConditionalBranch {
+                    &CFTransfer::ConditionalBranch {
                         target_if_true,
                         target_if_false,
                         ..
@@ -828,6 +1813,18 @@ impl IR {
                             target_if_false,
                         }
                     }
+                    CFTransfer::Switch { cases, default, .. } => {
+                        for target in cases.iter().map(|case| case.target).chain(Some(*default)) {
+                            backwards_branching_map
+                                .entry(target)
+                                .or_default()
+                                .insert(label);
+                        }
+                        ForwardEdge::Switch {
+                            cases: cases.iter().map(|case| case.target).collect(),
+                            default: *default,
+                        }
+                    }
                 };
                 (label, edge)
             })
@@ -843,12 +1840,9 @@ impl IR {
             })
             .collect();
 
-        Self {
-            blocks,
-            forwards_branching_map,
-            backwards_branching_map,
-            return_blocks,
-            return_counts,
+        BranchingMaps {
+            forwards: forwards_branching_map,
+            backwards: backwards_branching_map,
         }
     }
 }
@@ -901,8 +1895,15 @@ impl MoveLabel for Op {
                 usage_info_index: _,
             } => unsafe { label.move_label(previous, next) },
             Op::Add { lhs: _, rhs: _ } => (),
-            Op::FetchFlags(_) => (),
+            Op::FetchFlags { .. } => (),
             Op::Sub { lhs: _, rhs: _ } => (),
+            Op::CarryArithmetic { .. } | Op::CarryOut { .. } | Op::Bit { .. } => (),
+            Op::AtomicLoad { .. }
+            | Op::AtomicStore { .. }
+            | Op::AtomicRmw { .. }
+            | Op::AtomicCas { .. }
+            | Op::Fence(_)
+            | Op::Asm { .. } => (),
         }
     }
 }
@@ -921,178 +1922,342 @@ impl MoveLabel for Block {
                 target_if_true.move_label(previous, next);
                 target_if_false.move_label(previous, next);
             },
+            CFTransfer::Switch { cases, default, .. } => unsafe {
+                for case in cases.iter_mut() {
+                    case.target.move_label(previous, next);
+                }
+                default.move_label(previous, next);
+            },
         }
         for op in self.operations.iter_mut() {
             unsafe {
                 op.move_label(previous, next);
             }
         }
+        for usage in self.call_return_usages.iter_mut() {
+            unsafe {
+                usage.called_label.move_label(previous, next);
+            }
+        }
+        if let Some(label) = &mut self.returns_for {
+            unsafe {
+                label.move_label(previous, next);
+            }
+        }
     }
 }
 
-pub fn dissect_from_hlir(blocks: Vec<crate::hlir::Block>) -> IR {
-    use std::collections::BinaryHeap;
-    let mut returning_blocks = vec![HashSet::new(); blocks.len()].into_boxed_slice();
-    let (return_counts, malformed_branches) = compute_return_counts(&blocks, &mut returning_blocks);
+/// A branch whose targets return different amounts of values, so how many values come out of it
+/// depends on where it goes.
+#[derive(Debug, Clone)]
+pub struct MalformedBranch {
+    /// The block that branches, labeled as in the HLIR.
+    pub block: index::Label,
+    /// Two of its targets that disagree, along with how many values each one returns.
+    pub targets: [(index::Label, u8); 2],
+}
 
-    let mut compiled_blocks = blocks
-        .into_iter()
-        .filter_map(|block| Block::from_hlir_block(block, &return_counts))
-        .collect::<Vec<_>>();
+/// Makes the OPTIR of the HLIR blocks. Fails with the malformed branches if there's any.
+pub fn dissect_from_hlir(blocks: Vec<crate::hlir::Block>) -> Result<IR, Vec<MalformedBranch>> {
+    let (ir, _, malformed_branches) = compile_hlir_blocks(blocks);
+    if malformed_branches.is_empty() {
+        Ok(ir)
+    } else {
+        Err(malformed_branches)
+    }
+}
 
-    // being a max-heap, we'll have max indices first
-    let malformed_branches = BinaryHeap::from_iter(malformed_branches.into_iter());
-
-    for remove_index in malformed_branches.into_iter_sorted() {
-        // offset labels back one by one
-        for move_index in remove_index..(compiled_blocks.len() as u16).saturating_sub(1) {
-            // SAFE: we're using block indices, so these are true labels.
-            let old_label = unsafe { index::Label::from_index(move_index + 1) };
-            let new_label = unsafe { index::Label::from_index(move_index) };
-            // SAFE:
-            // - by doing the iteration is in this order, we ensure that labels aren't
-            // duplicated
-            // - all blocks that had a reference to this block appear in `malformed_branches`,
-            // so they are removed as well.
-            compiled_blocks
-                .iter_mut()
-                .for_each(|block| unsafe { block.move_label(old_label, new_label) });
+/// Same as `dissect_from_hlir`, but the malformed branches are removed instead, along with every
+/// block that ends up in them or refers to a removed block. The branches are returned anyway, so
+/// that they can be reported.
+pub fn dissect_from_hlir_lenient(blocks: Vec<crate::hlir::Block>) -> (IR, Vec<MalformedBranch>) {
+    let (mut ir, malformed, malformed_branches) = compile_hlir_blocks(blocks);
+    let mut referrers = vec![Vec::new(); ir.blocks.len()];
+    for (index, block) in ir.blocks.iter().enumerate() {
+        for label in block.referenced_labels() {
+            referrers[unsafe { label.to_index() } as usize].push(index);
         }
+    }
+    let mut removed = malformed
+        .into_iter()
+        .map(|index| index as usize)
+        .collect::<HashSet<_>>();
+    let mut pending = removed.iter().copied().collect::<Vec<_>>();
+    while let Some(index) = pending.pop() {
+        for referrer in referrers[index].iter().copied() {
+            if removed.insert(referrer) {
+                pending.push(referrer);
+            }
+        }
+    }
 
-        // now we can safely remove the block
-        compiled_blocks.remove(remove_index as usize);
+    if !removed.is_empty() {
+        ir.remove_blocks(&removed);
     }
+    (ir, malformed_branches)
+}
+
+/// Compiles every HLIR block, malformed or not. Returns the IR, along with the blocks that return
+/// different amounts of values depending on the path they take and where that starts.
+fn compile_hlir_blocks(
+    blocks: Vec<crate::hlir::Block>,
+) -> (IR, HashSet<u16>, Vec<MalformedBranch>) {
+    let ReturnCounts {
+        counts,
+        malformed,
+        malformed_branches,
+        undetermined,
+    } = compute_return_counts(&blocks);
+
+    let compiled_blocks = blocks
+        .into_iter()
+        .filter_map(|block| Block::from_hlir_block(block, &counts))
+        .collect::<Vec<_>>();
 
-    IR::from_blocks(
-        compiled_blocks,
-        crate::BoxIntoIter::new(returning_blocks)
-            .map(|set| set.into_iter().collect())
-            .collect::<Vec<_>>()
-            .into_boxed_slice(),
-        return_counts,
-    )
+    let ir = IR {
+        undetermined_returns: undetermined,
+        ..IR::from_blocks(compiled_blocks)
+    };
+    (ir, malformed, malformed_branches)
 }
 
-/// Returns an array of the return amounts for each block, as well as the blocks that have
-/// different return counts per branch.
-// NOTE: zero counts might just be that they're a loop
-fn compute_return_counts(
-    blocks: &[crate::hlir::Block],
-    returning_blocks: &mut [HashSet<index::Label>],
-) -> (FixedArray<u8>, HashSet<u16>) {
-    use crate::hlir::End;
-    use std::collections::VecDeque;
-    let mut slice = vec![0; blocks.len()];
+/// What is known about how many values a block returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReturnCount {
+    /// Nothing yet: no path out of the block that returns has been seen.
+    Unknown,
+    Known(u8),
+    /// Paths out of the block return different amounts of values.
+    Malformed,
+}
 
-    let mut solved = HashSet::new();
-    let mut malformed_branches = HashSet::new();
+impl ReturnCount {
+    /// The count of a block that may leave through either `self` or `other`. Paths that never
+    /// return don't constrain it.
+    fn join(self, other: Self) -> Self {
+        match (self, other) {
+            (ReturnCount::Malformed, _) | (_, ReturnCount::Malformed) => ReturnCount::Malformed,
+            (ReturnCount::Unknown, count) | (count, ReturnCount::Unknown) => count,
+            (ReturnCount::Known(lhs), ReturnCount::Known(rhs)) if lhs == rhs => {
+                ReturnCount::Known(lhs)
+            }
+            (ReturnCount::Known(_), ReturnCount::Known(_)) => ReturnCount::Malformed,
+        }
+    }
+}
 
-    struct Task {
-        index: u16,
-        tries: u16,
+/// The blocks that the return count of a block depends on: the ones it branches to, or the one it
+/// ends by calling.
+fn return_dependencies(block: &crate::hlir::Block) -> Vec<u16> {
+    use crate::hlir::{End, Value};
+    match &block.end {
+        End::TailValue(Value::Call { label, .. }) => vec![unsafe { label.to_index() }],
+        End::TailValue(_) => Vec::new(),
+        End::ConditionalBranch {
+            if_true, if_false, ..
+        } => vec![unsafe { if_true.label.to_index() }, unsafe {
+            if_false.label.to_index()
+        }],
+        End::Switch { cases, default, .. } => cases
+            .iter()
+            .map(|case| &case.redirection)
+            .chain(Some(default))
+            .map(|redirection| unsafe { redirection.label.to_index() })
+            .collect(),
     }
+}
 
-    impl Task {
-        const fn new(index: u16) -> Self {
-            Self { index, tries: 0 }
+/// The return count of a block from the counts of the blocks it depends on.
+fn evaluate_return_count(block: &crate::hlir::Block, counts: &[ReturnCount]) -> ReturnCount {
+    use crate::hlir::{End, Value};
+    match &block.end {
+        End::TailValue(value) => ReturnCount::Known(match value {
+            Value::Copied(pures) => pures.len() as u8,
+            Value::Add { .. }
+            | Value::Sub { .. }
+            | Value::Bit { .. }
+            | Value::AtomicLoad { .. }
+            | Value::AtomicRmw { .. }
+            | Value::AtomicCas { .. } => 1,
+            Value::Asm { outputs, .. } => outputs.len() as u8,
+            Value::CarryArithmetic { .. } => 2,
+            Value::AtomicStore { .. } | Value::Fence(_) => 0,
+            Value::Call { label, .. } => return counts[unsafe { label.to_index() } as usize],
+            Value::Flags { .. } => todo!("returning flags is not yet supported"),
+        }),
+        End::ConditionalBranch { .. } | End::Switch { .. } => return_dependencies(block)
+            .into_iter()
+            .map(|target| counts[target as usize])
+            .fold(ReturnCount::Unknown, ReturnCount::join),
+    }
+}
+
+struct ReturnCounts {
+    /// How many values each block returns.
+    counts: FixedArray<u8>,
+    /// The blocks that return different amounts of values depending on the path they take, and
+    /// the ones that end up in them. They're left at zero.
+    malformed: HashSet<u16>,
+    /// Where the paths disagree first.
+    malformed_branches: Vec<MalformedBranch>,
+    /// The blocks whose return count can't be told because they never return. They're left at
+    /// zero.
+    undetermined: Vec<index::Label>,
+}
+
+/// Computes how many values each block returns.
+///
+/// Each block depends on the blocks it branches to or ends by calling, so the counts are solved
+/// one strongly connected component of that graph at a time, dependencies first. Within a
+/// component, blocks are evaluated again whenever a block they depend on changes. A count only
+/// ever goes from unknown to known to malformed, so the counts settle in linear time.
+fn compute_return_counts(blocks: &[crate::hlir::Block]) -> ReturnCounts {
+    let dependencies = blocks.iter().map(return_dependencies).collect::<Vec<_>>();
+    let mut dependents = vec![Vec::new(); blocks.len()];
+    for (index, targets) in dependencies.iter().enumerate() {
+        for target in targets {
+            dependents[*target as usize].push(index as u16);
         }
     }
 
-    // ensure we go through everyone before we repeat.
-    let mut queue = VecDeque::from_iter((0..blocks.len() as u16).map(Task::new));
+    let mut counts = vec![ReturnCount::Unknown; blocks.len()];
+    let mut malformed_branches = Vec::new();
+    let mut component_of = vec![0; blocks.len()];
+    let components = strongly_connected_components(blocks.len(), |index| {
+        dependencies[index]
+            .iter()
+            .map(|target| *target as usize)
+            .collect()
+    });
+    for (component_index, component) in components.iter().enumerate() {
+        for index in component.iter() {
+            component_of[*index] = component_index;
+        }
 
-    while let Some(mut next) = queue.pop_front() {
-        match &blocks[next.index as usize].end {
-            End::TailValue(value) => {
-                // since we're at a TailValue, this block returns from itself.
-                returning_blocks[next.index as usize]
-                    .insert(unsafe { index::Label::from_index(next.index) });
-                match value {
-                    crate::hlir::Value::Copied(pures) => {
-                        slice[next.index as usize] = pures.len() as u8;
-                        solved.insert(next.index);
-                        continue;
-                    }
-                    crate::hlir::Value::Add { lhs: _, rest: _ }
-                    | crate::hlir::Value::Sub { lhs: _, rest: _ } => {
-                        slice[next.index as usize] = 1;
-                        solved.insert(next.index);
-                        continue;
+        let mut pending = component.iter().rev().copied().collect::<Vec<_>>();
+        while let Some(index) = pending.pop() {
+            let count = evaluate_return_count(&blocks[index], &counts);
+            let targets = &dependencies[index];
+            if count == ReturnCount::Malformed && counts[index] != ReturnCount::Malformed {
+                // the first block to disagree has two targets that are known to; the rest only
+                // end up in it.
+                let mut known = targets.iter().filter_map(|target| {
+                    let label = unsafe { index::Label::from_index(*target) };
+                    match counts[*target as usize] {
+                        ReturnCount::Known(count) => Some((label, count)),
+                        _ => None,
                     }
-                    crate::hlir::Value::Call { label, params: _ } => {
-                        let target_index = unsafe { label.to_index() };
-                        // NOTE: same TODO  from below applies here, but to a single call. This
-                        // implies that the rest of operations after any call whose block yields
-                        // never makes for the deletion of the *rest* of the block. And yes, this
-                        // could be done in HLIR :). Doing this is safe because the situations for
-                        // yielding never are exactly two: entering a block that yields never or
-                        // completing an unconditional jump chain.
-                        if malformed_branches.contains(&target_index) {
-                            malformed_branches.insert(next.index);
-                            continue;
-                        }
-                        // if our dependency was solved, then we can resolve this one to the same
-                        if solved.contains(&target_index) {
-                            slice[next.index as usize] = slice[target_index as usize];
-                            solved.insert(next.index);
-                            continue;
-                        }
-                    }
-                    crate::hlir::Value::Flags { .. } => {
-                        todo!("returning flags is not yet supported")
+                });
+                if let Some(first) = known.next() {
+                    if let Some(second) = known.find(|(_, count)| *count != first.1) {
+                        malformed_branches.push(MalformedBranch {
+                            block: unsafe { index::Label::from_index(index as u16) },
+                            targets: [first, second],
+                        });
                     }
                 }
             }
-            End::ConditionalBranch {
-                flag: _,
-                if_true,
-                if_false,
-            } => {
-                let true_index = unsafe { if_true.label.to_index() };
-                let false_index = unsafe { if_false.label.to_index() };
-                if malformed_branches.contains(&true_index)
-                    || malformed_branches.contains(&false_index)
-                {
-                    malformed_branches.insert(next.index);
-                    continue;
-                }
-                if solved.contains(&true_index) && solved.contains(&false_index) {
-                    // block calls are malformed
-                    // TODO: this is not particularly true, specially in the case that one of the
-                    // blocks never returns. This behavior should be pre-detected and then this
-                    // expression should select the amount of returns from the block that never
-                    // returns. If both blocks return and they return different arguments, then it
-                    // IS a malformed branch since it yields undefined values.
-                    if slice[true_index as usize] != slice[false_index as usize] {
-                        malformed_branches.insert(next.index);
-                        continue;
-                    }
+            if count != counts[index] {
+                counts[index] = count;
+                // blocks in components solved before don't depend on this one.
+                pending.extend(
+                    dependents[index]
+                        .iter()
+                        .map(|dependent| *dependent as usize)
+                        .filter(|dependent| component_of[*dependent] == component_index),
+                );
+            }
+        }
+    }
 
-                    // sadly due to the possibility of iterator invalidation during the extend()
-                    // method and my absence of knowledge about the inner workings of
-                    // HashSet::extend I have to clone these two.
-                    // TODO: peek into the implementation of HashSet::extend and figure out how to
-                    // avoid these two copies.
-                    let true_set = returning_blocks[true_index as usize].clone();
-                    let false_set = returning_blocks[false_index as usize].clone();
-
-                    returning_blocks[next.index as usize]
-                        .extend(false_set.into_iter().chain(true_set));
-                    slice[next.index as usize] = slice[true_index as usize];
-                    solved.insert(next.index);
-                    continue;
-                }
+    let mut malformed = HashSet::new();
+    let mut undetermined = Vec::new();
+    let counts = counts
+        .into_iter()
+        .enumerate()
+        .map(|(index, count)| match count {
+            ReturnCount::Known(count) => count,
+            ReturnCount::Unknown => {
+                undetermined.push(unsafe { index::Label::from_index(index as u16) });
+                0
+            }
+            ReturnCount::Malformed => {
+                malformed.insert(index as u16);
+                0
             }
+        })
+        .collect();
+    ReturnCounts {
+        counts,
+        malformed,
+        malformed_branches,
+        undetermined,
+    }
+}
+
+/// The strongly connected components of a graph, each one after every component it reaches
+/// (i.e in reverse topological order). Blocks are visited in index order, so the result is
+/// always the same for the same graph.
+fn strongly_connected_components(
+    count: usize,
+    successors: impl Fn(usize) -> Vec<usize>,
+) -> Vec<Vec<usize>> {
+    // Tarjan's algorithm, without recursion so that long call chains don't overflow the stack.
+    let mut order = vec![None::<usize>; count];
+    let mut lowlink = vec![0; count];
+    let mut on_stack = vec![false; count];
+    let mut stack = Vec::new();
+    let mut components = Vec::new();
+    let mut next_order = 0;
+    for root in 0..count {
+        if order[root].is_some() {
+            continue;
         }
-        if next.tries < 10 {
-            next.tries += 1;
-            // note: 10 tries means 10! call chain depth, which is very very unlikely.
-            // I'll just won't continue this one and leave it as zero.
-            queue.push_back(next);
+        let mut path = vec![(root, successors(root).into_iter())];
+        order[root] = Some(next_order);
+        lowlink[root] = next_order;
+        next_order += 1;
+        stack.push(root);
+        on_stack[root] = true;
+        while let Some((index, remaining)) = path.last_mut() {
+            let index = *index;
+            match remaining.next() {
+                Some(next) => match order[next] {
+                    None => {
+                        order[next] = Some(next_order);
+                        lowlink[next] = next_order;
+                        next_order += 1;
+                        stack.push(next);
+                        on_stack[next] = true;
+                        path.push((next, successors(next).into_iter()));
+                    }
+                    Some(next_order) if on_stack[next] => {
+                        lowlink[index] = lowlink[index].min(next_order);
+                    }
+                    Some(_) => (),
+                },
+                None => {
+                    path.pop();
+                    if let Some((parent, _)) = path.last() {
+                        lowlink[*parent] = lowlink[*parent].min(lowlink[index]);
+                    }
+                    if Some(lowlink[index]) == order[index] {
+                        let mut component = Vec::new();
+                        while let Some(member) = stack.pop() {
+                            on_stack[member] = false;
+                            component.push(member);
+                            if member == index {
+                                break;
+                            }
+                        }
+                        component.reverse();
+                        components.push(component);
+                    }
+                }
+            }
         }
     }
-
-    (slice.into_boxed_slice(), malformed_branches)
+    components
 }
 
 // NOTE: specs aren't used by optimizers, they're used by allocators.
@@ -0,0 +1,130 @@
+//! Local common subexpression elimination, through value numbering.
+//!
+//! Each pure operation is numbered by what it computes out of its (already numbered) operands.
+//! When an operation computes the same as an earlier one in the block, its usages are moved to
+//! the earlier result and the operation is removed.
+
+use std::collections::HashMap;
+
+use super::dce::remove_dead;
+use super::{Block, CFTransfer, Constant, Op, IR};
+use crate::hlir::{BitOperation, Condition};
+use crate::index;
+
+/// What a pure operation computes.
+#[derive(PartialEq, Eq, Hash)]
+enum Expression {
+    Numeric(u64),
+    Label(index::Label),
+    /// Operands are sorted, since adding is commutative.
+    Add(index::Binding, index::Binding),
+    Sub(index::Binding, index::Binding),
+    Bit(BitOperation, index::Binding),
+    Flags(Condition, index::Binding),
+}
+
+impl Expression {
+    fn of(op: &Op) -> Option<Self> {
+        Some(match op {
+            Op::Constant(Constant::Numeric(value)) => Self::Numeric(*value),
+            Op::Constant(Constant::Label(label)) => Self::Label(*label),
+            Op::Add { lhs, rhs } => {
+                if unsafe { lhs.to_index() <= rhs.to_index() } {
+                    Self::Add(*lhs, *rhs)
+                } else {
+                    Self::Add(*rhs, *lhs)
+                }
+            }
+            Op::Sub { lhs, rhs } => Self::Sub(*lhs, *rhs),
+            Op::Bit { operation, value } => Self::Bit(*operation, *value),
+            Op::FetchFlags {
+                condition,
+                instruction,
+            } => Self::Flags(*condition, *instruction),
+            // carries are read from the flags right after the instruction that set them, so
+            // they're tied to their place.
+            _ => return None,
+        })
+    }
+}
+
+/// Eliminates the repeated operations of every block. Returns whether anything changed.
+pub fn eliminate_common_subexpressions(ir: &mut IR) -> bool {
+    let mut changed = false;
+    for block in ir.blocks.iter_mut() {
+        changed |= number_values(block);
+    }
+    changed
+}
+
+fn number_values(block: &mut Block) -> bool {
+    let results = block.first_results();
+    // the flags of an instruction can only be read while it's the last one that set them, so
+    // the instructions whose flags are read have to stay where they are.
+    let flag_sources = block
+        .operations
+        .iter()
+        .filter_map(|op| match op {
+            Op::FetchFlags { instruction, .. } | Op::CarryOut { instruction } => Some(*instruction),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut numbered = HashMap::new();
+    let mut replacements = HashMap::new();
+    for op_index in 0..block.operations.len() {
+        for operand in block.operations[op_index].operands_mut() {
+            if let Some(replacement) = replacements.get(operand) {
+                *operand = *replacement;
+            }
+        }
+
+        let (Some(result), Some(expression)) = (
+            results[op_index],
+            Expression::of(&block.operations[op_index]),
+        ) else {
+            continue;
+        };
+        match numbered.get(&expression) {
+            Some(first) if !flag_sources.contains(&result) => {
+                replacements.insert(result, *first);
+            }
+            Some(_) => (),
+            None => {
+                numbered.insert(expression, result);
+            }
+        }
+    }
+
+    if replacements.is_empty() {
+        return false;
+    }
+
+    let replace = |binding: &mut index::Binding| {
+        if let Some(replacement) = replacements.get(binding) {
+            *binding = *replacement;
+        }
+    };
+    block.exported_bindings.iter_mut().for_each(replace);
+    match &mut block.end {
+        CFTransfer::Return | CFTransfer::DirectBranch { .. } => (),
+        CFTransfer::ConditionalBranch {
+            flag_definition, ..
+        } => replace(flag_definition),
+        CFTransfer::Switch { value, .. } => replace(value),
+    }
+
+    // the usages are kept in order, since allocators look at the first and last ones.
+    for (repeated, first) in replacements.iter() {
+        let moved =
+            std::mem::take(&mut block.binding_usages[unsafe { repeated.to_index() } as usize]);
+        let usages = &mut block.binding_usages[unsafe { first.to_index() } as usize];
+        let mut merged = std::mem::take(usages).into_vec();
+        merged.extend(moved.iter().copied());
+        merged.sort_by_key(|usage| usage.index);
+        *usages = merged.into_boxed_slice();
+    }
+
+    remove_dead(block, replacements.into_keys().collect());
+    true
+}
@@ -0,0 +1,207 @@
+//! Data flow between blocks.
+//!
+//! Within a block, bindings are connected by the operations that use them. Between blocks, they're
+//! connected by what the blocks pass each other: the bindings exported by a branch become the
+//! arguments of its target, the arguments of a call become the arguments of the called block, and
+//! the bindings that a block returns become the results of the calls to it. The graph connects
+//! those bindings directly, so values can be followed (and kept in the same place) across blocks.
+
+use std::collections::HashMap;
+
+use super::{CFTransfer, Op, IR};
+use crate::index;
+
+/// A binding of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Value {
+    pub block: index::Label,
+    pub binding: index::Binding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowKind {
+    /// An exported binding, into an argument of the target of the branch.
+    Branch,
+    /// An argument of the call at `op_index`, into an argument of the called block.
+    Call { op_index: u16 },
+    /// A returned binding, into a result of the call at `op_index`.
+    Return { op_index: u16 },
+}
+
+/// A value that becomes another one when control goes from one block to the next.
+#[derive(Debug, Clone, Copy)]
+pub struct Flow {
+    pub from: Value,
+    pub to: Value,
+    pub kind: FlowKind,
+}
+
+/// Every flow of a value from one block into another.
+pub struct DataFlowGraph {
+    pub flows: Vec<Flow>,
+    /// Indices into `flows`, by the value they come from.
+    outgoing: HashMap<Value, Vec<usize>>,
+    /// Indices into `flows`, by the value they go into.
+    incoming: HashMap<Value, Vec<usize>>,
+}
+
+impl DataFlowGraph {
+    /// Builds the graph, following the results of calls to the blocks that the called ones
+    /// return from (see `IR::return_blocks`).
+    pub fn compute(ir: &IR, return_blocks: &[Vec<index::Label>]) -> Self {
+        let mut flows = Vec::new();
+        for (block_index, block) in ir.blocks.iter().enumerate() {
+            let label = unsafe { index::Label::from_index(block_index as u16) };
+            let value = |binding| Value {
+                block: label,
+                binding,
+            };
+
+            for (target, range) in block.redirections() {
+                for (arg, binding) in block.exported_bindings[range].iter().copied().enumerate() {
+                    flows.push(Flow {
+                        from: value(binding),
+                        to: argument(target, arg),
+                        kind: FlowKind::Branch,
+                    });
+                }
+            }
+
+            for (op_index, op) in block.operations.iter().enumerate() {
+                let Op::Call {
+                    label: called,
+                    args,
+                    usage_info_index,
+                } = op
+                else {
+                    continue;
+                };
+                let op_index = op_index as u16;
+                for (arg, binding) in args.iter().copied().enumerate() {
+                    flows.push(Flow {
+                        from: value(binding),
+                        to: argument(*called, arg),
+                        kind: FlowKind::Call { op_index },
+                    });
+                }
+
+                let usage = &block.call_return_usages[*usage_info_index];
+                let results = usage
+                    .result_usage
+                    .iter()
+                    .zip(usage.result_binding_range.clone());
+                for (returned, result) in results {
+                    // blocks returning on behalf of the called one return its values too.
+                    for returning in return_blocks[unsafe { called.to_index() } as usize]
+                        .iter()
+                        .copied()
+                    {
+                        let returning_block = &ir.blocks[unsafe { returning.to_index() } as usize];
+                        let CFTransfer::Return = returning_block.end else {
+                            continue;
+                        };
+                        if let Some(binding) =
+                            returning_block.exported_bindings.get(*returned as usize)
+                        {
+                            flows.push(Flow {
+                                from: Value {
+                                    block: returning,
+                                    binding: *binding,
+                                },
+                                to: value(result),
+                                kind: FlowKind::Return { op_index },
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut outgoing: HashMap<_, Vec<_>> = HashMap::new();
+        let mut incoming: HashMap<_, Vec<_>> = HashMap::new();
+        for (index, flow) in flows.iter().enumerate() {
+            outgoing.entry(flow.from).or_default().push(index);
+            incoming.entry(flow.to).or_default().push(index);
+        }
+        Self {
+            flows,
+            outgoing,
+            incoming,
+        }
+    }
+
+    /// Where the value goes once it leaves its block.
+    pub fn outgoing(&self, value: Value) -> impl Iterator<Item = &Flow> {
+        self.outgoing
+            .get(&value)
+            .into_iter()
+            .flatten()
+            .map(|index| &self.flows[*index])
+    }
+
+    /// Where the value comes from, if it's an argument or the result of a call.
+    pub fn incoming(&self, value: Value) -> impl Iterator<Item = &Flow> {
+        self.incoming
+            .get(&value)
+            .into_iter()
+            .flatten()
+            .map(|index| &self.flows[*index])
+    }
+
+    /// The values that are connected by the flows that `connects` accepts, either way, grouped
+    /// together. Keeping each group in the same place saves moving values around between blocks.
+    /// Values that aren't connected to any other aren't in any group.
+    pub fn groups(&self, connects: impl Fn(&Flow) -> bool) -> Vec<Vec<Value>> {
+        let mut group_of: HashMap<Value, usize> = HashMap::new();
+        let mut groups: Vec<Vec<Value>> = Vec::new();
+        for flow in self.flows.iter().filter(|flow| connects(flow)) {
+            match (
+                group_of.get(&flow.from).copied(),
+                group_of.get(&flow.to).copied(),
+            ) {
+                (Some(from), Some(to)) if from == to => (),
+                (Some(from), Some(to)) => {
+                    // the smaller group joins the bigger one.
+                    let (kept, merged) = if groups[from].len() >= groups[to].len() {
+                        (from, to)
+                    } else {
+                        (to, from)
+                    };
+                    let moved = std::mem::take(&mut groups[merged]);
+                    for value in moved.iter() {
+                        group_of.insert(*value, kept);
+                    }
+                    groups[kept].extend(moved);
+                }
+                (Some(group), None) | (None, Some(group)) => {
+                    let value = if group_of.contains_key(&flow.from) {
+                        flow.to
+                    } else {
+                        flow.from
+                    };
+                    group_of.insert(value, group);
+                    groups[group].push(value);
+                }
+                (None, None) => {
+                    group_of.insert(flow.from, groups.len());
+                    group_of.insert(flow.to, groups.len());
+                    groups.push(if flow.from == flow.to {
+                        vec![flow.from]
+                    } else {
+                        vec![flow.from, flow.to]
+                    });
+                }
+            }
+        }
+        groups.retain(|group| !group.is_empty());
+        groups
+    }
+}
+
+/// The argument at `arg` of a block, which is always its binding at the same index.
+fn argument(block: index::Label, arg: usize) -> Value {
+    Value {
+        block,
+        binding: unsafe { index::Binding::from_index(arg as u16) },
+    }
+}
@@ -0,0 +1,92 @@
+//! Dead code elimination.
+//!
+//! An operation is dead when none of the bindings it defines has a usage and it has no effects
+//! besides its results (see `Op::is_reorderable`). Removing it takes away the usages of its
+//! operands, which might leave them dead as well, so the removal goes up through them.
+//!
+//! Calls are dead too when the called block can be removed (see `Effects::is_removable`): it
+//! doesn't write memory, trap or run inline assembly, and it always returns. Other calls are
+//! never removed, even if `CallReturnUsage::result_usage` shows that all of their results are
+//! ignored: the called block might do anything.
+
+use std::collections::HashSet;
+
+use super::{bucket, Analyses, Block, Effects, Op, IR};
+use crate::index;
+
+/// Removes the dead operations of every block. Returns whether anything changed.
+pub fn eliminate_dead_code(ir: &mut IR, analyses: &mut Analyses) -> bool {
+    // removing calls only takes effects away, so the ones from before are still safe to go by.
+    let effects = analyses.effects(ir);
+    let mut changed = false;
+    for block in ir.blocks.iter_mut() {
+        // calls without results aren't defining any binding, so operations are the candidates.
+        let operations = (0..block.operations.len() as u16).collect();
+        changed |= remove_dead_operations(block, operations, effects);
+    }
+    changed
+}
+
+/// Removes the operations of the `candidates` that are dead, and then the ones that only they
+/// used. Returns whether any operation was removed.
+pub(super) fn remove_dead(block: &mut Block, candidates: Vec<index::Binding>) -> bool {
+    let operations = candidates
+        .into_iter()
+        .filter_map(|binding| defining_operation(block, binding))
+        .collect();
+    remove_dead_operations(block, operations, &[])
+}
+
+/// Same as `remove_dead`, but with the operations themselves as the candidates. Calls are only
+/// removed if `effects` says the called block can be.
+fn remove_dead_operations(
+    block: &mut Block,
+    mut candidates: Vec<u16>,
+    effects: &[Effects],
+) -> bool {
+    let mut removed = HashSet::new();
+    while let Some(op_index) = candidates.pop() {
+        let op = &block.operations[op_index as usize];
+        let removable = match op {
+            Op::Call { label, .. } => effects
+                .get(unsafe { label.to_index() } as usize)
+                .is_some_and(Effects::is_removable),
+            _ => op.is_reorderable(),
+        };
+        if removed.contains(&op_index)
+            || !removable
+            || block.results_of(op_index as usize).any(|result| {
+                !block.binding_usages[unsafe { result.to_index() } as usize].is_empty()
+            })
+        {
+            continue;
+        }
+
+        removed.insert(op_index);
+        for operand in block.operations[op_index as usize].operands() {
+            drop_usage(block, operand, bucket::UsageIndex::Op(op_index));
+            candidates.extend(defining_operation(block, operand));
+        }
+    }
+    block.remove_operations(&removed);
+    !removed.is_empty()
+}
+
+/// The operation that defines the binding, unless it's an argument (which is part of the block's
+/// interface, so it stays).
+fn defining_operation(block: &Block, binding: index::Binding) -> Option<u16> {
+    match block.binding_defs[unsafe { binding.to_index() } as usize] {
+        bucket::Definition::Argument(_) => None,
+        bucket::Definition::Op(op_index) => Some(op_index),
+    }
+}
+
+/// Removes one usage of `binding` at `index`.
+pub(super) fn drop_usage(block: &mut Block, binding: index::Binding, index: bucket::UsageIndex) {
+    let usages = &mut block.binding_usages[unsafe { binding.to_index() } as usize];
+    let mut kept = std::mem::take(usages).into_vec();
+    if let Some(position) = kept.iter().position(|usage| usage.index == index) {
+        kept.remove(position);
+    }
+    *usages = kept.into_boxed_slice();
+}
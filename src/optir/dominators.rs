@@ -0,0 +1,312 @@
+//! Dominance.
+//!
+//! A block dominates another one when every way of getting to the second one goes through the
+//! first one, and post-dominates it when every way of leaving it goes through the first one.
+//! Blocks can be entered from more than one place (exports and calls), and left from more than
+//! one place (returns), so the trees hang from a virtual block that goes to all of them: the
+//! blocks right under it have no immediate dominator.
+//!
+//! The trees are computed with the iterative algorithm of Cooper, Harvey and Kennedy, from the
+//! branching maps.
+
+use super::{BranchingMaps, ForwardEdge, IR};
+use crate::index;
+
+pub struct DominatorTree {
+    /// The immediate dominator of each block. `None` for the blocks right under the virtual one,
+    /// and for the unreachable ones.
+    idoms: Box<[Option<index::Label>]>,
+    children: Box<[Vec<index::Label>]>,
+    /// The blocks right under the virtual one.
+    roots: Vec<index::Label>,
+    /// When each block is entered and left while walking the tree, for constant time dominance
+    /// checks. `None` for the unreachable blocks.
+    intervals: Box<[Option<(u32, u32)>]>,
+    /// Whether it's the tree of post-dominators, which goes against the branches.
+    reversed: bool,
+}
+
+impl DominatorTree {
+    fn compute(ir: &IR, maps: &BranchingMaps, reversed: bool) -> Self {
+        let count = ir.blocks.len();
+        let edges = |index: usize| -> Vec<usize> {
+            let label = unsafe { index::Label::from_index(index as u16) };
+            let labels = if reversed {
+                maps.backwards
+                    .get(&label)
+                    .map_or(Vec::new(), |parents| parents.to_vec())
+            } else {
+                maps.forwards[&label].targets()
+            };
+            labels
+                .into_iter()
+                .map(|label| unsafe { label.to_index() } as usize)
+                .collect()
+        };
+        let entries = if reversed {
+            ir.exits(maps)
+        } else {
+            ir.entries(maps)
+        }
+        .into_iter()
+        .map(|label| unsafe { label.to_index() } as usize)
+        .collect::<Vec<_>>();
+
+        // the virtual block is the last one.
+        let root = count;
+        let successors = |index: usize| {
+            if index == root {
+                entries.clone()
+            } else {
+                edges(index)
+            }
+        };
+        let mut predecessors = vec![Vec::new(); count + 1];
+        for index in 0..=count {
+            for successor in successors(index) {
+                predecessors[successor].push(index);
+            }
+        }
+
+        let postorder = postorder(root, count + 1, successors);
+        let mut postorder_number = vec![None; count + 1];
+        for (number, index) in postorder.iter().enumerate() {
+            postorder_number[*index] = Some(number);
+        }
+
+        let mut idoms = vec![None; count + 1];
+        idoms[root] = Some(root);
+        let intersect = |idoms: &[Option<usize>], mut lhs: usize, mut rhs: usize| {
+            while lhs != rhs {
+                while postorder_number[lhs] < postorder_number[rhs] {
+                    lhs = idoms[lhs].expect("processed blocks have a dominator");
+                }
+                while postorder_number[rhs] < postorder_number[lhs] {
+                    rhs = idoms[rhs].expect("processed blocks have a dominator");
+                }
+            }
+            lhs
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in postorder
+                .iter()
+                .rev()
+                .copied()
+                .filter(|index| *index != root)
+            {
+                let new_idom = predecessors[index]
+                    .iter()
+                    .copied()
+                    .filter(|predecessor| idoms[*predecessor].is_some())
+                    .reduce(|idom, predecessor| intersect(&idoms, idom, predecessor))
+                    .expect("reachable blocks are reached from somewhere");
+                if idoms[index] != Some(new_idom) {
+                    idoms[index] = Some(new_idom);
+                    changed = true;
+                }
+            }
+        }
+
+        let label = |index: usize| unsafe { index::Label::from_index(index as u16) };
+        let idoms = idoms[..count]
+            .iter()
+            .map(|idom| idom.filter(|idom| *idom != root).map(label))
+            .collect::<Box<[_]>>();
+        let mut children = vec![Vec::new(); count].into_boxed_slice();
+        let mut roots = Vec::new();
+        // children are kept in reverse postorder, so walking the tree goes along the branches.
+        for index in postorder
+            .iter()
+            .rev()
+            .copied()
+            .filter(|index| *index != root)
+        {
+            match idoms[index] {
+                Some(idom) => children[unsafe { idom.to_index() } as usize].push(label(index)),
+                None => roots.push(label(index)),
+            }
+        }
+
+        let mut tree = Self {
+            idoms,
+            children,
+            roots,
+            intervals: vec![None; count].into_boxed_slice(),
+            reversed,
+        };
+        let mut clock = 0;
+        let mut path = tree
+            .roots
+            .iter()
+            .rev()
+            .map(|root| (*root, false))
+            .collect::<Vec<_>>();
+        while let Some((label, visited)) = path.pop() {
+            let index = unsafe { label.to_index() } as usize;
+            if visited {
+                if let Some((_, exit)) = &mut tree.intervals[index] {
+                    *exit = clock;
+                }
+            } else {
+                tree.intervals[index] = Some((clock, 0));
+                path.push((label, true));
+                path.extend(
+                    tree.children[index]
+                        .iter()
+                        .rev()
+                        .map(|child| (*child, false)),
+                );
+            }
+            clock += 1;
+        }
+        tree
+    }
+
+    /// The closest block that strictly dominates `label`, if any.
+    pub fn idom(&self, label: index::Label) -> Option<index::Label> {
+        self.idoms[unsafe { label.to_index() } as usize]
+    }
+
+    /// The blocks that `label` immediately dominates.
+    pub fn children(&self, label: index::Label) -> &[index::Label] {
+        &self.children[unsafe { label.to_index() } as usize]
+    }
+
+    /// The blocks without an immediate dominator: where the blocks are entered from (or left
+    /// from, for post-dominators).
+    pub fn roots(&self) -> &[index::Label] {
+        &self.roots
+    }
+
+    /// Whether the block can be reached at all (or can leave at all, for post-dominators).
+    pub fn is_reachable(&self, label: index::Label) -> bool {
+        self.intervals[unsafe { label.to_index() } as usize].is_some()
+    }
+
+    /// Whether every path to `b` goes through `a`. Blocks dominate themselves.
+    pub fn dominates(&self, a: index::Label, b: index::Label) -> bool {
+        match (
+            self.intervals[unsafe { a.to_index() } as usize],
+            self.intervals[unsafe { b.to_index() } as usize],
+        ) {
+            (Some((a_entry, a_exit)), Some((b_entry, b_exit))) => {
+                a_entry <= b_entry && b_exit <= a_exit
+            }
+            _ => false,
+        }
+    }
+
+    pub fn strictly_dominates(&self, a: index::Label, b: index::Label) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    /// The reachable blocks, each one before the ones it dominates.
+    pub fn iter(&self) -> impl Iterator<Item = index::Label> + '_ {
+        let mut pending = self.roots.iter().rev().copied().collect::<Vec<_>>();
+        std::iter::from_fn(move || {
+            let label = pending.pop()?;
+            pending.extend(self.children(label).iter().rev().copied());
+            Some(label)
+        })
+    }
+}
+
+/// Visits the blocks depth first from `root`, returning them in postorder.
+fn postorder(root: usize, count: usize, successors: impl Fn(usize) -> Vec<usize>) -> Vec<usize> {
+    let mut visited = vec![false; count];
+    let mut postorder = Vec::with_capacity(count);
+    visited[root] = true;
+    let mut path = vec![(root, successors(root).into_iter())];
+    while let Some((index, remaining)) = path.last_mut() {
+        let index = *index;
+        match remaining.next() {
+            Some(next) if !visited[next] => {
+                visited[next] = true;
+                path.push((next, successors(next).into_iter()));
+            }
+            Some(_) => (),
+            None => {
+                postorder.push(index);
+                path.pop();
+            }
+        }
+    }
+    postorder
+}
+
+/// The blocks where the dominance of each block ends: the ones right after the blocks it
+/// dominates, that it doesn't strictly dominate. For post-dominators, the ones right before.
+pub struct DominanceFrontiers {
+    frontiers: Box<[Vec<index::Label>]>,
+}
+
+impl DominanceFrontiers {
+    pub fn compute(ir: &IR, maps: &BranchingMaps, tree: &DominatorTree) -> Self {
+        let mut frontiers = vec![Vec::new(); ir.blocks.len()].into_boxed_slice();
+        for index in 0..ir.blocks.len() {
+            let label = unsafe { index::Label::from_index(index as u16) };
+            if !tree.is_reachable(label) {
+                continue;
+            }
+            let predecessors = if tree.reversed {
+                maps.forwards[&label].targets()
+            } else {
+                maps.backwards
+                    .get(&label)
+                    .map_or(Vec::new(), |parents| parents.to_vec())
+            };
+            let idom = tree.idom(label);
+            for predecessor in predecessors {
+                let mut runner = Some(predecessor).filter(|runner| tree.is_reachable(*runner));
+                while let Some(current) = runner.filter(|current| Some(*current) != idom) {
+                    let frontier: &mut Vec<_> =
+                        &mut frontiers[unsafe { current.to_index() } as usize];
+                    if !frontier.contains(&label) {
+                        frontier.push(label);
+                    }
+                    runner = tree.idom(current);
+                }
+            }
+        }
+        Self { frontiers }
+    }
+
+    /// The frontier of a block.
+    pub fn of(&self, label: index::Label) -> &[index::Label] {
+        &self.frontiers[unsafe { label.to_index() } as usize]
+    }
+}
+
+impl IR {
+    pub fn dominators(&self, maps: &BranchingMaps) -> DominatorTree {
+        DominatorTree::compute(self, maps, false)
+    }
+
+    pub fn post_dominators(&self, maps: &BranchingMaps) -> DominatorTree {
+        DominatorTree::compute(self, maps, true)
+    }
+
+    /// Blocks that may be entered without a branch: the exported ones, the ones that are called,
+    /// and the ones that nothing branches to. They're in label order.
+    pub fn entries(&self, maps: &BranchingMaps) -> Vec<index::Label> {
+        let called = self.call_sites();
+        (0..self.blocks.len() as u16)
+            .map(|index| unsafe { index::Label::from_index(index) })
+            .filter(|label| {
+                self.blocks[unsafe { label.to_index() } as usize].exported
+                    || called.contains_key(label)
+                    || !maps.backwards.contains_key(label)
+            })
+            .collect()
+    }
+
+    /// Blocks that return, in label order.
+    pub fn exits(&self, maps: &BranchingMaps) -> Vec<index::Label> {
+        (0..self.blocks.len() as u16)
+            .map(|index| unsafe { index::Label::from_index(index) })
+            .filter(|label| matches!(maps.forwards[label], ForwardEdge::Dynamic))
+            .collect()
+    }
+}
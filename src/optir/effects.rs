@@ -0,0 +1,146 @@
+//! What running a block does, besides computing its results.
+//!
+//! A block does whatever its own operations do, and whatever the blocks it branches to or calls
+//! do, since control goes on through them before coming back to its caller. The summaries are
+//! solved over that graph one strongly connected component at a time, dependencies first, so
+//! every block of a component (e.g a loop, or blocks that call each other) shares its summary.
+
+use super::{strongly_connected_components, Block, Op};
+
+/// The effects of running a block, and of everything it ends up running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Effects {
+    /// It reads memory, so it may compute something else the next time it runs.
+    pub reads_memory: bool,
+    /// It writes memory, or orders the accesses around it (with a fence).
+    pub writes_memory: bool,
+    /// It may fault, e.g when accessing memory at a bad address.
+    pub may_trap: bool,
+    /// It runs code that the IR can't see into (inline assembly, which is also how system calls
+    /// are made), which may do anything.
+    pub opaque: bool,
+    /// Every path out of it returns: it doesn't loop or recurse, since that can't be told to ever
+    /// end.
+    pub always_returns: bool,
+}
+
+impl Effects {
+    /// A block that doesn't do anything besides computing its results, and returns.
+    pub const NONE: Self = Self {
+        reads_memory: false,
+        writes_memory: false,
+        may_trap: false,
+        opaque: false,
+        always_returns: true,
+    };
+
+    /// The effects of a single operation. Calls don't have any of their own, they have the ones
+    /// of the block they call.
+    pub const fn of(op: &Op) -> Self {
+        match op {
+            Op::AtomicLoad { .. } => Self {
+                reads_memory: true,
+                may_trap: true,
+                ..Self::NONE
+            },
+            Op::AtomicStore { .. } => Self {
+                writes_memory: true,
+                may_trap: true,
+                ..Self::NONE
+            },
+            Op::AtomicRmw { .. } | Op::AtomicCas { .. } => Self {
+                reads_memory: true,
+                writes_memory: true,
+                may_trap: true,
+                ..Self::NONE
+            },
+            Op::Fence(_) => Self {
+                writes_memory: true,
+                ..Self::NONE
+            },
+            Op::Asm { .. } => Self {
+                reads_memory: true,
+                writes_memory: true,
+                may_trap: true,
+                opaque: true,
+                ..Self::NONE
+            },
+            _ => Self::NONE,
+        }
+    }
+
+    /// Whether it always computes the same results out of the same arguments, and nothing else:
+    /// running it twice is the same as running it once, and not running it at all is the same
+    /// as running it and ignoring its results.
+    pub const fn is_pure(&self) -> bool {
+        !self.reads_memory
+            && !self.writes_memory
+            && !self.may_trap
+            && !self.opaque
+            && self.always_returns
+    }
+
+    /// Whether not running it can't be told apart from running it and ignoring its results.
+    pub const fn is_removable(&self) -> bool {
+        !self.writes_memory && !self.may_trap && !self.opaque && self.always_returns
+    }
+
+    /// The effects of running both.
+    pub const fn join(self, other: Self) -> Self {
+        Self {
+            reads_memory: self.reads_memory || other.reads_memory,
+            writes_memory: self.writes_memory || other.writes_memory,
+            may_trap: self.may_trap || other.may_trap,
+            opaque: self.opaque || other.opaque,
+            always_returns: self.always_returns && other.always_returns,
+        }
+    }
+}
+
+/// Computes the effects of every block.
+pub fn compute_effects(blocks: &[Block]) -> Box<[Effects]> {
+    let successors = blocks.iter().map(successors).collect::<Vec<_>>();
+    let mut effects = vec![Effects::NONE; blocks.len()];
+    let components = strongly_connected_components(blocks.len(), |index| successors[index].clone());
+    for component in components {
+        // blocks that can run again before returning can't be told to ever return.
+        let cycles = component.len() > 1 || successors[component[0]].contains(&component[0]);
+        let mut summary = Effects {
+            always_returns: !cycles,
+            ..Effects::NONE
+        };
+        for index in component.iter() {
+            summary = blocks[*index]
+                .operations
+                .iter()
+                .map(Effects::of)
+                .fold(summary, Effects::join);
+            // the components that come before have their effects already.
+            summary = successors[*index]
+                .iter()
+                .filter(|successor| !component.contains(successor))
+                .map(|successor| effects[*successor])
+                .fold(summary, Effects::join);
+        }
+        for index in component {
+            effects[index] = summary;
+        }
+    }
+    effects.into_boxed_slice()
+}
+
+/// The blocks that run after (or while) a block runs, before it returns.
+fn successors(block: &Block) -> Vec<usize> {
+    let called = block.operations.iter().filter_map(|op| match op {
+        Op::Call { label, .. } => Some(*label),
+        _ => None,
+    });
+    let branched = block.redirections().into_iter().map(|(target, _)| target);
+    let mut successors = called
+        .chain(branched)
+        .map(|label| unsafe { label.to_index() } as usize)
+        .collect::<Vec<_>>();
+    successors.sort_unstable();
+    successors.dedup();
+    successors
+}
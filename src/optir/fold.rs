@@ -0,0 +1,221 @@
+//! Constant folding and propagation.
+//!
+//! Operations whose operands are all known are replaced by the constant they compute, which in
+//! turn makes their result known for the operations that come after them. The flags that known
+//! arithmetic sets are tracked as well, so conditional branches (and switches) on known values
+//! become direct branches.
+//!
+//! Folding leaves the operands of the folded operations without a use, so the pure operations
+//! that were only kept alive by them are removed afterwards, like dead code.
+
+use std::collections::HashMap;
+
+use super::dce::{drop_usage, remove_dead};
+use super::{bucket, Block, CFTransfer, Constant, Op, IR};
+use crate::arch::Flags;
+use crate::hlir::{BitOperation, CarryOperation, Condition};
+use crate::index;
+
+/// Folds the constants of every block. Returns whether anything changed.
+pub fn fold_constants(ir: &mut IR) -> bool {
+    let mut changed = false;
+    for block in ir.blocks.iter_mut() {
+        let (ops_changed, end_changed) = fold_block(block);
+        changed |= ops_changed || end_changed;
+    }
+    changed
+}
+
+/// Folds a single block. Returns whether any operation was folded, and whether the way the
+/// block ends was folded.
+fn fold_block(block: &mut Block) -> (bool, bool) {
+    let results = block.first_results();
+    // values of the bindings that are known
+    let mut values = HashMap::new();
+    // flags set by the arithmetic whose operands are known
+    let mut flags = HashMap::new();
+    // outgoing carries of the carry arithmetic whose operands are known
+    let mut carries = HashMap::new();
+    // bindings that lost a usage, which might be dead now
+    let mut orphans = Vec::new();
+    let mut ops_changed = false;
+
+    for op_index in 0..block.operations.len() {
+        // everything that can be folded defines a binding.
+        let Some(result) = results[op_index] else {
+            continue;
+        };
+        let known = |binding: &index::Binding| values.get(binding).copied();
+        let folded = match &block.operations[op_index] {
+            Op::Constant(Constant::Numeric(value)) => {
+                values.insert(result, *value);
+                continue;
+            }
+            Op::Add { lhs, rhs } | Op::Sub { lhs, rhs } => {
+                let operation = match &block.operations[op_index] {
+                    Op::Add { .. } => CarryOperation::Add,
+                    _ => CarryOperation::Sub,
+                };
+                known(lhs).zip(known(rhs)).map(|(lhs, rhs)| {
+                    let (value, set_flags) = arithmetic(operation, lhs, rhs, false);
+                    flags.insert(result, set_flags);
+                    value
+                })
+            }
+            Op::CarryArithmetic {
+                operation,
+                lhs,
+                rhs,
+                carry_in,
+            } => {
+                let carry_in = match carry_in {
+                    Some(carry_in) => known(carry_in).map(|carry| carry != 0),
+                    None => Some(false),
+                };
+                match (known(lhs), known(rhs), carry_in) {
+                    (Some(lhs), Some(rhs), Some(carry_in)) => {
+                        let (value, set_flags) = arithmetic(*operation, lhs, rhs, carry_in);
+                        flags.insert(result, set_flags);
+                        carries.insert(result, set_flags.contains(Flags::CARRY));
+                        Some(value)
+                    }
+                    _ => None,
+                }
+            }
+            Op::CarryOut { instruction } => carries.get(instruction).map(|carry| *carry as u64),
+            Op::FetchFlags {
+                condition,
+                instruction,
+            } => flags
+                .get(instruction)
+                .map(|set_flags| holds(*condition, *set_flags) as u64),
+            Op::Bit { operation, value } => known(value).map(|value| bit(*operation, value)),
+            _ => None,
+        };
+
+        if let Some(value) = folded {
+            for operand in block.operations[op_index].operands() {
+                drop_usage(block, operand, bucket::UsageIndex::Op(op_index as u16));
+                orphans.push(operand);
+            }
+            block.operations[op_index] = Op::Constant(Constant::Numeric(value));
+            values.insert(result, value);
+            ops_changed = true;
+        }
+    }
+
+    let end_changed = fold_end(block, &values, &mut orphans);
+    remove_dead(block, orphans);
+    (ops_changed, end_changed)
+}
+
+/// Turns branches on known values into direct branches. Returns whether the end was folded.
+fn fold_end(
+    block: &mut Block,
+    values: &HashMap<index::Binding, u64>,
+    orphans: &mut Vec<index::Binding>,
+) -> bool {
+    let folded = match &block.end {
+        CFTransfer::ConditionalBranch {
+            flag_definition,
+            target_if_true,
+            target_if_false,
+            true_branch_binding_count,
+            ..
+        } => values.get(flag_definition).map(|value| {
+            let split = *true_branch_binding_count as usize;
+            if *value != 0 {
+                (*flag_definition, *target_if_true, 0..split)
+            } else {
+                (
+                    *flag_definition,
+                    *target_if_false,
+                    split..block.exported_bindings.len(),
+                )
+            }
+        }),
+        CFTransfer::Switch {
+            value,
+            cases,
+            default,
+        } => values.get(value).map(|known| {
+            let mut start = 0;
+            for case in cases.iter() {
+                let end = start + case.binding_count as usize;
+                if case.value == *known {
+                    return (*value, case.target, start..end);
+                }
+                start = end;
+            }
+            (*value, *default, start..block.exported_bindings.len())
+        }),
+        CFTransfer::Return | CFTransfer::DirectBranch { .. } => None,
+    };
+    let Some((selector, target, kept)) = folded else {
+        return false;
+    };
+
+    drop_usage(block, selector, bucket::UsageIndex::BlockEnd);
+    orphans.push(selector);
+    let exported = std::mem::take(&mut block.exported_bindings);
+    for (index, binding) in exported.iter().copied().enumerate() {
+        if !kept.contains(&index) {
+            drop_usage(block, binding, bucket::UsageIndex::BlockEnd);
+            orphans.push(binding);
+        }
+    }
+    block.exported_bindings = exported[kept].into();
+    block.end = CFTransfer::DirectBranch { target };
+    true
+}
+
+/// Computes an addition or subtraction with an incoming carry (or borrow), along with the flags
+/// that it sets. `Flags::CARRY` is the outgoing carry, or borrow for subtractions.
+fn arithmetic(operation: CarryOperation, lhs: u64, rhs: u64, carry_in: bool) -> (u64, Flags) {
+    let carry_in = carry_in as i128;
+    let (unsigned, signed) = match operation {
+        CarryOperation::Add => (
+            lhs as i128 + rhs as i128 + carry_in,
+            lhs as i64 as i128 + rhs as i64 as i128 + carry_in,
+        ),
+        CarryOperation::Sub => (
+            lhs as i128 - rhs as i128 - carry_in,
+            lhs as i64 as i128 - rhs as i64 as i128 - carry_in,
+        ),
+    };
+    let value = unsigned as u64;
+
+    let mut flags = Flags::empty();
+    flags.set(Flags::NEGATIVE, (value as i64) < 0);
+    flags.set(Flags::ZERO, value == 0);
+    flags.set(Flags::CARRY, unsigned != value as i128);
+    flags.set(Flags::OVERFLOW, signed != value as i64 as i128);
+    (value, flags)
+}
+
+/// Whether `condition` holds with the given flags. Comparisons are signed.
+fn holds(condition: Condition, flags: Flags) -> bool {
+    let zero = flags.contains(Flags::ZERO);
+    let overflow = flags.contains(Flags::OVERFLOW);
+    let less = flags.contains(Flags::NEGATIVE) != overflow;
+    match condition {
+        Condition::LessThan => less,
+        Condition::GreaterEqual => !less,
+        Condition::LessEqual => zero || less,
+        Condition::GreaterThan => !zero && !less,
+        Condition::Overflow => overflow,
+        Condition::NotOverflow => !overflow,
+        Condition::Zero => zero,
+        Condition::NotZero => !zero,
+    }
+}
+
+fn bit(operation: BitOperation, value: u64) -> u64 {
+    match operation {
+        BitOperation::PopCount => value.count_ones() as u64,
+        BitOperation::LeadingZeros => value.leading_zeros() as u64,
+        BitOperation::TrailingZeros => value.trailing_zeros() as u64,
+        BitOperation::ByteSwap => value.swap_bytes(),
+        BitOperation::BitReverse => value.reverse_bits(),
+    }
+}
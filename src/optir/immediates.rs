@@ -0,0 +1,88 @@
+//! Immediate operands.
+//!
+//! Constants that are added or subtracted don't need a register of their own when the target can
+//! encode them right into the instruction. Their usages are marked as immediate, which the
+//! allocator doesn't keep a register alive for, and the lowering encodes them in place. Constants
+//! that end up without any other usage aren't loaded at all.
+//!
+//! This runs once the passes are done, right before allocating registers, since passes that
+//! rebuild blocks forget how their usages were marked.
+
+use super::{bucket, Block, Constant, Op, IR};
+use crate::index;
+
+/// Marks the constant operands of additions and subtractions that `fits` as immediates. Returns
+/// whether any was marked.
+pub fn select_immediates(ir: &mut IR, fits: impl Fn(u64) -> bool) -> bool {
+    let mut changed = false;
+    for block in ir.blocks.iter_mut() {
+        for op_index in 0..block.operations.len() {
+            // additions are commutative, so either operand may be the immediate one.
+            let candidates = match block.operations[op_index] {
+                Op::Add { lhs, rhs } => vec![rhs, lhs],
+                Op::Sub { rhs, .. } => vec![rhs],
+                _ => continue,
+            };
+            let immediate = candidates.into_iter().find(|operand| {
+                matches!(
+                    constant_value(block, *operand),
+                    Some(Constant::Numeric(value)) if fits(value)
+                )
+            });
+            if let Some(operand) = immediate {
+                changed |= mark_immediate(block, operand, op_index as u16);
+            }
+        }
+    }
+    changed
+}
+
+/// The constant that a binding holds, if it's defined by one.
+fn constant_value(block: &Block, binding: index::Binding) -> Option<Constant> {
+    match block.binding_defs[unsafe { binding.to_index() } as usize] {
+        bucket::Definition::Op(op_index) => match block.operations[op_index as usize] {
+            Op::Constant(constant) => Some(constant),
+            _ => None,
+        },
+        bucket::Definition::Argument(_) => None,
+    }
+}
+
+/// Marks the usage of `binding` by the operation at `op_index` as immediate. Returns whether it
+/// wasn't already.
+fn mark_immediate(block: &mut Block, binding: index::Binding, op_index: u16) -> bool {
+    let usage = block.binding_usages[unsafe { binding.to_index() } as usize]
+        .iter_mut()
+        .find(|usage| usage.index == bucket::UsageIndex::Op(op_index));
+    match usage {
+        Some(usage) if !matches!(usage.usage_kind, bucket::UsageKind::Immediate) => {
+            usage.usage_kind = bucket::UsageKind::Immediate;
+            true
+        }
+        _ => false,
+    }
+}
+
+impl Block {
+    /// The constant that the operation at `op_index` takes as an immediate for `binding`, if it
+    /// does.
+    pub fn immediate_operand(&self, binding: index::Binding, op_index: u16) -> Option<Constant> {
+        self.binding_usages[unsafe { binding.to_index() } as usize]
+            .iter()
+            .any(|usage| {
+                usage.index == bucket::UsageIndex::Op(op_index)
+                    && matches!(usage.usage_kind, bucket::UsageKind::Immediate)
+            })
+            .then(|| constant_value(self, binding))
+            .flatten()
+    }
+
+    /// Whether a binding is only ever used as an immediate, so it doesn't need to be loaded.
+    pub fn is_only_immediate(&self, binding: index::Binding) -> bool {
+        let usages = &self.binding_usages[unsafe { binding.to_index() } as usize];
+        !usages.is_empty()
+            && usages
+                .iter()
+                .all(|usage| matches!(usage.usage_kind, bucket::UsageKind::Immediate))
+    }
+}
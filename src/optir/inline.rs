@@ -0,0 +1,306 @@
+//! Inlining of calls.
+//!
+//! A call is replaced by the operations of the called block, with the arguments of the callee
+//! renamed to the bindings passed by the caller, and the results of the call renamed to the
+//! bindings that the callee returns.
+//!
+//! When the callee branches to other blocks before returning, the rest of the caller is moved to
+//! a new *continuation* block. The blocks the callee may branch to are copied so that, instead of
+//! returning, they branch to the continuation with the returned values. The bindings of the
+//! caller that are still needed after the call are passed along with them, as extra arguments.
+
+use std::collections::{HashSet, VecDeque};
+
+use super::rewrite::{rewrite_end, Renames, Writer};
+use super::{bucket, Block, CFTransfer, Op, IR};
+use crate::hlir::InlineHint;
+use crate::index;
+
+/// Inlines the calls that are worth it in every block. Blocks without a hint are worth it when
+/// they have up to `threshold` operations, counting the ones of the blocks they branch to.
+/// Returns whether anything changed.
+pub fn inline_calls(ir: &mut IR, threshold: usize) -> bool {
+    let mut changed = false;
+    // each block is visited once, so recursive blocks don't expand forever. Continuations are
+    // visited too, since they hold the rest of the calls of the block they were split from.
+    let mut pending = (0..ir.blocks.len()).collect::<VecDeque<_>>();
+    while let Some(caller) = pending.pop_front() {
+        let Some(Inlined {
+            caller: block,
+            new_blocks,
+        }) = inline_into(ir, caller, threshold)
+        else {
+            continue;
+        };
+        changed = true;
+
+        ir.blocks[caller] = block;
+        if !new_blocks.is_empty() {
+            // the continuation is the last one.
+            pending.push_back(ir.blocks.len() + new_blocks.len() - 1);
+            ir.blocks.extend(new_blocks);
+        }
+    }
+    changed
+}
+
+struct Inlined {
+    caller: Block,
+    /// Copies of the blocks that the callee branches to, followed by the continuation.
+    new_blocks: Vec<Block>,
+}
+
+/// Blocks that can be reached through branches from `start`, without counting it unless it's
+/// reached again.
+pub(super) fn branch_targets(ir: &IR, start: usize) -> Vec<usize> {
+    let mut found = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![start];
+    while let Some(index) = pending.pop() {
+        for target in targets_of(&ir.blocks[index].end) {
+            let target = unsafe { target.to_index() } as usize;
+            if visited.insert(target) {
+                found.push(target);
+                pending.push(target);
+            }
+        }
+    }
+    found
+}
+
+fn targets_of(end: &CFTransfer) -> Vec<index::Label> {
+    match end {
+        CFTransfer::Return => Vec::new(),
+        CFTransfer::DirectBranch { target } => vec![*target],
+        CFTransfer::ConditionalBranch {
+            target_if_true,
+            target_if_false,
+            ..
+        } => vec![*target_if_true, *target_if_false],
+        CFTransfer::Switch { cases, default, .. } => cases
+            .iter()
+            .map(|case| case.target)
+            .chain(Some(*default))
+            .collect(),
+    }
+}
+
+fn should_inline(
+    ir: &IR,
+    caller: usize,
+    callee: usize,
+    arg_count: usize,
+    threshold: usize,
+) -> bool {
+    let callee_block = &ir.blocks[callee];
+    if callee == caller || callee_block.arg_count != arg_count {
+        return false;
+    }
+    let targets = branch_targets(ir, callee);
+    // branching back into the caller would need to copy the caller itself.
+    if targets.contains(&caller) {
+        return false;
+    }
+    match callee_block.inline {
+        Some(InlineHint::Always) => true,
+        Some(InlineHint::Never) => false,
+        None => {
+            let size = callee_block.operations.len()
+                + targets
+                    .iter()
+                    .map(|target| ir.blocks[*target].operations.len())
+                    .sum::<usize>();
+            size <= threshold
+        }
+    }
+}
+
+fn inline_into(ir: &IR, caller_index: usize, threshold: usize) -> Option<Inlined> {
+    let caller = &ir.blocks[caller_index];
+    let mut writer = Writer::new(caller.arg_count);
+    let mut renames = Writer::arguments(caller.arg_count);
+    let mut inlined_any = false;
+
+    for op_index in 0..caller.operations.len() {
+        let Op::Call {
+            label,
+            args,
+            usage_info_index,
+        } = &caller.operations[op_index]
+        else {
+            writer.copy_op(caller, op_index, &mut renames);
+            continue;
+        };
+        let callee_index = unsafe { label.to_index() } as usize;
+        if !should_inline(ir, caller_index, callee_index, args.len(), threshold) {
+            writer.copy_op(caller, op_index, &mut renames);
+            continue;
+        }
+
+        let callee = &ir.blocks[callee_index];
+        let mut callee_renames = args
+            .iter()
+            .enumerate()
+            .map(|(index, arg)| (Writer::argument(index), renames[arg]))
+            .collect::<Renames>();
+        for callee_op in 0..callee.operations.len() {
+            writer.copy_op(callee, callee_op, &mut callee_renames);
+        }
+        inlined_any = true;
+
+        let usage = &caller.call_return_usages[*usage_info_index];
+        if let CFTransfer::Return = callee.end {
+            for (result, returned) in usage
+                .result_binding_range
+                .clone()
+                .into_iter()
+                .zip(usage.result_usage.iter())
+            {
+                let returned = callee.exported_bindings[*returned as usize];
+                renames.insert(result, callee_renames[&returned]);
+            }
+            continue;
+        }
+
+        return Some(split_at_call(
+            ir,
+            caller_index,
+            op_index,
+            writer,
+            &renames,
+            &callee_renames,
+        ));
+    }
+
+    inlined_any.then(|| Inlined {
+        caller: writer
+            .finish_in_place_of(rewrite_end(caller, &renames, |label| label, &[]), caller),
+        new_blocks: Vec::new(),
+    })
+}
+
+/// Finishes inlining a callee that branches: the caller ends the way the callee does, the blocks
+/// the callee branches to are copied, and the rest of the caller goes to a continuation.
+fn split_at_call(
+    ir: &IR,
+    caller_index: usize,
+    call_index: usize,
+    writer: Writer,
+    renames: &Renames,
+    callee_renames: &Renames,
+) -> Inlined {
+    let caller = &ir.blocks[caller_index];
+    let Op::Call {
+        label,
+        usage_info_index,
+        ..
+    } = &caller.operations[call_index]
+    else {
+        unreachable!("inlined operations are calls")
+    };
+    let callee_index = unsafe { label.to_index() } as usize;
+    let usage = &caller.call_return_usages[*usage_info_index];
+
+    // bindings defined before the call that are used after it.
+    let live = (0..caller.binding_defs.len() as u16)
+        .filter(|binding| {
+            let defined_before = match caller.binding_defs[*binding as usize] {
+                bucket::Definition::Argument(_) => true,
+                bucket::Definition::Op(op_index) => (op_index as usize) < call_index,
+            };
+            defined_before
+                && caller.binding_usages[*binding as usize]
+                    .iter()
+                    .any(|usage| usage.index > call_index as u16)
+        })
+        .map(|binding| unsafe { index::Binding::from_index(binding) })
+        .collect::<Vec<_>>();
+
+    let copied = branch_targets(ir, callee_index);
+    let first_label = ir.blocks.len();
+    let copy_of = |label: index::Label| {
+        let position = copied
+            .iter()
+            .position(|index| *index == unsafe { label.to_index() } as usize)
+            .expect("the callee only branches to the copied blocks");
+        unsafe { index::Label::from_index((first_label + position) as u16) }
+    };
+    let continuation = unsafe { index::Label::from_index((first_label + copied.len()) as u16) };
+    let returns_for = caller.returns_for.or(Some(unsafe {
+        index::Label::from_index(caller_index as u16)
+    }));
+
+    let live_in_caller = live
+        .iter()
+        .map(|binding| renames[binding])
+        .collect::<Vec<_>>();
+    let callee = &ir.blocks[callee_index];
+    let caller_block = writer.finish_in_place_of(
+        rewrite_end(callee, callee_renames, copy_of, &live_in_caller),
+        caller,
+    );
+
+    let mut new_blocks = Vec::with_capacity(copied.len() + 1);
+    for source_index in copied.iter().copied() {
+        let source = &ir.blocks[source_index];
+        let mut writer = Writer::new(source.arg_count + live.len());
+        let mut renames = Writer::arguments(source.arg_count);
+        for op_index in 0..source.operations.len() {
+            writer.copy_op(source, op_index, &mut renames);
+        }
+        let live_arguments = (source.arg_count..source.arg_count + live.len())
+            .map(Writer::argument)
+            .collect::<Vec<_>>();
+        let end = match source.end {
+            // returns go to the continuation instead
+            CFTransfer::Return => (
+                CFTransfer::DirectBranch {
+                    target: continuation,
+                },
+                source
+                    .exported_bindings
+                    .iter()
+                    .map(|binding| renames[binding])
+                    .chain(live_arguments)
+                    .collect(),
+            ),
+            _ => rewrite_end(source, &renames, copy_of, &live_arguments),
+        };
+        new_blocks.push(writer.finish(end, None));
+    }
+
+    // the continuation gets all the returned values, then the live bindings. They're returned by
+    // the blocks the callee branches to, since it doesn't return on its own.
+    let return_count = copied
+        .iter()
+        .map(|index| &ir.blocks[*index])
+        .filter(|block| matches!(block.end, CFTransfer::Return))
+        .map(|block| block.exported_bindings.len())
+        .max()
+        .unwrap_or(0);
+    let mut writer = Writer::new(return_count + live.len());
+    let mut renames = usage
+        .result_binding_range
+        .clone()
+        .into_iter()
+        .zip(usage.result_usage.iter())
+        .map(|(result, returned)| (result, Writer::argument(*returned as usize)))
+        .chain(
+            live.iter()
+                .enumerate()
+                .map(|(index, binding)| (*binding, Writer::argument(return_count + index))),
+        )
+        .collect::<Renames>();
+    for op_index in call_index + 1..caller.operations.len() {
+        writer.copy_op(caller, op_index, &mut renames);
+    }
+    new_blocks.push(writer.finish(
+        rewrite_end(caller, &renames, |label| label, &[]),
+        returns_for,
+    ));
+
+    Inlined {
+        caller: caller_block,
+        new_blocks,
+    }
+}
@@ -0,0 +1,223 @@
+//! Interprocedural constant propagation into block arguments.
+//!
+//! When every call and every branch into a block passes the same constant for one of its
+//! arguments, the argument becomes that constant inside of the block, and it's no longer passed.
+//! Arguments that end up unused (e.g because the only thing the block did with them was passing
+//! them into an argument that became a constant) are no longer passed either.
+//! Exported blocks keep all of their arguments, since they might be called from outside.
+
+use std::collections::{HashMap, HashSet};
+
+use super::dce::{drop_usage, remove_dead};
+use super::rewrite::{rewrite_end, Renames, Writer};
+use super::{
+    bucket, Analyses, Block, CFTransfer, CallSite, Constant, DataFlowGraph, Op, Value, IR,
+};
+use crate::index;
+
+/// Turns the arguments that are always the same constant into constants, and drops the arguments
+/// that aren't used. Returns whether anything changed.
+pub fn propagate_constant_arguments(ir: &mut IR, analyses: &mut Analyses) -> bool {
+    let mut changed = false;
+    // a block that gets a constant might pass it along to another one, so this goes on until
+    // nothing changes. Call sites and flows move around as arguments are dropped, so they're
+    // collected again every time.
+    loop {
+        let data_flow = analyses.data_flow(ir);
+        let Some((label, constants, dropped)) = (0..ir.blocks.len()).find_map(|block_index| {
+            let label = unsafe { index::Label::from_index(block_index as u16) };
+            let constants = constant_arguments(ir, data_flow, label);
+            let dropped = constants
+                .keys()
+                .copied()
+                .chain(dead_arguments(&ir.blocks[block_index]))
+                .collect::<HashSet<_>>();
+            (!dropped.is_empty()).then_some((label, constants, dropped))
+        }) else {
+            break;
+        };
+
+        let call_sites = analyses
+            .call_sites(ir)
+            .get(&label)
+            .cloned()
+            .unwrap_or_default();
+        let parents = analyses
+            .branching_maps(ir)
+            .backwards
+            .get(&label)
+            .map_or(Vec::new(), |parents| parents.to_vec());
+        drop_from_calls(ir, &call_sites, &dropped);
+        drop_from_branches(ir, label, &parents, &dropped);
+        let block = &ir.blocks[unsafe { label.to_index() } as usize];
+        ir.blocks[unsafe { label.to_index() } as usize] =
+            without_arguments(block, &constants, &dropped);
+        analyses.invalidate();
+        changed = true;
+    }
+    changed
+}
+
+/// The arguments of a block that have no usages.
+fn dead_arguments(block: &Block) -> impl Iterator<Item = usize> + '_ {
+    (0..block.arg_count).filter(|arg| !block.exported && block.binding_usages[*arg].is_empty())
+}
+
+/// The arguments of a block that are passed the same constant everywhere it's entered from.
+fn constant_arguments(
+    ir: &IR,
+    data_flow: &DataFlowGraph,
+    label: index::Label,
+) -> HashMap<usize, Constant> {
+    let block = &ir.blocks[unsafe { label.to_index() } as usize];
+    if block.exported {
+        return HashMap::new();
+    }
+
+    (0..block.arg_count)
+        .filter_map(|arg| {
+            let argument = Value {
+                block: label,
+                binding: unsafe { index::Binding::from_index(arg as u16) },
+            };
+            let mut known = None;
+            for flow in data_flow.incoming(argument) {
+                // a block that passes its own argument back to itself doesn't change it.
+                if flow.from == argument {
+                    continue;
+                }
+                let from = &ir.blocks[unsafe { flow.from.block.to_index() } as usize];
+                let value = constant_of(from, flow.from.binding)?;
+                match known {
+                    Some(known) if known != value => return None,
+                    _ => known = Some(value),
+                }
+            }
+            // blocks that are never entered are left alone.
+            known.map(|value| (arg, value))
+        })
+        .collect()
+}
+
+pub(super) fn constant_of(block: &Block, binding: index::Binding) -> Option<Constant> {
+    match block.binding_defs[unsafe { binding.to_index() } as usize] {
+        bucket::Definition::Op(op_index) => match block.operations[op_index as usize] {
+            Op::Constant(constant) => Some(constant),
+            _ => None,
+        },
+        bucket::Definition::Argument(_) => None,
+    }
+}
+
+/// Stops passing the `dropped` arguments at the calls of the block.
+fn drop_from_calls(ir: &mut IR, call_sites: &[CallSite], dropped: &HashSet<usize>) {
+    let mut orphans: HashMap<_, Vec<_>> = HashMap::new();
+    for site in call_sites {
+        let caller = &mut ir.blocks[unsafe { site.caller.to_index() } as usize];
+        let Op::Call { args, .. } = &mut caller.operations[site.op_index as usize] else {
+            unreachable!("call sites point at calls")
+        };
+        let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(args)
+            .iter()
+            .copied()
+            .enumerate()
+            .partition(|(arg, _)| !dropped.contains(arg));
+        *args = kept.into_iter().map(|(_, binding)| binding).collect();
+        for (_, binding) in removed {
+            drop_usage(caller, binding, bucket::UsageIndex::Op(site.op_index));
+            orphans.entry(site.caller).or_default().push(binding);
+        }
+    }
+    // removing dead operations moves the rest of them, so it's done once all the calls of a
+    // block are rewritten.
+    for (caller, orphans) in orphans {
+        remove_dead(
+            &mut ir.blocks[unsafe { caller.to_index() } as usize],
+            orphans,
+        );
+    }
+}
+
+/// Stops passing the `dropped` arguments at the branches into the block, from its `parents`.
+fn drop_from_branches(
+    ir: &mut IR,
+    label: index::Label,
+    parents: &[index::Label],
+    dropped: &HashSet<usize>,
+) {
+    for parent in parents.iter().copied() {
+        let parent = &mut ir.blocks[unsafe { parent.to_index() } as usize];
+        let removed = parent
+            .redirections()
+            .into_iter()
+            .filter(|(target, _)| *target == label)
+            .flat_map(|(_, range)| dropped.iter().map(move |arg| range.start + arg))
+            .collect::<HashSet<_>>();
+
+        match &mut parent.end {
+            CFTransfer::Return | CFTransfer::DirectBranch { .. } => (),
+            CFTransfer::ConditionalBranch {
+                true_branch_binding_count,
+                ..
+            } => {
+                let split = *true_branch_binding_count as usize;
+                *true_branch_binding_count -=
+                    removed.iter().filter(|index| **index < split).count() as u8;
+            }
+            CFTransfer::Switch { cases, .. } => {
+                let mut start = 0;
+                for case in cases.iter_mut() {
+                    let range = start..start + case.binding_count as usize;
+                    start = range.end;
+                    case.binding_count -=
+                        removed.iter().filter(|index| range.contains(index)).count() as u8;
+                }
+            }
+        }
+
+        let exported = std::mem::take(&mut parent.exported_bindings);
+        let mut orphans = Vec::new();
+        for (index, binding) in exported.iter().copied().enumerate() {
+            if removed.contains(&index) {
+                drop_usage(parent, binding, bucket::UsageIndex::BlockEnd);
+                orphans.push(binding);
+            }
+        }
+        parent.exported_bindings = exported
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(index, _)| !removed.contains(index))
+            .map(|(_, binding)| binding)
+            .collect();
+        remove_dead(parent, orphans);
+    }
+}
+
+/// Rebuilds a block without the `dropped` arguments, defining the ones in `constants` as
+/// constants instead. The rest of the dropped arguments must be unused.
+fn without_arguments(
+    block: &Block,
+    constants: &HashMap<usize, Constant>,
+    dropped: &HashSet<usize>,
+) -> Block {
+    let mut writer = Writer::new(block.arg_count - dropped.len());
+    let mut renames = Renames::new();
+    let mut kept = 0;
+    for arg in 0..block.arg_count {
+        let binding = Writer::argument(arg);
+        let renamed = match constants.get(&arg) {
+            Some(constant) => writer.define(Op::Constant(*constant)),
+            None if dropped.contains(&arg) => continue,
+            None => {
+                kept += 1;
+                Writer::argument(kept - 1)
+            }
+        };
+        renames.insert(binding, renamed);
+    }
+    for op_index in 0..block.operations.len() {
+        writer.copy_op(block, op_index, &mut renames);
+    }
+    writer.finish_in_place_of(rewrite_end(block, &renames, |label| label, &[]), block)
+}
@@ -0,0 +1,195 @@
+//! Join points.
+//!
+//! A block that is branched into from several places gets its arguments from different bindings
+//! depending on where it's entered from, which is what phi nodes are for in other IRs. Those
+//! bindings are put in one selection bucket along with the argument they become: only one of
+//! them exists at a time, so the allocator can give all of them the same register, and the
+//! branches don't have to move anything around.
+//!
+//! This runs once the passes are done, right before allocating registers, since passes that
+//! rebuild blocks forget how their usages were marked.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{bucket, Analyses, Block, BranchingMaps, Flow, FlowKind, PhiSelector, Value, IR};
+use crate::index;
+
+/// Puts the bindings that are passed into the arguments of join points in selection buckets,
+/// along with the arguments. Returns whether any bucket was made.
+pub fn select_join_buckets(ir: &mut IR, analyses: &mut Analyses) -> bool {
+    let maps = analyses.branching_maps(ir);
+    let selectors = (0..ir.blocks.len() as u16)
+        .map(|index| ir.phi_selectors(maps, unsafe { index::Label::from_index(index) }))
+        .collect::<Vec<_>>();
+    let data_flow = analyses.data_flow(ir);
+    // arguments that can only be passed by branching, since other blocks and the outside world
+    // don't know about buckets.
+    let branched_only = |value: Value| {
+        let block = &ir.blocks[unsafe { value.block.to_index() } as usize];
+        !block.exported
+            && (unsafe { value.binding.to_index() } as usize) < block.arg_count
+            && data_flow.incoming(value).next().is_some()
+            && data_flow
+                .incoming(value)
+                .all(|flow| flow.kind == FlowKind::Branch)
+    };
+    // the usages at the end of a block are all alike, so a binding can only be put in a bucket if
+    // everywhere it's passed into can be in it too. Passing it into two arguments of the same
+    // block would put both of them in the bucket, although they exist at the same time.
+    let joins_bucket = |value: Value| {
+        let mut arguments = HashMap::new();
+        let mut outgoing = data_flow
+            .outgoing(value)
+            .filter(|flow| flow.kind == FlowKind::Branch)
+            .peekable();
+        outgoing.peek().is_some()
+            && outgoing.all(|flow| {
+                branched_only(flow.to)
+                    && *arguments.entry(flow.to.block).or_insert(flow.to.binding) == flow.to.binding
+            })
+    };
+
+    let joined_arguments = (0..ir.blocks.len())
+        .flat_map(|block_index| {
+            let label = unsafe { index::Label::from_index(block_index as u16) };
+            joined_arguments(ir.blocks[block_index].arg_count, &selectors[block_index])
+                .into_iter()
+                .map(move |binding| Value {
+                    block: label,
+                    binding,
+                })
+        })
+        .filter(|value| branched_only(*value))
+        .collect::<HashSet<_>>();
+
+    let buckets = data_flow
+        .groups(|flow: &Flow| flow.kind == FlowKind::Branch && joins_bucket(flow.from))
+        .into_iter()
+        .filter(|group| group.iter().any(|value| joined_arguments.contains(value)))
+        .filter(|group| !overlaps(ir, group))
+        .take(u16::MAX as usize)
+        .collect::<Vec<_>>();
+
+    let passed = buckets
+        .iter()
+        .enumerate()
+        .flat_map(|(selection_bucket, group)| {
+            group
+                .iter()
+                .copied()
+                .filter(|value| joins_bucket(*value))
+                .map(move |value| (selection_bucket, value))
+        })
+        .collect::<Vec<_>>();
+    for (selection_bucket, value) in passed {
+        let block = &mut ir.blocks[unsafe { value.block.to_index() } as usize];
+        for usage in block.binding_usages[unsafe { value.binding.to_index() } as usize]
+            .iter_mut()
+            .filter(|usage| usage.index == bucket::UsageIndex::BlockEnd)
+        {
+            usage.usage_kind = bucket::UsageKind::Selective {
+                selection_bucket: selection_bucket as u16,
+            };
+        }
+    }
+
+    let changed = !buckets.is_empty();
+    ir.selection_buckets = buckets;
+    changed
+}
+
+/// The arguments that aren't passed the same binding from everywhere the block is entered from.
+fn joined_arguments(arg_count: usize, selectors: &[PhiSelector]) -> Vec<index::Binding> {
+    (0..arg_count)
+        .filter(|arg| {
+            selectors
+                .iter()
+                .filter_map(|selector| {
+                    Some((selector.block_from, *selector.used_bindings.get(*arg)?))
+                })
+                .collect::<HashSet<_>>()
+                .len()
+                > 1
+        })
+        .map(|arg| unsafe { index::Binding::from_index(arg as u16) })
+        .collect()
+}
+
+/// Whether any two values of the group from the same block exist at the same time, so they
+/// can't share a place.
+fn overlaps(ir: &IR, group: &[Value]) -> bool {
+    let mut by_block: HashMap<_, Vec<_>> = HashMap::new();
+    for value in group {
+        by_block.entry(value.block).or_default().push(value.binding);
+    }
+    by_block.into_iter().any(|(label, bindings)| {
+        let block = &ir.blocks[unsafe { label.to_index() } as usize];
+        let lifetimes = bindings
+            .iter()
+            .map(|binding| lifetime(block, *binding))
+            .collect::<Vec<_>>();
+        let is_argument =
+            |binding: &index::Binding| (unsafe { binding.to_index() } as usize) < block.arg_count;
+        lifetimes.iter().enumerate().any(|(index, (start, end))| {
+            // arguments all exist when the block is entered, however soon they're last used.
+            lifetimes[index + 1..]
+                .iter()
+                .zip(&bindings[index + 1..])
+                .any(|((other_start, other_end), other)| {
+                    (start < other_end && other_start < end)
+                        || is_argument(&bindings[index]) && is_argument(other)
+                })
+        })
+    })
+}
+
+/// From the operation that defines the binding (arguments are defined on entry) to the last one
+/// that needs it in a place, where the end of the block comes after every operation.
+fn lifetime(block: &Block, binding: index::Binding) -> (u16, u16) {
+    let binding_index = unsafe { binding.to_index() } as usize;
+    let start = match block.binding_defs[binding_index] {
+        bucket::Definition::Argument(_) => 0,
+        bucket::Definition::Op(op_index) => op_index,
+    };
+    let end = block.binding_usages[binding_index]
+        .iter()
+        .filter(|usage| !matches!(usage.usage_kind, bucket::UsageKind::Immediate))
+        .map(|usage| {
+            usage
+                .index
+                .as_index()
+                .unwrap_or(block.operations.len() as u16)
+        })
+        .max()
+        .unwrap_or(start + 1);
+    (start, end)
+}
+
+impl IR {
+    /// What each of the branches into the block passes as its arguments. A block that branches
+    /// into it more than once (e.g from both sides of a conditional branch) has a selector for
+    /// each time.
+    pub fn phi_selectors(&self, maps: &BranchingMaps, label: index::Label) -> Vec<PhiSelector> {
+        let mut selectors = Vec::new();
+        let mut seen = HashSet::new();
+        for parent in maps
+            .backwards
+            .get(&label)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|parent| seen.insert(*parent))
+        {
+            let block = &self.blocks[unsafe { parent.to_index() } as usize];
+            for (target, range) in block.redirections() {
+                if target == label {
+                    selectors.push(PhiSelector {
+                        used_bindings: block.exported_bindings[range].to_vec(),
+                        block_from: parent,
+                    });
+                }
+            }
+        }
+        selectors
+    }
+}
@@ -0,0 +1,69 @@
+//! Block layout.
+//!
+//! A branch into the block that is placed right after it doesn't need a jump, since execution
+//! falls through into it. Blocks are placed in chains: each block is followed by the successor it
+//! most likely branches to, as long as that one wasn't placed yet, and a chain starts again from
+//! the first block (in label order) that wasn't placed when it can't go on.
+//!
+//! The successor that stays in the deepest loop is the likely one, since loops usually run more
+//! than once. Then, the one that is only entered from this block, which can't fall through from
+//! anywhere else. The codegen inverts the condition of a branch whenever its true target is the
+//! one that falls through.
+
+use super::{Analyses, BranchingMaps, Loop, IR};
+use crate::index;
+
+/// Orders the blocks so that branches fall through into their likely successors, and keeps the
+/// order in `IR::layout`. Returns whether it's any different from label order.
+pub fn lay_out_blocks(ir: &mut IR, analyses: &mut Analyses) -> bool {
+    let loops = analyses.loops(ir).to_vec();
+    let maps = analyses.branching_maps(ir);
+    let mut placed = vec![false; ir.blocks.len()];
+    let mut layout = Vec::with_capacity(ir.blocks.len());
+    for start in 0..ir.blocks.len() {
+        let mut next = Some(unsafe { index::Label::from_index(start as u16) });
+        while let Some(label) = next.filter(|label| !placed[unsafe { label.to_index() } as usize]) {
+            placed[unsafe { label.to_index() } as usize] = true;
+            layout.push(label);
+            next = likely_successor(ir, maps, &loops, label, &placed);
+        }
+    }
+
+    let changed = layout
+        .iter()
+        .enumerate()
+        .any(|(position, label)| unsafe { label.to_index() } as usize != position);
+    ir.layout = layout;
+    changed
+}
+
+/// The successor of the block that would be best placed after it, out of the ones that weren't
+/// placed yet. Ties go to the last target (the false one of conditional branches, and the default
+/// of switches), like when the blocks are in label order.
+fn likely_successor(
+    ir: &IR,
+    maps: &BranchingMaps,
+    loops: &[Loop],
+    label: index::Label,
+    placed: &[bool],
+) -> Option<index::Label> {
+    let block = &ir.blocks[unsafe { label.to_index() } as usize];
+    block
+        .redirections()
+        .into_iter()
+        .map(|(target, _)| target)
+        .enumerate()
+        .filter(|(_, target)| !placed[unsafe { target.to_index() } as usize])
+        .max_by_key(|(position, target)| {
+            let loop_depth = loops
+                .iter()
+                .filter(|lp| lp.body.contains(&label) && lp.body.contains(target))
+                .count();
+            let entered_only_from_here = maps
+                .backwards
+                .get(target)
+                .is_some_and(|parents| parents[..] == [label]);
+            (loop_depth, entered_only_from_here, *position)
+        })
+        .map(|(_, target)| target)
+}
@@ -0,0 +1,424 @@
+//! Loop-invariant code motion.
+//!
+//! Pure operations of a loop whose operands are the same on every iteration are computed once,
+//! before entering it. Bindings only live inside of their block, so the header of the loop keeps
+//! its label and becomes the *preheader*: it computes the hoisted values, then branches into a
+//! copy of the header that takes them as extra arguments. Every other block of the loop takes
+//! them as extra arguments too, and passes them along whenever it branches inside of the loop.
+
+use std::collections::{HashMap, HashSet};
+
+use super::rewrite::{rewrite_end_with, Writer};
+use super::{Analyses, Block, BranchingMaps, CFTransfer, Constant, Loop, Op, IR};
+use crate::hlir::BitOperation;
+use crate::index;
+
+/// A value that stays the same while the loop runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Invariant {
+    /// An argument of the header that every iteration passes back as it got it.
+    Argument(usize),
+    Constant(Constant),
+    /// The result of a hoisted operation.
+    Hoisted(usize),
+}
+
+/// A hoisted operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Expression {
+    Add(Invariant, Invariant),
+    Sub(Invariant, Invariant),
+    Bit(BitOperation, Invariant),
+}
+
+impl Expression {
+    fn of(op: &Op, values: &HashMap<index::Binding, Invariant>) -> Option<Self> {
+        let value = |binding| values.get(binding).copied();
+        Some(match op {
+            Op::Add { lhs, rhs } => Self::Add(value(lhs)?, value(rhs)?),
+            Op::Sub { lhs, rhs } => Self::Sub(value(lhs)?, value(rhs)?),
+            Op::Bit {
+                operation,
+                value: operand,
+            } => Self::Bit(*operation, value(operand)?),
+            _ => return None,
+        })
+    }
+}
+
+struct Invariants {
+    /// The bindings of each block of the loop that stay the same while it runs.
+    values: HashMap<index::Label, HashMap<index::Binding, Invariant>>,
+    /// The hoisted operations, each one after the ones whose results it uses.
+    expressions: Vec<Expression>,
+}
+
+/// Hoists the invariant operations out of every loop. Returns whether anything changed.
+pub fn hoist_loop_invariants(ir: &mut IR, analyses: &mut Analyses) -> bool {
+    let mut changed = false;
+    // hoisting changes the blocks, so loops are found again after each one. The operations
+    // hoisted out of a loop may be hoisted again out of the one around it.
+    loop {
+        let loops = analyses.loops(ir).to_vec();
+        let maps = analyses.branching_maps(ir);
+        let Some((found, invariants, passed)) = loops.into_iter().find_map(|found| {
+            let invariants = find_invariants(ir, maps, &found)?;
+            let passed = passed_values(ir, &found, &invariants);
+            (!passed.is_empty()).then_some((found, invariants, passed))
+        }) else {
+            break;
+        };
+        hoist(ir, &found, &invariants, &passed);
+        analyses.invalidate();
+        changed = true;
+    }
+    changed
+}
+
+fn find_invariants(ir: &IR, maps: &BranchingMaps, found: &Loop) -> Option<Invariants> {
+    let order = reverse_postorder(maps, found);
+    // blocks that branch into the loop without being reachable from anywhere are left alone.
+    if order.len() != found.body.len() {
+        return None;
+    }
+    let position = order
+        .iter()
+        .enumerate()
+        .map(|(position, label)| (*label, position))
+        .collect::<HashMap<_, _>>();
+    let back_edges = back_edges(maps, &position);
+    // the arguments that back edges pass are assumed to be invariant, until one that passes
+    // something else is found.
+    let mut assumed = back_edges
+        .iter()
+        .flat_map(|(_, target)| {
+            let arg_count = ir.blocks[unsafe { target.to_index() } as usize].arg_count;
+            (0..arg_count).map(|arg| (*target, arg))
+        })
+        .collect::<HashSet<_>>();
+    loop {
+        let invariants = evaluate(ir, maps, found, &order, &position, &assumed);
+        let mut settled = true;
+        for (from, target) in back_edges.iter().copied() {
+            let block = &ir.blocks[unsafe { from.to_index() } as usize];
+            let passed_values = &invariants.values[&from];
+            let target_values = &invariants.values[&target];
+            for (_, range) in block
+                .redirections()
+                .into_iter()
+                .filter(|(label, _)| *label == target)
+            {
+                for (arg, binding) in block.exported_bindings[range].iter().enumerate() {
+                    let expected = target_values.get(&Writer::argument(arg));
+                    if expected.is_some() && passed_values.get(binding) != expected {
+                        assumed.remove(&(target, arg));
+                        settled = false;
+                    }
+                }
+            }
+        }
+        if settled {
+            return (!invariants.expressions.is_empty()).then_some(invariants);
+        }
+    }
+}
+
+/// The blocks of the loop, each one after the blocks that branch into it (except through back
+/// edges).
+fn reverse_postorder(maps: &BranchingMaps, found: &Loop) -> Vec<index::Label> {
+    let targets_of = |label| maps.forwards[&label].targets();
+    let mut postorder = Vec::with_capacity(found.body.len());
+    let mut visited = HashSet::from([found.header]);
+    let mut path = vec![(found.header, targets_of(found.header))];
+    while let Some((label, targets)) = path.last_mut() {
+        match targets.pop() {
+            Some(target) if found.body.contains(&target) && visited.insert(target) => {
+                path.push((target, targets_of(target)))
+            }
+            Some(_) => (),
+            None => {
+                postorder.push(*label);
+                path.pop();
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+/// The branches inside of the loop that go back to a block that comes before in `position`
+/// (or to the same one), as `(from, target)`. Those into the header are the ones that close
+/// the loop, the rest close nested ones.
+fn back_edges(
+    maps: &BranchingMaps,
+    position: &HashMap<index::Label, usize>,
+) -> Vec<(index::Label, index::Label)> {
+    let mut back_edges = Vec::new();
+    for (from, from_position) in position.iter() {
+        for target in maps.forwards[from].targets() {
+            if position
+                .get(&target)
+                .is_some_and(|target_position| target_position <= from_position)
+                && !back_edges.contains(&(*from, target))
+            {
+                back_edges.push((*from, target));
+            }
+        }
+    }
+    back_edges
+}
+
+/// Finds the invariant bindings of the loop, given the arguments that are assumed to be passed
+/// back as they were.
+fn evaluate(
+    ir: &IR,
+    maps: &BranchingMaps,
+    found: &Loop,
+    order: &[index::Label],
+    position: &HashMap<index::Label, usize>,
+    assumed: &HashSet<(index::Label, usize)>,
+) -> Invariants {
+    let mut values = HashMap::new();
+    let mut expressions = Vec::new();
+    let mut numbered = HashMap::new();
+    for label in order.iter().copied() {
+        let block = &ir.blocks[unsafe { label.to_index() } as usize];
+        let mut block_values = HashMap::new();
+        for arg in 0..block.arg_count {
+            let value = if label == found.header {
+                assumed
+                    .contains(&(label, arg))
+                    .then_some(Invariant::Argument(arg))
+            } else {
+                incoming_value(ir, maps, &values, position, assumed, label, arg)
+            };
+            if let Some(value) = value {
+                block_values.insert(Writer::argument(arg), value);
+            }
+        }
+
+        // the flags of an instruction are read right after it, so it can't be moved.
+        let flag_sources = block
+            .operations
+            .iter()
+            .filter_map(|op| match op {
+                Op::FetchFlags { instruction, .. } | Op::CarryOut { instruction } => {
+                    Some(*instruction)
+                }
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        let results = block.first_results();
+        for (op_index, op) in block.operations.iter().enumerate() {
+            let Some(result) = results[op_index] else {
+                continue;
+            };
+            if let Op::Constant(constant) = op {
+                block_values.insert(result, Invariant::Constant(*constant));
+                continue;
+            }
+            if flag_sources.contains(&result) {
+                continue;
+            }
+            let Some(expression) = Expression::of(op, &block_values) else {
+                continue;
+            };
+            let next = expressions.len();
+            let number = *numbered.entry(expression).or_insert(next);
+            if number == next {
+                expressions.push(expression);
+            }
+            block_values.insert(result, Invariant::Hoisted(number));
+        }
+        values.insert(label, block_values);
+    }
+    Invariants {
+        values,
+        expressions,
+    }
+}
+
+/// The value of an argument of a block of the loop (other than the header), if every branch into
+/// it that comes before passes the same invariant one. Back edges must pass it back too, so
+/// the argument has to be assumed to be invariant when there are any.
+fn incoming_value(
+    ir: &IR,
+    maps: &BranchingMaps,
+    values: &HashMap<index::Label, HashMap<index::Binding, Invariant>>,
+    position: &HashMap<index::Label, usize>,
+    assumed: &HashSet<(index::Label, usize)>,
+    label: index::Label,
+    arg: usize,
+) -> Option<Invariant> {
+    let arg_count = ir.blocks[unsafe { label.to_index() } as usize].arg_count;
+    let mut known = None;
+    for parent in maps.backwards.get(&label).into_iter().flatten() {
+        if position[parent] >= position[&label] {
+            if !assumed.contains(&(label, arg)) {
+                return None;
+            }
+            continue;
+        }
+        let parent_values = &values[parent];
+        let parent_block = &ir.blocks[unsafe { parent.to_index() } as usize];
+        for (_, range) in parent_block
+            .redirections()
+            .into_iter()
+            .filter(|(target, _)| *target == label)
+        {
+            if range.len() != arg_count {
+                return None;
+            }
+            let value = parent_values.get(&parent_block.exported_bindings[range.start + arg])?;
+            match known {
+                Some(known) if known != *value => return None,
+                _ => known = Some(*value),
+            }
+        }
+    }
+    known
+}
+
+/// The hoisted values that are still used inside of the loop, so they have to be passed around
+/// it. They're sorted.
+fn passed_values(ir: &IR, found: &Loop, invariants: &Invariants) -> Vec<usize> {
+    let mut passed = HashSet::new();
+    for label in found.body.iter() {
+        let block = &ir.blocks[unsafe { label.to_index() } as usize];
+        let values = &invariants.values[label];
+        let hoisted = |binding: &index::Binding| match values.get(binding) {
+            Some(Invariant::Hoisted(number)) => Some(*number),
+            _ => None,
+        };
+        let results = block.first_results();
+        for (op_index, op) in block.operations.iter().enumerate() {
+            if results[op_index].is_some_and(|result| hoisted(&result).is_some()) {
+                continue;
+            }
+            passed.extend(op.operands().iter().filter_map(hoisted));
+        }
+        passed.extend(block.exported_bindings.iter().filter_map(hoisted));
+        if let CFTransfer::Switch { value, .. } = &block.end {
+            passed.extend(hoisted(value));
+        }
+    }
+    let mut passed = passed.into_iter().collect::<Vec<_>>();
+    passed.sort_unstable();
+    passed
+}
+
+fn hoist(ir: &mut IR, found: &Loop, invariants: &Invariants, passed: &[usize]) {
+    let header_index = unsafe { found.header.to_index() } as usize;
+    let loop_header = unsafe { index::Label::from_index(ir.blocks.len() as u16) };
+
+    let mut header_copy = None;
+    for label in found.body.iter().copied() {
+        let index = unsafe { label.to_index() } as usize;
+        let block = &ir.blocks[index];
+        let (writer, end) =
+            without_invariants(block, &invariants.values[&label], passed, |target| {
+                if target == found.header {
+                    Some(loop_header)
+                } else {
+                    found.body.contains(&target).then_some(target)
+                }
+            });
+        if label == found.header {
+            let returns_for = block.returns_for.or(Some(found.header));
+            header_copy = Some(writer.finish(end, returns_for));
+        } else {
+            ir.blocks[index] = writer.finish_in_place_of(end, block);
+        }
+    }
+
+    let header = &ir.blocks[header_index];
+    ir.blocks[header_index] = preheader(header, &invariants.expressions, passed, loop_header);
+    ir.blocks
+        .push(header_copy.expect("the header is part of the loop"));
+}
+
+/// Rewrites a block of the loop, taking the `passed` values as extra arguments instead of
+/// computing them. `inside` gives where the targets of the block that are in the loop went, and
+/// `None` for the rest.
+fn without_invariants(
+    block: &Block,
+    values: &HashMap<index::Binding, Invariant>,
+    passed: &[usize],
+    inside: impl Fn(index::Label) -> Option<index::Label>,
+) -> (Writer, (CFTransfer, super::FixedArray<index::Binding>)) {
+    let mut writer = Writer::new(block.arg_count + passed.len());
+    let mut renames = Writer::arguments(block.arg_count);
+    let extra = (block.arg_count..block.arg_count + passed.len())
+        .map(Writer::argument)
+        .collect::<Vec<_>>();
+    let results = block.first_results();
+    for op_index in 0..block.operations.len() {
+        let hoisted = results[op_index].and_then(|result| match values.get(&result) {
+            Some(Invariant::Hoisted(number)) => Some((result, *number)),
+            _ => None,
+        });
+        match hoisted {
+            Some((result, number)) => {
+                // values that aren't passed aren't used either.
+                if let Ok(position) = passed.binary_search(&number) {
+                    renames.insert(result, extra[position]);
+                }
+            }
+            None => writer.copy_op(block, op_index, &mut renames),
+        }
+    }
+    let end = rewrite_end_with(block, &renames, |target| match inside(target) {
+        Some(target) => (target, &extra[..]),
+        None => (target, &[]),
+    });
+    (writer, end)
+}
+
+/// Makes the block that computes the hoisted values, in place of the header.
+fn preheader(
+    header: &Block,
+    expressions: &[Expression],
+    passed: &[usize],
+    loop_header: index::Label,
+) -> Block {
+    let mut writer = Writer::new(header.arg_count);
+    let mut constants = HashMap::new();
+    let mut results: Vec<index::Binding> = Vec::with_capacity(expressions.len());
+    for expression in expressions.iter().copied() {
+        let mut operand = |invariant| match invariant {
+            Invariant::Argument(arg) => Writer::argument(arg),
+            Invariant::Constant(constant) => *constants
+                .entry(constant)
+                .or_insert_with(|| writer.define(Op::Constant(constant))),
+            Invariant::Hoisted(number) => results[number],
+        };
+        let op = match expression {
+            Expression::Add(lhs, rhs) => Op::Add {
+                lhs: operand(lhs),
+                rhs: operand(rhs),
+            },
+            Expression::Sub(lhs, rhs) => Op::Sub {
+                lhs: operand(lhs),
+                rhs: operand(rhs),
+            },
+            Expression::Bit(operation, value) => Op::Bit {
+                operation,
+                value: operand(value),
+            },
+        };
+        results.push(writer.define(op));
+    }
+    let exported = (0..header.arg_count)
+        .map(Writer::argument)
+        .chain(passed.iter().map(|number| results[*number]))
+        .collect();
+    writer.finish_in_place_of(
+        (
+            CFTransfer::DirectBranch {
+                target: loop_header,
+            },
+            exported,
+        ),
+        header,
+    )
+}
@@ -0,0 +1,69 @@
+//! Natural loops.
+//!
+//! Loops are found from their back edges: branches into a block that dominates the one branching.
+//! The body of a loop is its header, along with every block that reaches a latch without going
+//! through the header.
+
+use std::collections::HashSet;
+
+use super::{BranchingMaps, DominatorTree, IR};
+use crate::index;
+
+#[derive(Debug, Clone)]
+pub struct Loop {
+    /// The only block of the loop that is entered from outside of it.
+    pub header: index::Label,
+    /// The blocks of the loop that branch back into the header.
+    pub latches: Vec<index::Label>,
+    /// Every block of the loop, header and latches included.
+    pub body: HashSet<index::Label>,
+}
+
+impl IR {
+    /// The natural loops, one per header, from the dominator tree of the IR. Nested loops are
+    /// reported on their own, after the loops around them, whose bodies include theirs.
+    pub fn loops(&self, maps: &BranchingMaps, dominators: &DominatorTree) -> Vec<Loop> {
+        dominators
+            .iter()
+            .filter_map(|header| {
+                let latches = maps
+                    .backwards
+                    .get(&header)
+                    .into_iter()
+                    .flatten()
+                    .copied()
+                    .filter(|parent| dominators.dominates(header, *parent))
+                    .collect::<Vec<_>>();
+                (!latches.is_empty()).then(|| Loop {
+                    header,
+                    body: loop_body(maps, dominators, header, &latches),
+                    latches,
+                })
+            })
+            .collect()
+    }
+}
+
+fn loop_body(
+    maps: &BranchingMaps,
+    dominators: &DominatorTree,
+    header: index::Label,
+    latches: &[index::Label],
+) -> HashSet<index::Label> {
+    let mut body = HashSet::from([header]);
+    let mut pending = latches.to_vec();
+    while let Some(label) = pending.pop() {
+        if body.insert(label) {
+            // blocks that can't be reached branch wherever they like.
+            pending.extend(
+                maps.backwards
+                    .get(&label)
+                    .into_iter()
+                    .flatten()
+                    .copied()
+                    .filter(|parent| dominators.is_reachable(*parent)),
+            );
+        }
+    }
+    body
+}
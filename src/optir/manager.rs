@@ -0,0 +1,385 @@
+//! The pass manager.
+//!
+//! Passes run in a pipeline that depends on the optimization level, over and over until none of
+//! them changes anything (or until it's clear that they won't settle down). Analyses are computed
+//! when a pass asks for them, and kept until a pass reports that it changed the IR.
+//!
+//! Some of them, like the branching maps and the blocks that each block returns from, are needed
+//! by the backend too, which takes them from the manager once the pipeline is done (see
+//! `PassManager::analyses`).
+
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use super::{
+    bucket, compute_effects, eliminate_common_subexpressions, eliminate_dead_code, fold_constants,
+    hoist_loop_invariants, inline_calls, propagate_constant_arguments, simplify_cfg,
+    specialize_calls, BranchingMaps, CallSite, DataFlowGraph, DominatorTree, Effects, FixedArray,
+    Loop, IR,
+};
+use crate::index;
+
+/// A transformation over the whole IR.
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    /// Runs the pass. Returns whether the IR changed.
+    fn run(&self, ir: &mut IR, analyses: &mut Analyses) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    /// No optimizations at all.
+    O0,
+    /// Only the passes that work within a block.
+    O1,
+    /// Everything, inlining blocks as long as they're small.
+    O2,
+    /// Everything that doesn't make the code grow, only inlining blocks that are about as small
+    /// as calling them.
+    Os,
+}
+
+/// Blocks with up to this many operations are inlined at `-O2`.
+const SPEED_INLINE_THRESHOLD: usize = 16;
+/// Blocks with up to this many operations are inlined at `-Os`, which costs about the same as
+/// setting up the arguments and calling them.
+const SIZE_INLINE_THRESHOLD: usize = 2;
+/// Blocks are specialized for constant arguments at `-O2` until this many operations are copied.
+const SPECIALIZATION_BUDGET: usize = 64;
+
+/// Passes that settle down do so in a handful of rounds; the rest are cut short.
+const MAX_ROUNDS: usize = 8;
+
+pub struct PassManager {
+    /// The passes, and whether they run in every round rather than only in the first one.
+    passes: Vec<(Box<dyn Pass>, bool)>,
+    analyses: Analyses,
+}
+
+impl PassManager {
+    /// Makes the pipeline of an optimization level.
+    pub fn new(level: OptLevel) -> Self {
+        let mut manager = Self {
+            passes: Vec::new(),
+            analyses: Analyses::default(),
+        };
+        let inline_threshold = match level {
+            OptLevel::O0 => return manager,
+            OptLevel::O1 => None,
+            OptLevel::O2 => Some(SPEED_INLINE_THRESHOLD),
+            OptLevel::Os => Some(SIZE_INLINE_THRESHOLD),
+        };
+        // inlining again would expand recursive blocks on every round.
+        if let Some(threshold) = inline_threshold {
+            manager.add_once(Inline { threshold });
+        }
+        manager.add(FoldConstants);
+        if level == OptLevel::O2 {
+            manager.add(Specialize {
+                budget: Cell::new(SPECIALIZATION_BUDGET),
+            });
+        }
+        if inline_threshold.is_some() {
+            manager.add(PropagateConstantArguments);
+            manager.add(SimplifyCfg);
+        }
+        manager.add(EliminateCommonSubexpressions);
+        // hoisting adds a block in front of each loop.
+        if level == OptLevel::O2 {
+            manager.add(HoistLoopInvariants);
+        }
+        manager.add(EliminateDeadCode);
+        manager
+    }
+
+    /// Adds a pass at the end of the pipeline.
+    pub fn add(&mut self, pass: impl Pass + 'static) {
+        self.passes.push((Box::new(pass), true));
+    }
+
+    /// Adds a pass at the end of the pipeline that only runs in the first round.
+    pub fn add_once(&mut self, pass: impl Pass + 'static) {
+        self.passes.push((Box::new(pass), false));
+    }
+
+    /// The analyses of the IR as the last pass left it, for what runs after the pipeline.
+    pub fn analyses(&mut self) -> &mut Analyses {
+        &mut self.analyses
+    }
+
+    /// Runs the pipeline until nothing changes. Returns whether anything changed.
+    pub fn run(&mut self, ir: &mut IR) -> bool {
+        let mut changed = false;
+        for round in 0..MAX_ROUNDS {
+            let mut round_changed = false;
+            for (pass, _) in self
+                .passes
+                .iter()
+                .filter(|(_, repeats)| round == 0 || *repeats)
+            {
+                if pass.run(ir, &mut self.analyses) {
+                    self.analyses.invalidate();
+                    round_changed = true;
+                    if cfg!(debug_assertions) {
+                        if let Err(errors) = super::verify(ir) {
+                            let errors = errors
+                                .iter()
+                                .map(|error| error.to_string())
+                                .collect::<Vec<_>>();
+                            panic!(
+                                "the IR is broken after {}:\n{}",
+                                pass.name(),
+                                errors.join("\n")
+                            );
+                        }
+                    }
+                }
+            }
+            changed |= round_changed;
+            if !round_changed {
+                break;
+            }
+        }
+        changed
+    }
+}
+
+/// Analyses of the IR, computed the first time they're needed. Passes that keep changing the IR
+/// while they run invalidate them on their own.
+#[derive(Default)]
+pub struct Analyses {
+    branching_maps: Option<BranchingMaps>,
+    return_blocks: Option<FixedArray<Vec<index::Label>>>,
+    return_counts: Option<FixedArray<u8>>,
+    effects: Option<FixedArray<Effects>>,
+    liveness: Option<Liveness>,
+    call_sites: Option<HashMap<index::Label, Vec<CallSite>>>,
+    data_flow: Option<DataFlowGraph>,
+    dominators: Option<DominatorTree>,
+    loops: Option<Vec<Loop>>,
+}
+
+impl Analyses {
+    /// See `IR::branching_maps`.
+    pub fn branching_maps(&mut self, ir: &IR) -> &BranchingMaps {
+        self.branching_maps
+            .get_or_insert_with(|| ir.branching_maps())
+    }
+
+    /// See `IR::return_blocks`. They're found from the branching maps, so those are kept as well.
+    pub fn return_blocks(&mut self, ir: &IR) -> &[Vec<index::Label>] {
+        if self.return_blocks.is_none() {
+            let return_blocks = ir.return_blocks(self.branching_maps(ir));
+            self.return_blocks = Some(return_blocks);
+        }
+        self.return_blocks
+            .as_deref()
+            .expect("the return blocks were just found")
+    }
+
+    /// See `IR::return_counts`.
+    pub fn return_counts(&mut self, ir: &IR) -> &[u8] {
+        if self.return_counts.is_none() {
+            let return_counts = ir.return_counts(self.return_blocks(ir));
+            self.return_counts = Some(return_counts);
+        }
+        self.return_counts
+            .as_deref()
+            .expect("the return counts were just computed")
+    }
+
+    /// The effects of running each block.
+    pub fn effects(&mut self, ir: &IR) -> &[Effects] {
+        self.effects
+            .get_or_insert_with(|| compute_effects(&ir.blocks))
+    }
+
+    pub fn liveness(&mut self, ir: &IR) -> &Liveness {
+        self.liveness.get_or_insert_with(|| Liveness::compute(ir))
+    }
+
+    /// See `IR::call_sites`.
+    pub fn call_sites(&mut self, ir: &IR) -> &HashMap<index::Label, Vec<CallSite>> {
+        self.call_sites.get_or_insert_with(|| ir.call_sites())
+    }
+
+    /// The data flow graph. It follows returns through the return blocks, so those are kept as
+    /// well.
+    pub fn data_flow(&mut self, ir: &IR) -> &DataFlowGraph {
+        if self.data_flow.is_none() {
+            let data_flow = DataFlowGraph::compute(ir, self.return_blocks(ir));
+            self.data_flow = Some(data_flow);
+        }
+        self.data_flow
+            .as_ref()
+            .expect("the data flow graph was just built")
+    }
+
+    pub fn dominators(&mut self, ir: &IR) -> &DominatorTree {
+        if self.dominators.is_none() {
+            let dominators = ir.dominators(self.branching_maps(ir));
+            self.dominators = Some(dominators);
+        }
+        self.dominators
+            .as_ref()
+            .expect("the dominators were just computed")
+    }
+
+    /// See `IR::loops`. They're found from the dominator tree, so it's kept as well.
+    pub fn loops(&mut self, ir: &IR) -> &[Loop] {
+        if self.loops.is_none() {
+            self.dominators(ir);
+            let (Some(maps), Some(dominators)) = (&self.branching_maps, &self.dominators) else {
+                unreachable!("the dominators were just computed from the branching maps")
+            };
+            self.loops = Some(ir.loops(maps, dominators));
+        }
+        self.loops.as_deref().expect("the loops were just found")
+    }
+
+    /// Forgets every analysis, after the IR changed.
+    pub fn invalidate(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Where each binding of each block is used for the last time.
+pub struct Liveness {
+    /// Per block and binding, `None` if the binding is never used.
+    pub last_usages: FixedArray<FixedArray<Option<bucket::UsageIndex>>>,
+}
+
+impl Liveness {
+    pub fn compute(ir: &IR) -> Self {
+        Self {
+            last_usages: ir
+                .blocks
+                .iter()
+                .map(|block| {
+                    block
+                        .binding_usages
+                        .iter()
+                        .map(|usages| usages.iter().map(|usage| usage.index).max())
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether the binding is still needed after the operation at `op_index` of the block.
+    pub fn is_live_after(
+        &self,
+        block: index::Label,
+        binding: index::Binding,
+        op_index: u16,
+    ) -> bool {
+        let last_usage = self.last_usages[unsafe { block.to_index() } as usize]
+            [unsafe { binding.to_index() } as usize];
+        last_usage.is_some_and(|last_usage| last_usage > op_index)
+    }
+}
+
+pub struct Inline {
+    /// Blocks without hints are inlined if they have up to this many operations.
+    pub threshold: usize,
+}
+
+impl Pass for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run(&self, ir: &mut IR, _: &mut Analyses) -> bool {
+        inline_calls(ir, self.threshold)
+    }
+}
+
+pub struct FoldConstants;
+
+impl Pass for FoldConstants {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(&self, ir: &mut IR, _: &mut Analyses) -> bool {
+        fold_constants(ir)
+    }
+}
+
+pub struct PropagateConstantArguments;
+
+impl Pass for PropagateConstantArguments {
+    fn name(&self) -> &'static str {
+        "ipcp"
+    }
+
+    fn run(&self, ir: &mut IR, analyses: &mut Analyses) -> bool {
+        propagate_constant_arguments(ir, analyses)
+    }
+}
+
+pub struct Specialize {
+    /// How many operations may still be copied, over all the rounds.
+    pub budget: Cell<usize>,
+}
+
+impl Pass for Specialize {
+    fn name(&self) -> &'static str {
+        "specialize"
+    }
+
+    fn run(&self, ir: &mut IR, analyses: &mut Analyses) -> bool {
+        let mut budget = self.budget.get();
+        let changed = specialize_calls(ir, &mut budget, analyses);
+        self.budget.set(budget);
+        changed
+    }
+}
+
+pub struct SimplifyCfg;
+
+impl Pass for SimplifyCfg {
+    fn name(&self) -> &'static str {
+        "simplifycfg"
+    }
+
+    fn run(&self, ir: &mut IR, _: &mut Analyses) -> bool {
+        simplify_cfg(ir)
+    }
+}
+
+pub struct EliminateCommonSubexpressions;
+
+impl Pass for EliminateCommonSubexpressions {
+    fn name(&self) -> &'static str {
+        "cse"
+    }
+
+    fn run(&self, ir: &mut IR, _: &mut Analyses) -> bool {
+        eliminate_common_subexpressions(ir)
+    }
+}
+
+pub struct HoistLoopInvariants;
+
+impl Pass for HoistLoopInvariants {
+    fn name(&self) -> &'static str {
+        "licm"
+    }
+
+    fn run(&self, ir: &mut IR, analyses: &mut Analyses) -> bool {
+        hoist_loop_invariants(ir, analyses)
+    }
+}
+
+pub struct EliminateDeadCode;
+
+impl Pass for EliminateDeadCode {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run(&self, ir: &mut IR, analyses: &mut Analyses) -> bool {
+        eliminate_dead_code(ir, analyses)
+    }
+}
@@ -0,0 +1,237 @@
+//! Rebuilding of blocks.
+//!
+//! Passes that change the shape of a block (e.g its arguments, or where its operations come from)
+//! write its operations again into a new one, renaming the bindings on the way. The usages of the
+//! new block are computed from scratch once it's finished.
+
+use std::collections::HashMap;
+
+use super::{bucket, BindingRange, Block, CFTransfer, CallReturnUsage, FixedArray, Op};
+use crate::index;
+
+/// Rewrites the operations of blocks into a new one.
+pub(super) struct Writer {
+    arg_count: usize,
+    binding_defs: Vec<bucket::Definition>,
+    operations: Vec<Op>,
+    call_return_usages: Vec<CallReturnUsage>,
+}
+
+pub(super) type Renames = HashMap<index::Binding, index::Binding>;
+
+impl Writer {
+    pub(super) fn new(arg_count: usize) -> Self {
+        Self {
+            arg_count,
+            binding_defs: (0..arg_count as u16)
+                .map(bucket::Definition::Argument)
+                .collect(),
+            operations: Vec::new(),
+            call_return_usages: Vec::new(),
+        }
+    }
+
+    pub(super) fn argument(index: usize) -> index::Binding {
+        // SAFE: arguments are the first bindings of a block
+        unsafe { index::Binding::from_index(index as u16) }
+    }
+
+    /// Renames that keep the first `count` arguments of a block in place.
+    pub(super) fn arguments(count: usize) -> Renames {
+        (0..count)
+            .map(|index| (Self::argument(index), Self::argument(index)))
+            .collect()
+    }
+
+    /// Copies an operation of `block`, renaming its operands, and registering the renames of the
+    /// bindings it defines.
+    pub(super) fn copy_op(&mut self, block: &Block, op_index: usize, renames: &mut Renames) {
+        let mut op = block.operations[op_index].clone();
+        for operand in op.operands_mut() {
+            *operand = renames[operand];
+        }
+
+        let definition = bucket::Definition::Op(self.operations.len() as u16);
+        let first_result = self.binding_defs.len() as u16;
+        for result in block.results_of(op_index) {
+            // SAFE: the operation is pushed right after
+            let renamed = unsafe { index::Binding::from_index(self.binding_defs.len() as u16) };
+            self.binding_defs.push(definition);
+            renames.insert(result, renamed);
+        }
+
+        if let Op::Call {
+            usage_info_index, ..
+        } = &mut op
+        {
+            let usage = &block.call_return_usages[*usage_info_index];
+            *usage_info_index = self.call_return_usages.len();
+            self.call_return_usages.push(CallReturnUsage {
+                result_usage: usage.result_usage.clone(),
+                result_binding_range: BindingRange(first_result..self.binding_defs.len() as u16),
+                called_label: usage.called_label,
+            });
+        }
+        self.operations.push(op);
+    }
+
+    /// Adds a new operation that defines a single binding.
+    pub(super) fn define(&mut self, op: Op) -> index::Binding {
+        // SAFE: the operation is pushed right after
+        let binding = unsafe { index::Binding::from_index(self.binding_defs.len() as u16) };
+        self.binding_defs
+            .push(bucket::Definition::Op(self.operations.len() as u16));
+        self.operations.push(op);
+        binding
+    }
+
+    /// Makes a block that replaces `block`, keeping how it is called.
+    pub(super) fn finish_in_place_of(
+        self,
+        end: (CFTransfer, FixedArray<index::Binding>),
+        block: &Block,
+    ) -> Block {
+        Block {
+            inline: block.inline,
+            exported: block.exported,
+            ..self.finish(end, block.returns_for)
+        }
+    }
+
+    pub(super) fn finish(
+        self,
+        (end, exported_bindings): (CFTransfer, FixedArray<index::Binding>),
+        returns_for: Option<index::Label>,
+    ) -> Block {
+        let mut binding_usages = vec![Vec::new(); self.binding_defs.len()];
+        let mut use_at = |binding: index::Binding, index| {
+            binding_usages[unsafe { binding.to_index() } as usize].push(bucket::Usage {
+                usage_kind: bucket::UsageKind::Exclusive,
+                index,
+            })
+        };
+        for (op_index, op) in self.operations.iter().enumerate() {
+            for operand in op.operands() {
+                use_at(operand, bucket::UsageIndex::Op(op_index as u16));
+            }
+        }
+        for binding in exported_bindings.iter().copied() {
+            use_at(binding, bucket::UsageIndex::BlockEnd);
+        }
+        match &end {
+            CFTransfer::Return | CFTransfer::DirectBranch { .. } => (),
+            CFTransfer::ConditionalBranch {
+                flag_definition, ..
+            } => use_at(*flag_definition, bucket::UsageIndex::BlockEnd),
+            CFTransfer::Switch { value, .. } => use_at(*value, bucket::UsageIndex::BlockEnd),
+        }
+
+        Block {
+            arg_count: self.arg_count,
+            binding_defs: self.binding_defs.into_boxed_slice(),
+            binding_usages: binding_usages
+                .into_iter()
+                .map(Vec::into_boxed_slice)
+                .collect(),
+            call_return_usages: self.call_return_usages.into_boxed_slice(),
+            operations: self.operations.into_boxed_slice(),
+            exported_bindings,
+            end,
+            inline: None,
+            exported: false,
+            returns_for,
+        }
+    }
+}
+
+/// Renames the bindings of how a block ends, and redirects its branches with `retarget`. The
+/// `extra` bindings are passed to every target, after their own.
+pub(super) fn rewrite_end(
+    block: &Block,
+    renames: &Renames,
+    retarget: impl Fn(index::Label) -> index::Label,
+    extra: &[index::Binding],
+) -> (CFTransfer, FixedArray<index::Binding>) {
+    rewrite_end_with(block, renames, |label| (retarget(label), extra))
+}
+
+/// Same as `rewrite_end`, but `redirect` picks the extra bindings passed to each target.
+pub(super) fn rewrite_end_with<'a>(
+    block: &Block,
+    renames: &Renames,
+    redirect: impl Fn(index::Label) -> (index::Label, &'a [index::Binding]),
+) -> (CFTransfer, FixedArray<index::Binding>) {
+    let exported = block
+        .exported_bindings
+        .iter()
+        .map(|binding| renames[binding])
+        .collect::<Vec<_>>();
+    match &block.end {
+        CFTransfer::Return => (CFTransfer::Return, exported.into_boxed_slice()),
+        CFTransfer::DirectBranch { target } => {
+            let (target, extra) = redirect(*target);
+            (
+                CFTransfer::DirectBranch { target },
+                [&exported[..], extra].concat().into_boxed_slice(),
+            )
+        }
+        CFTransfer::ConditionalBranch {
+            stored_condition,
+            flag_definition,
+            target_if_true,
+            target_if_false,
+            true_branch_binding_count,
+        } => {
+            let (if_true, if_false) = exported.split_at(*true_branch_binding_count as usize);
+            let (target_if_true, extra_if_true) = redirect(*target_if_true);
+            let (target_if_false, extra_if_false) = redirect(*target_if_false);
+            (
+                CFTransfer::ConditionalBranch {
+                    stored_condition: *stored_condition,
+                    flag_definition: renames[flag_definition],
+                    target_if_true,
+                    target_if_false,
+                    true_branch_binding_count: *true_branch_binding_count
+                        + extra_if_true.len() as u8,
+                },
+                [if_true, extra_if_true, if_false, extra_if_false]
+                    .concat()
+                    .into_boxed_slice(),
+            )
+        }
+        CFTransfer::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            let mut rest = &exported[..];
+            let mut passed = Vec::new();
+            let cases = cases
+                .iter()
+                .map(|case| {
+                    let (bindings, next) = rest.split_at(case.binding_count as usize);
+                    rest = next;
+                    let (target, extra) = redirect(case.target);
+                    passed.extend_from_slice(bindings);
+                    passed.extend_from_slice(extra);
+                    super::SwitchCase {
+                        value: case.value,
+                        target,
+                        binding_count: case.binding_count + extra.len() as u8,
+                    }
+                })
+                .collect();
+            let (default, extra) = redirect(*default);
+            passed.extend_from_slice(rest);
+            passed.extend_from_slice(extra);
+            (
+                CFTransfer::Switch {
+                    value: renames[value],
+                    cases,
+                    default,
+                },
+                passed.into_boxed_slice(),
+            )
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Control flow simplification.
+//!
+//! Conditional branches that go to the same place with the same bindings either way become direct
+//! branches. Blocks that can't be reached from the exported ones are dropped, and blocks that are
+//! only ever entered by a direct branch from a single block are merged into it. The blocks that
+//! are left are renumbered in order, so exported blocks keep their labels.
+//!
+//! A block is reached by branching to it, calling it, taking its label as a constant, or returning
+//! on its behalf (see `Block::returns_for`).
+
+use std::collections::HashSet;
+
+use super::{
+    bucket,
+    dce::{drop_usage, remove_dead},
+    rewrite::{rewrite_end, Renames, Writer},
+    Block, CFTransfer, IR,
+};
+use crate::index;
+
+/// Simplifies how the blocks branch into each other. Returns whether anything changed.
+pub fn simplify_cfg(ir: &mut IR) -> bool {
+    let mut changed = false;
+    for block in ir.blocks.iter_mut() {
+        changed |= fold_identical_branch(block);
+    }
+
+    let reachable = reachable_blocks(ir);
+    let mut removed = (0..ir.blocks.len())
+        .filter(|index| !reachable[*index])
+        .collect::<HashSet<_>>();
+
+    let mut references = vec![0usize; ir.blocks.len()];
+    for (_, block) in ir
+        .blocks
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| reachable[*index])
+    {
+        for label in block.referenced_labels() {
+            references[unsafe { label.to_index() } as usize] += 1;
+        }
+    }
+    for index in 0..ir.blocks.len() {
+        if removed.contains(&index) {
+            continue;
+        }
+        // merging may leave the block branching directly into another one that can be merged.
+        while let Some(next) = mergeable_successor(ir, index, &references) {
+            ir.blocks[index] = merge(&ir.blocks[index], &ir.blocks[next]);
+            removed.insert(next);
+        }
+    }
+
+    changed |= !removed.is_empty();
+    ir.remove_blocks(&removed);
+    changed
+}
+
+/// Turns a conditional branch whose targets get the same bindings into a direct branch. Returns
+/// whether it did.
+fn fold_identical_branch(block: &mut Block) -> bool {
+    let CFTransfer::ConditionalBranch {
+        flag_definition,
+        target_if_true,
+        target_if_false,
+        true_branch_binding_count,
+        ..
+    } = block.end
+    else {
+        return false;
+    };
+    let (if_true, if_false) = block
+        .exported_bindings
+        .split_at(true_branch_binding_count as usize);
+    if target_if_true != target_if_false || if_true != if_false {
+        return false;
+    }
+
+    let mut if_true = std::mem::take(&mut block.exported_bindings).into_vec();
+    let if_false = if_true.split_off(true_branch_binding_count as usize);
+    for binding in if_false {
+        drop_usage(block, binding, bucket::UsageIndex::BlockEnd);
+    }
+    drop_usage(block, flag_definition, bucket::UsageIndex::BlockEnd);
+    block.end = CFTransfer::DirectBranch {
+        target: target_if_true,
+    };
+    block.exported_bindings = if_true.into_boxed_slice();
+    remove_dead(block, vec![flag_definition]);
+    true
+}
+
+/// Which blocks can be reached from the exported ones.
+fn reachable_blocks(ir: &IR) -> Vec<bool> {
+    let mut reachable = ir
+        .blocks
+        .iter()
+        .map(|block| block.exported)
+        .collect::<Vec<_>>();
+    let mut pending = (0..ir.blocks.len())
+        .filter(|index| reachable[*index])
+        .collect::<Vec<_>>();
+    while let Some(index) = pending.pop() {
+        for label in ir.blocks[index].referenced_labels() {
+            let label = unsafe { label.to_index() } as usize;
+            if !reachable[label] {
+                reachable[label] = true;
+                pending.push(label);
+            }
+        }
+    }
+    reachable
+}
+
+/// The block that the block at `index` branches directly into, if nothing else enters it.
+fn mergeable_successor(ir: &IR, index: usize, references: &[usize]) -> Option<usize> {
+    let block = &ir.blocks[index];
+    let CFTransfer::DirectBranch { target } = block.end else {
+        return None;
+    };
+    let next = unsafe { target.to_index() } as usize;
+    let successor = &ir.blocks[next];
+    // blocks split from another one return like it does, so they can only be merged into a block
+    // that returns the same way.
+    let returns_alike = match successor.returns_for {
+        None => true,
+        Some(label) => {
+            block
+                .returns_for
+                .unwrap_or(unsafe { index::Label::from_index(index as u16) })
+                == label
+        }
+    };
+    (next != index && !successor.exported && references[next] == 1 && returns_alike).then_some(next)
+}
+
+/// Makes a block that does what `block` does and then what `successor` does.
+fn merge(block: &Block, successor: &Block) -> Block {
+    let mut writer = Writer::new(block.arg_count);
+    let mut renames = Writer::arguments(block.arg_count);
+    for op_index in 0..block.operations.len() {
+        writer.copy_op(block, op_index, &mut renames);
+    }
+
+    let mut successor_renames = block
+        .exported_bindings
+        .iter()
+        .enumerate()
+        .map(|(index, binding)| (Writer::argument(index), renames[binding]))
+        .collect::<Renames>();
+    for op_index in 0..successor.operations.len() {
+        writer.copy_op(successor, op_index, &mut successor_renames);
+    }
+    writer.finish_in_place_of(
+        rewrite_end(successor, &successor_renames, |label| label, &[]),
+        block,
+    )
+}
@@ -0,0 +1,188 @@
+//! Specialization of blocks for constant arguments.
+//!
+//! A call that passes constants to a block can call a copy of it instead, where those arguments
+//! are the constants themselves, so that folding goes on through it: a partially evaluated block.
+//! The blocks that it branches to are copied along with it, since they get the constants passed
+//! along. Calls that pass the same constants to the same block share the copy, and the copy keeps
+//! taking all of the arguments until `propagate_constant_arguments` drops the ones it ignores.
+//!
+//! Copies make the code grow, so they're only made while they fit in a budget of operations, which
+//! is shared by every time the pass runs, for the hottest calls first: the ones in the deepest
+//! loops. The copies call the original blocks, so a recursive block is specialized one level at a
+//! time, as its calls get folded down to constants, until the budget runs out. Blocks that are
+//! always called with the same constants don't need a copy, `propagate_constant_arguments` takes
+//! care of them.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use super::inline::branch_targets;
+use super::ipcp::constant_of;
+use super::rewrite::{rewrite_end, Writer};
+use super::{Analyses, Block, CallSite, Constant, Loop, MoveLabel, Op, IR};
+use crate::index;
+
+/// The constant arguments of a call, by their position.
+type Constants = Vec<(usize, Constant)>;
+
+/// Makes calls that pass constants call copies of their blocks specialized for them, taking the
+/// operations it copies out of the `budget`. Returns whether any call was specialized.
+pub fn specialize_calls(ir: &mut IR, budget: &mut usize, analyses: &mut Analyses) -> bool {
+    let call_sites = analyses.call_sites(ir).clone();
+    let branched_into = analyses
+        .branching_maps(ir)
+        .backwards
+        .keys()
+        .copied()
+        .collect::<HashSet<_>>();
+    let loops = analyses.loops(ir);
+    let mut candidates = Vec::new();
+    for (callee, sites) in call_sites.iter() {
+        let callee_block = &ir.blocks[unsafe { callee.to_index() } as usize];
+        let constants = sites
+            .iter()
+            .map(|site| constant_arguments(ir, *site))
+            .collect::<Vec<_>>();
+        // every call passes the same constants, and nothing branches in.
+        let uniform = !callee_block.exported
+            && !branched_into.contains(callee)
+            && constants.iter().all(|passed| *passed == constants[0]);
+        if uniform {
+            continue;
+        }
+        for (site, constants) in sites.iter().zip(constants) {
+            if let Some(constants) = constants {
+                let depth = loop_depth(loops, site.caller);
+                candidates.push((depth, *site, *callee, constants));
+            }
+        }
+    }
+    candidates.sort_by_key(|(depth, site, _, _)| {
+        (
+            Reverse(*depth),
+            unsafe { site.caller.to_index() },
+            site.op_index,
+        )
+    });
+
+    let mut copies: HashMap<(index::Label, Constants), index::Label> = HashMap::new();
+    let mut changed = false;
+    for (_, site, callee, constants) in candidates {
+        let key = (callee, constants);
+        let copy = match copies.get(&key) {
+            Some(copy) => *copy,
+            None => {
+                let region = region(ir, callee);
+                let size = region
+                    .iter()
+                    .map(|index| ir.blocks[*index].operations.len())
+                    .sum::<usize>();
+                if size > *budget {
+                    continue;
+                }
+                *budget -= size;
+                let copy = copy_region(ir, &region, &key.1);
+                copies.insert(key, copy);
+                copy
+            }
+        };
+
+        let caller = &mut ir.blocks[unsafe { site.caller.to_index() } as usize];
+        let op = &mut caller.operations[site.op_index as usize];
+        let Op::Call {
+            usage_info_index, ..
+        } = op
+        else {
+            unreachable!("call sites point at calls")
+        };
+        let usage = &mut caller.call_return_usages[*usage_info_index];
+        // SAFE: the copy returns the same values as the block it was copied from.
+        unsafe {
+            op.move_label(callee, copy);
+            usage.called_label.move_label(callee, copy);
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// The arguments of the call that are constants the called block uses, if there's any.
+fn constant_arguments(ir: &IR, site: CallSite) -> Option<Constants> {
+    let caller = &ir.blocks[unsafe { site.caller.to_index() } as usize];
+    let Op::Call { label, args, .. } = &caller.operations[site.op_index as usize] else {
+        unreachable!("call sites point at calls")
+    };
+    let callee = &ir.blocks[unsafe { label.to_index() } as usize];
+    let constants = args
+        .iter()
+        .enumerate()
+        .filter(|(arg, _)| !callee.binding_usages[*arg].is_empty())
+        .filter_map(|(arg, binding)| Some((arg, constant_of(caller, *binding)?)))
+        .collect::<Vec<_>>();
+    (!constants.is_empty()).then_some(constants)
+}
+
+/// The block, followed by the blocks it may branch into.
+fn region(ir: &IR, label: index::Label) -> Vec<usize> {
+    let start = unsafe { label.to_index() } as usize;
+    let mut region = vec![start];
+    region.extend(
+        branch_targets(ir, start)
+            .into_iter()
+            .filter(|target| *target != start),
+    );
+    region
+}
+
+/// Copies the blocks of the region after the rest, with the `constants` in place of the arguments
+/// of the first one. Branches between the blocks of the region go between the copies, but calls
+/// still go to the original blocks. Returns the label of the first copy.
+fn copy_region(ir: &mut IR, region: &[usize], constants: &Constants) -> index::Label {
+    let first_copy = ir.blocks.len();
+    let copy_of = |label: index::Label| {
+        region
+            .iter()
+            .position(|index| *index == unsafe { label.to_index() } as usize)
+            .map(|position| unsafe { index::Label::from_index((first_copy + position) as u16) })
+    };
+
+    let mut copies = Vec::with_capacity(region.len());
+    for (position, index) in region.iter().copied().enumerate() {
+        let block = &ir.blocks[index];
+        let mut writer = Writer::new(block.arg_count);
+        let mut renames = Writer::arguments(block.arg_count);
+        if position == 0 {
+            for (arg, constant) in constants.iter() {
+                let binding = writer.define(Op::Constant(*constant));
+                renames.insert(Writer::argument(*arg), binding);
+            }
+        }
+        for op_index in 0..block.operations.len() {
+            writer.copy_op(block, op_index, &mut renames);
+        }
+        let end = rewrite_end(
+            block,
+            &renames,
+            |target| copy_of(target).unwrap_or(target),
+            &[],
+        );
+        let returns_for = block
+            .returns_for
+            .map(|label| copy_of(label).unwrap_or(label));
+        copies.push(Block {
+            inline: block.inline,
+            ..writer.finish(end, returns_for)
+        });
+    }
+
+    ir.blocks.extend(copies);
+    unsafe { index::Label::from_index(first_copy as u16) }
+}
+
+/// How many loops the block is in.
+fn loop_depth(loops: &[Loop], label: index::Label) -> usize {
+    loops
+        .iter()
+        .filter(|found| found.body.contains(&label))
+        .count()
+}
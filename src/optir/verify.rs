@@ -0,0 +1,377 @@
+//! Checks for the invariants of the IR.
+//!
+//! Nothing in the data structures stops a pass from leaving, say, a usage behind for an operation
+//! it removed, and the backend would only turn that into wrong assembly. The verifier checks that
+//! every index points where it should and that the usages match what the operations and the ends
+//! of the blocks actually use, so broken passes are caught right after they run.
+
+use super::{bucket, Block, CFTransfer, Constant, Op, IR};
+use crate::index;
+
+#[derive(Debug, Clone)]
+pub struct VerifyError {
+    /// The block where the invariant doesn't hold, if it's about a single block.
+    pub block: Option<index::Label>,
+    pub violation: Violation,
+}
+
+#[derive(Debug, Clone)]
+pub enum Violation {
+    /// The block takes more arguments than it has bindings.
+    ArgumentCount { arg_count: usize, bindings: usize },
+    /// The first bindings aren't the arguments in order, or the rest aren't operation results.
+    MisplacedArgument { binding: index::Binding },
+    /// There's not a list of usages per binding.
+    UsagesLength { bindings: usize, usages: usize },
+    /// The binding is defined by an operation that doesn't exist.
+    DefinitionOutOfRange { binding: index::Binding },
+    /// A binding is referenced but the block doesn't have it.
+    BindingOutOfRange { binding: index::Binding },
+    /// An operation uses a binding that is only defined by itself or after it.
+    UsedBeforeDefinition {
+        binding: index::Binding,
+        op_index: u16,
+    },
+    /// The usages of the binding don't match where it's used, or aren't in order.
+    UsagesMismatch { binding: index::Binding },
+    /// A label is referenced but there's no block for it.
+    LabelOutOfRange { label: index::Label },
+    /// The bindings that the end of the block splits between its targets aren't as many as it
+    /// exports.
+    ExportedBindingCount { split: usize, exported: usize },
+    /// A block is branched to or called with a different amount of bindings than it takes.
+    ArgumentMismatch {
+        target: index::Label,
+        passed: usize,
+        expected: usize,
+    },
+    /// A call doesn't have its usage information, or it doesn't describe it.
+    CallUsageMismatch { op_index: u16 },
+    /// A call uses a result that the called block doesn't return.
+    ReturnOutOfRange { op_index: u16, result: u8 },
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(block) = self.block {
+            write!(f, "block {:?}: ", block)?;
+        }
+        match &self.violation {
+            Violation::ArgumentCount {
+                arg_count,
+                bindings,
+            } => write!(
+                f,
+                "takes {} arguments but only has {} bindings",
+                arg_count, bindings
+            ),
+            Violation::MisplacedArgument { binding } => {
+                write!(f, "{:?} is misplaced among the arguments", binding)
+            }
+            Violation::UsagesLength { bindings, usages } => {
+                write!(f, "has {} bindings but usages for {}", bindings, usages)
+            }
+            Violation::DefinitionOutOfRange { binding } => {
+                write!(
+                    f,
+                    "{:?} is defined by an operation that doesn't exist",
+                    binding
+                )
+            }
+            Violation::BindingOutOfRange { binding } => {
+                write!(f, "{:?} is used but doesn't exist", binding)
+            }
+            Violation::UsedBeforeDefinition { binding, op_index } => write!(
+                f,
+                "{:?} is used by operation {} before it's defined",
+                binding, op_index
+            ),
+            Violation::UsagesMismatch { binding } => {
+                write!(f, "the usages of {:?} don't match where it's used", binding)
+            }
+            Violation::LabelOutOfRange { label } => {
+                write!(f, "{:?} is referenced but doesn't exist", label)
+            }
+            Violation::ExportedBindingCount { split, exported } => write!(
+                f,
+                "splits {} bindings between its targets but exports {}",
+                split, exported
+            ),
+            Violation::ArgumentMismatch {
+                target,
+                passed,
+                expected,
+            } => write!(
+                f,
+                "passes {} bindings to {:?}, which takes {}",
+                passed, target, expected
+            ),
+            Violation::CallUsageMismatch { op_index } => write!(
+                f,
+                "the usage information of the call at {} doesn't describe it",
+                op_index
+            ),
+            Violation::ReturnOutOfRange { op_index, result } => write!(
+                f,
+                "the call at {} uses result {}, which isn't returned",
+                op_index, result
+            ),
+        }
+    }
+}
+
+/// Checks the invariants of the IR. Analyses are computed from the blocks, so they aren't
+/// checked, but the return counts are computed to check the results of calls against them.
+pub fn verify(ir: &IR) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+    // return counts are found by following the branches, which all have to go somewhere.
+    let branches_exist = ir
+        .blocks
+        .iter()
+        .flat_map(Block::referenced_labels)
+        .all(|label| (unsafe { label.to_index() } as usize) < ir.blocks.len());
+    let return_counts = if branches_exist {
+        ir.return_counts(&ir.return_blocks(&ir.branching_maps()))
+    } else {
+        Default::default()
+    };
+    for (index, block) in ir.blocks.iter().enumerate() {
+        let label = unsafe { index::Label::from_index(index as u16) };
+        errors.extend(
+            verify_block(ir, &return_counts, block)
+                .into_iter()
+                .map(|violation| VerifyError {
+                    block: Some(label),
+                    violation,
+                }),
+        );
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn verify_block(ir: &IR, return_counts: &[u8], block: &Block) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let bindings = block.binding_defs.len();
+    if block.arg_count > bindings {
+        violations.push(Violation::ArgumentCount {
+            arg_count: block.arg_count,
+            bindings,
+        });
+        return violations;
+    }
+    if block.binding_usages.len() != bindings {
+        violations.push(Violation::UsagesLength {
+            bindings,
+            usages: block.binding_usages.len(),
+        });
+        return violations;
+    }
+
+    // where each binding is defined, as the index of the operation (arguments come first).
+    let mut definitions = Vec::with_capacity(bindings);
+    for (binding_index, definition) in block.binding_defs.iter().enumerate() {
+        let binding = unsafe { index::Binding::from_index(binding_index as u16) };
+        let is_argument = binding_index < block.arg_count;
+        match *definition {
+            bucket::Definition::Argument(argument)
+                if is_argument && argument as usize == binding_index =>
+            {
+                definitions.push(None)
+            }
+            bucket::Definition::Op(op_index) if !is_argument => {
+                if op_index as usize >= block.operations.len() {
+                    violations.push(Violation::DefinitionOutOfRange { binding });
+                }
+                definitions.push(Some(op_index));
+            }
+            _ => {
+                violations.push(Violation::MisplacedArgument { binding });
+                definitions.push(None);
+            }
+        }
+    }
+
+    let label_exists =
+        |label: index::Label| (unsafe { label.to_index() } as usize) < ir.blocks.len();
+    let check_label = |violations: &mut Vec<Violation>, label: index::Label| {
+        let exists = label_exists(label);
+        if !exists {
+            violations.push(Violation::LabelOutOfRange { label });
+        }
+        exists
+    };
+
+    // the usages that each binding should have, from what actually uses it.
+    let mut expected_usages = vec![Vec::new(); bindings];
+    let mut use_at =
+        |violations: &mut Vec<Violation>, binding: index::Binding, index| match expected_usages
+            .get_mut(unsafe { binding.to_index() } as usize)
+        {
+            Some(usages) => usages.push(index),
+            None => violations.push(Violation::BindingOutOfRange { binding }),
+        };
+
+    for (op_index, op) in block.operations.iter().enumerate() {
+        let op_index = op_index as u16;
+        for operand in op.operands() {
+            use_at(&mut violations, operand, bucket::UsageIndex::Op(op_index));
+            let defined_before = match definitions.get(unsafe { operand.to_index() } as usize) {
+                Some(Some(definition)) => *definition < op_index,
+                _ => true,
+            };
+            if !defined_before {
+                violations.push(Violation::UsedBeforeDefinition {
+                    binding: operand,
+                    op_index,
+                });
+            }
+        }
+        match op {
+            Op::Constant(Constant::Label(label)) => {
+                check_label(&mut violations, *label);
+            }
+            Op::Call {
+                label,
+                args,
+                usage_info_index,
+            } if check_label(&mut violations, *label) => {
+                verify_call(
+                    ir,
+                    return_counts,
+                    block,
+                    op_index,
+                    *label,
+                    args.len(),
+                    *usage_info_index,
+                )
+                .into_iter()
+                .for_each(|violation| violations.push(violation));
+            }
+            _ => (),
+        }
+    }
+
+    for binding in block.exported_bindings.iter().copied() {
+        use_at(&mut violations, binding, bucket::UsageIndex::BlockEnd);
+    }
+    let split = match &block.end {
+        CFTransfer::Return | CFTransfer::DirectBranch { .. } => block.exported_bindings.len(),
+        CFTransfer::ConditionalBranch {
+            flag_definition,
+            true_branch_binding_count,
+            ..
+        } => {
+            use_at(
+                &mut violations,
+                *flag_definition,
+                bucket::UsageIndex::BlockEnd,
+            );
+            *true_branch_binding_count as usize
+        }
+        CFTransfer::Switch { value, cases, .. } => {
+            use_at(&mut violations, *value, bucket::UsageIndex::BlockEnd);
+            cases.iter().map(|case| case.binding_count as usize).sum()
+        }
+    };
+    if split > block.exported_bindings.len() {
+        violations.push(Violation::ExportedBindingCount {
+            split,
+            exported: block.exported_bindings.len(),
+        });
+    } else {
+        for (target, range) in block.redirections() {
+            if check_label(&mut violations, target) {
+                let expected = ir.blocks[unsafe { target.to_index() } as usize].arg_count;
+                if range.len() != expected {
+                    violations.push(Violation::ArgumentMismatch {
+                        target,
+                        passed: range.len(),
+                        expected,
+                    });
+                }
+            }
+        }
+    }
+    if let Some(label) = block.returns_for {
+        check_label(&mut violations, label);
+    }
+
+    for (binding_index, (usages, expected)) in block
+        .binding_usages
+        .iter()
+        .zip(expected_usages.iter_mut())
+        .enumerate()
+    {
+        // usages are kept in order, so they're the sorted list of where the binding is used.
+        expected.sort();
+        if !usages
+            .iter()
+            .map(|usage| usage.index)
+            .eq(expected.iter().copied())
+        {
+            violations.push(Violation::UsagesMismatch {
+                binding: unsafe { index::Binding::from_index(binding_index as u16) },
+            });
+        }
+    }
+    violations
+}
+
+fn verify_call(
+    ir: &IR,
+    return_counts: &[u8],
+    block: &Block,
+    op_index: u16,
+    label: index::Label,
+    arg_count: usize,
+    usage_info_index: usize,
+) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let callee = unsafe { label.to_index() } as usize;
+    let expected = ir.blocks[callee].arg_count;
+    if arg_count != expected {
+        violations.push(Violation::ArgumentMismatch {
+            target: label,
+            passed: arg_count,
+            expected,
+        });
+    }
+
+    let Some(usage) = block.call_return_usages.get(usage_info_index) else {
+        violations.push(Violation::CallUsageMismatch { op_index });
+        return violations;
+    };
+    let results = usage
+        .result_binding_range
+        .clone()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let results_match = usage.called_label == label
+        && results.len() == usage.result_usage.len()
+        && results.iter().all(|result| {
+            matches!(
+                block.binding_defs.get(unsafe { result.to_index() } as usize),
+                Some(bucket::Definition::Op(definition)) if *definition == op_index
+            )
+        });
+    if !results_match {
+        violations.push(Violation::CallUsageMismatch { op_index });
+    }
+    if let Some(return_count) = return_counts.get(callee) {
+        violations.extend(
+            usage
+                .result_usage
+                .iter()
+                .filter(|result| **result >= *return_count)
+                .map(|result| Violation::ReturnOutOfRange {
+                    op_index,
+                    result: *result,
+                }),
+        );
+    }
+    violations
+}
@@ -0,0 +1,408 @@
+//! Regression tests. Each one compiles a program, links it with the system's C compiler and checks
+//! what it exits with, since the bugs they cover only show up when the output is run.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Where the files of the test named `name` go.
+fn scratch(name: &str, extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "sawblade-{}-{}.{}",
+        std::process::id(),
+        name,
+        extension
+    ))
+}
+
+/// Compiles `source` with `flags`, returning the assembly, or what the compiler printed to stderr
+/// if it failed.
+fn compile(name: &str, source: &str, flags: &[&str]) -> Result<String, String> {
+    let path = scratch(name, "sawblade");
+    std::fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sawblade"))
+        .args(flags)
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    if output.status.success() {
+        Ok(String::from_utf8(output.stdout).unwrap())
+    } else {
+        Err(String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+/// Compiles, links and runs `source`, returning its exit code.
+fn run(name: &str, source: &str, flags: &[&str]) -> i32 {
+    let assembly = compile(name, source, flags).unwrap_or_else(|error| panic!("{}", error));
+    let (assembly_path, executable) = (scratch(name, "s"), scratch(name, "out"));
+    std::fs::write(&assembly_path, &assembly).unwrap();
+    let linked = Command::new("cc")
+        .args(["-w", "-Wl,-z,noexecstack"])
+        .arg(&assembly_path)
+        .arg("-o")
+        .arg(&executable)
+        .status()
+        .unwrap();
+    assert!(linked.success(), "failed to link:\n{}", assembly);
+    let status = Command::new(&executable).status().unwrap();
+    std::fs::remove_file(&assembly_path).unwrap();
+    std::fs::remove_file(&executable).unwrap();
+    status.code().expect("the program should exit normally")
+}
+
+const OPT_LEVELS: [&str; 4] = ["-O0", "-O1", "-O2", "-Os"];
+
+/// An argument that is never read used to overlap the first result of its block, which then
+/// didn't end up in the return register.
+#[test]
+fn unused_argument_of_a_returning_block() {
+    let source = r#"
+block "main" :: { return [rax] } {
+    %a = add 2 3;
+    %f = flags %a eq;
+    br %f @no(%a) @yes(%a)
+}
+
+block %no :: (%b) {
+    %b
+}
+
+block %yes :: (%b) {
+    %k = 10;
+    %k
+}
+"#;
+    for level in OPT_LEVELS {
+        assert_eq!(run("unused-argument", source, &[level]), 10, "at {}", level);
+    }
+}
+
+/// Constant propagation used to leave the arguments that it didn't turn into constants in place,
+/// even when nothing used them anymore.
+#[test]
+fn argument_left_unused_by_constant_propagation() {
+    let source = r#"
+block "main" :: { arguments [rdi] return [rax] } (%argc) {
+    %a = add %argc 4;
+    %f = flags %a eq;
+    br %f @no(%a) @yes(%a, 10)
+}
+
+block %no :: (%b) {
+    %b
+}
+
+block %yes :: (%b %c) {
+    %c
+}
+"#;
+    for level in OPT_LEVELS {
+        assert_eq!(
+            run("unused-after-ipcp", source, &[level]),
+            10,
+            "at {}",
+            level
+        );
+    }
+}
+
+/// Exports kept the labels they had before `--lenient` dropped the malformed ones, so the blocks
+/// after a dropped export were emitted under its name.
+#[test]
+fn export_after_a_dropped_one() {
+    let source = r#"
+block "broken" :: { return [rax] } {
+    %a = add 2 3;
+    %f = flags %a eq;
+    br %f @one() @two()
+}
+
+block "main" :: { return [rax] } {
+    3
+}
+
+block %one {
+    1
+}
+
+block %two {
+    %x = 1;
+    %x %x
+}
+"#;
+    let assembly = compile("dropped-export", source, &["--lenient"]).unwrap();
+    assert!(!assembly.contains("broken"), "{}", assembly);
+    assert_eq!(run("dropped-export", source, &["--lenient"]), 3);
+}
+
+/// The first result of inline assembly used to stay pinned to the register that the next
+/// assembly writes, which panicked instead of moving it out of the way.
+#[test]
+fn assembly_output_live_across_assembly() {
+    let source = r#"
+block "main" :: { return [rax] } {
+    %x = asm "mov rax, 5" out [rax];
+    %y = asm "mov rax, 7" out [rax];
+    sub %y %x
+}
+"#;
+    for level in OPT_LEVELS {
+        assert_eq!(run("asm-live-output", source, &[level]), 2, "at {}", level);
+    }
+
+    let source = r#"
+block "main" :: { return [rax] } {
+    %lo %hi = asm "rdtsc" out [rax, rdx];
+    %lo2 %hi2 = asm "rdtsc" out [rax, rdx];
+    %d = sub %lo2 %lo;
+    sub %d %d
+}
+"#;
+    for level in OPT_LEVELS {
+        assert_eq!(run("asm-rdtsc", source, &[level]), 0, "at {}", level);
+    }
+}
+
+/// Registers that the target doesn't have used to panic while unwrapping them, instead of being
+/// reported as an error.
+#[test]
+fn assembly_register_missing_on_the_target() {
+    let source = r#"
+block "main" :: { return [x0] } {
+    %x = asm "mrs x0, cntvct_el0" out [rax];
+    %x
+}
+"#;
+    let error = compile("asm-missing-register", source, &["--target=aarch64"]).unwrap_err();
+    assert_eq!(
+        error,
+        "error: block \"main\" uses register \"rax\" in assembly, which doesn't exist on this \
+         target\n"
+    );
+}
+
+/// System calls are inline assembly, so the result of the first one used to stay pinned to the
+/// register that the second one returns in.
+#[test]
+fn syscall_result_live_across_syscall() {
+    let source = r#"
+block "main" :: { return [rax] } {
+    %r = syscall 39;
+    %p = syscall 39;
+    %d = sub %p %r;
+    add %d 7
+}
+"#;
+    for level in OPT_LEVELS {
+        assert_eq!(
+            run("syscall-live-result", source, &[level]),
+            7,
+            "at {}",
+            level
+        );
+    }
+}
+
+/// A program that switches over `value`, which it gets by adding to `argc` so that it isn't
+/// folded away. Each case returns 10 plus its position, and the default returns 30.
+fn switch_source(value: u64, cases: &[u64]) -> String {
+    let mut source = String::from("block \"main\" :: { arguments [rdi] return [rax] } (%argc) {\n");
+    source.push_str(&format!("    %x = add %argc {};\n", value.wrapping_sub(1)));
+    source.push_str("    switch %x [");
+    for (index, case) in cases.iter().enumerate() {
+        if index != 0 {
+            source.push_str(", ");
+        }
+        source.push_str(&format!("{} @case{}()", case, index));
+    }
+    source.push_str("] @default()\n}\n");
+    for index in 0..cases.len() {
+        source.push_str(&format!(
+            "block %case{} {{ %r = {}; %r }}\n",
+            index,
+            10 + index
+        ));
+    }
+    source.push_str("block %default { %r = 30; %r }\n");
+    source
+}
+
+/// Checks that switching over each of `values` ends up in its case, at every optimization level.
+fn check_switch(name: &str, cases: &[u64], values: &[u64]) {
+    for value in values.iter().copied() {
+        let expected = cases
+            .iter()
+            .position(|case| *case == value)
+            .map_or(30, |index| 10 + index as i32);
+        let source = switch_source(value, cases);
+        for level in OPT_LEVELS {
+            assert_eq!(
+                run(name, &source, &[level]),
+                expected,
+                "switching over {} at {}",
+                value,
+                level
+            );
+        }
+    }
+}
+
+/// Dense switches are lowered to jump tables, which have to link into position independent
+/// executables.
+#[test]
+fn dense_switch() {
+    check_switch(
+        "dense-switch",
+        &[0, 1, 2, 3, 4],
+        &[u64::MAX, 0, 1, 2, 3, 4, 5],
+    );
+    check_switch("offset-dense-switch", &[9, 7, 8, 6], &[5, 6, 7, 8, 9, 10]);
+}
+
+/// Sparse switches are lowered to a compare chain.
+#[test]
+fn sparse_switch() {
+    check_switch(
+        "sparse-switch",
+        &[3, 100, 7000, 40],
+        &[0, 3, 40, 100, 7000, 7001],
+    );
+}
+
+/// Cases that don't fit in an immediate used to be compared against as one, and jump tables
+/// based on them overflowed their displacement.
+#[test]
+fn switch_on_values_wider_than_32_bits() {
+    check_switch(
+        "wide-sparse-switch",
+        &[7, 5_000_000_000, 12],
+        &[7, 4_999_999_999, 5_000_000_000],
+    );
+    let base = 5_000_000_000;
+    check_switch(
+        "wide-dense-switch",
+        &[base, base + 1, base + 2, base + 3, base + 4],
+        &[base - 1, base, base + 2, base + 4, base + 5],
+    );
+}
+
+/// Flags that are fetched before an operation that overwrites them, and branched on after it,
+/// used to be read from whatever that operation left in them.
+#[test]
+fn flags_live_across_an_operation_that_overwrites_them() {
+    // the address is far enough below the stack pointer to not be overwritten by the pushes
+    // around the atomic operation.
+    let operations = [
+        (
+            r#"%p = asm "lea rax, [rsp - 64]" out [rax];
+    %seven = 7;
+    atomic.store seqcst %p %seven;
+    %v = atomic.add seqcst %p 5"#,
+            7,
+        ),
+        ("%v = popcnt %argc", 1),
+        (r#"%v = asm "mov rdx, 7" out [rdx]"#, 7),
+    ];
+    for (operation, expected) in operations {
+        let source = format!(
+            r#"
+block "main" :: {{ arguments [rdi] return [rax] }} (%argc) {{
+    %c = sub %argc 1;
+    %f = flags %c eq;
+    {};
+    br %f @equal(%v) @different(%v)
+}}
+
+block %equal :: (%v) {{
+    %v
+}}
+
+block %different :: (%v) {{
+    %r = add %v 100;
+    %r
+}}
+"#,
+            operation
+        );
+        for level in OPT_LEVELS {
+            assert_eq!(
+                run("flags-overwritten", &source, &[level]),
+                expected,
+                "{} at {}",
+                operation,
+                level
+            );
+        }
+    }
+}
+
+/// Arithmetic on aarch64 only sets the flags when asked to, so branching on the flags of a
+/// subtraction used to test whatever was left in them.
+#[test]
+fn aarch64_flags_of_arithmetic() {
+    let source = r#"
+block "main" :: { arguments [x0] return [x0] } (%argc) {
+    %c = sub %argc 1;
+    %f = flags %c eq;
+    br %f @equal() @different()
+}
+
+block %equal { %r = 1; %r }
+block %different { %r = 2; %r }
+"#;
+    for level in OPT_LEVELS {
+        let assembly = compile("aarch64-flags", source, &["--target=aarch64", level]).unwrap();
+        assert!(assembly.contains("subs "), "at {}:\n{}", level, assembly);
+    }
+}
+
+/// Calls overwrite the link register, which used to make the caller return into itself.
+#[test]
+fn aarch64_link_register_around_calls() {
+    let source = r#"
+noinline block %add_one :: (%a) {
+    add %a 1
+}
+
+block "main" :: { return [x0] } {
+    %value = call @add_one 10;
+    add 15 %value
+}
+"#;
+    for level in OPT_LEVELS {
+        let assembly = compile("aarch64-calls", source, &["--target=aarch64", level]).unwrap();
+        let lines = assembly.lines().map(str::trim).collect::<Vec<_>>();
+        let calls = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.starts_with("bl "))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        assert!(!calls.is_empty(), "at {}:\n{}", level, assembly);
+        for index in calls {
+            assert_eq!(lines[index - 1], "stp x29, x30, [sp, -16]!", "at {}", level);
+            assert_eq!(lines[index + 1], "ldp x29, x30, [sp], 16", "at {}", level);
+        }
+    }
+}
+
+/// Jump tables on aarch64 have to link into position independent executables too.
+#[test]
+fn aarch64_position_independent_jump_table() {
+    let source = r#"
+block "main" :: { arguments [x0] return [x0] } (%argc) {
+    switch %argc [0 @a(), 1 @b(), 2 @c(), 3 @d()] @e()
+}
+block %a { %r = 1; %r }
+block %b { %r = 2; %r }
+block %c { %r = 3; %r }
+block %d { %r = 4; %r }
+block %e { %r = 5; %r }
+"#;
+    let assembly = compile("aarch64-jump-table", source, &["--target=aarch64"]).unwrap();
+    assert!(assembly.contains("adrp "), "{}", assembly);
+    assert!(assembly.contains(":lo12:"), "{}", assembly);
+    assert!(!assembly.contains("ldr x17, ="), "{}", assembly);
+    assert!(assembly.contains(".word .BB0 - .LJT0"), "{}", assembly);
+}